@@ -0,0 +1,93 @@
+//! Run a ROM headless for a fixed number of frames, then print what it sent over the serial port
+//! and a hash of the final frame. Handy for checking the emulator against test ROM suites, many of
+//! which report their results over serial (eg. Blargg's) or on screen (compare the hash against a
+//! known-good run).
+//!
+//! Usage: `cargo run --example test_runner path/to/rom.gb [frames]`
+use gameboy::emulator::Emulator;
+use gameboy::options::EmulatorOptions;
+use std::env;
+
+const DEFAULT_FRAMES: usize = 600; // About 10 seconds of emulated time.
+
+/// The outcome of running a test ROM.
+pub struct TestResult {
+    pub serial_output: String,
+    pub frame_hash: u64,
+}
+
+/// Run the ROM at `path` for `frames` frames without the boot ROM, a window, audio or input.
+/// Unimplemented opcodes are skipped rather than panicking, so a run always completes.
+pub fn run(path: &str, frames: usize) -> Result<TestResult, String> {
+    let options = EmulatorOptions::default()
+        .rom_path(path)
+        .use_bootrom(false)
+        .strict_opcodes(false)
+        .headless(true);
+
+    let mut emulator = Emulator::new(options)?;
+    emulator.run_frames(frames);
+
+    Ok(TestResult {
+        serial_output: String::from_utf8_lossy(emulator.serial_output()).into_owned(),
+        frame_hash: fnv1a(emulator.frame_buffer()),
+    })
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, it's stable between Rust versions, so hashes can be
+/// recorded and compared later.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let path = match args.get(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: test_runner <rom> [frames]");
+            std::process::exit(1);
+        }
+    };
+    let frames = match args.get(2) {
+        Some(n) => n.parse().expect("Frames must be a number."),
+        None => DEFAULT_FRAMES,
+    };
+
+    let result = run(path, frames).unwrap();
+    println!("Serial output:\n{}", result.serial_output);
+    println!("Frame hash: {:016x}", result.frame_hash);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run_trivial_rom() {
+        // A ROM-only cartridge that sends "ok" over serial, then loops forever.
+        let program = [
+            0x3E, b'o', // LD A,'o'
+            0xE0, 0x01, // LDH (SB),A
+            0x3E, 0x81, // LD A,0x81
+            0xE0, 0x02, // LDH (SC),A: start a transfer on the internal clock.
+            0x3E, b'k', // LD A,'k'
+            0xE0, 0x01, // LDH (SB),A
+            0x3E, 0x81, // LD A,0x81
+            0xE0, 0x02, // LDH (SC),A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+        let path = env::temp_dir().join("gameboy_test_runner.gb");
+        fs::write(&path, rom).unwrap();
+
+        let result = run(path.to_str().unwrap(), 2).unwrap();
+        assert_eq!(result.serial_output, "ok");
+        assert_eq!(result.frame_hash, fnv1a(&[0; 160 * 144]));
+    }
+}
@@ -0,0 +1,190 @@
+pub use crate::guest::systems::{AudioOverflow, Socd};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// The classic green DMG-01 palette, from lightest (colour 0) to darkest (colour 3).
+pub const DEFAULT_PALETTE: [(u8, u8, u8); 4] = [
+    (155, 188, 15), // #9bbc0f
+    (139, 172, 15), // #8bac0f
+    (48, 98, 48),   // #306230
+    (15, 56, 15),   // #0f380f
+];
+
+/// User configuration, loaded from a TOML file at startup. Every field is optional in the file;
+/// anything missing falls back to the default. For example:
+/// ```toml
+/// scale = 4
+/// volume = 0.5
+/// boot_rom_path = "roms/dmg_rom.bin"
+/// save_dir = "saves"
+/// socd = "last-wins"
+/// palette = [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]]
+///
+/// [keys]
+/// a = "J"
+/// b = "K"
+/// ```
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub keys: KeyBindings,
+    pub scale: usize,
+    pub palette: [(u8, u8, u8); 4],
+    pub volume: f32,
+    pub boot_rom_path: String,
+    pub turbo_preserve_pitch: bool, // Keep audio at its normal pitch while fast-forwarding.
+    pub contrast: f32,              // Contrast of the grayscale palette mode. 1.0 is normal.
+    pub high_contrast_threshold: u8, // Shades below this are white in high contrast mode (1-3).
+    pub save_dir: Option<String>,   // Where to keep .sav files. Defaults to next to the ROM.
+    pub socd: Socd,                 // What to do with opposing d-pad directions held together.
+    pub audio_buffer_cap: usize,    // Most APU samples to hold for the host. ~1M is a second.
+    pub audio_overflow: AudioOverflow, // What to do with samples once the cap is reached.
+}
+
+/// Keyboard bindings for the eight gamepad keys. Values are SDL scancode names (eg. "Left", "A").
+/// They are kept as strings so that parsing the config does not depend on SDL.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub right: String,
+    pub left: String,
+    pub up: String,
+    pub down: String,
+    pub a: String,
+    pub b: String,
+    pub select: String,
+    pub start: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            keys: KeyBindings::default(),
+            scale: 8,
+            palette: DEFAULT_PALETTE,
+            volume: 0.25,
+            boot_rom_path: String::from("data/dmg_rom.bin"),
+            turbo_preserve_pitch: false,
+            contrast: 1.0,
+            high_contrast_threshold: 2,
+            save_dir: None,
+            socd: Socd::Allow,
+            audio_buffer_cap: 1 << 20,
+            audio_overflow: AudioOverflow::DropOldest,
+        }
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            right: String::from("Right"),
+            left: String::from("Left"),
+            up: String::from("Up"),
+            down: String::from("Down"),
+            a: String::from("A"),
+            b: String::from("S"),
+            select: String::from("X"),
+            start: String::from("Z"),
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Return the bindings in the same order the gamepad expects its state:
+    /// [Right, Left, Up, Down, A, B, Select, Start]
+    pub fn as_array(&self) -> [&str; 8] {
+        [
+            &self.right,
+            &self.left,
+            &self.up,
+            &self.down,
+            &self.a,
+            &self.b,
+            &self.select,
+            &self.start,
+        ]
+    }
+}
+
+impl Config {
+    /// Parse a config from a TOML string.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid config: {}", e))
+    }
+
+    /// Load a config from a TOML file. A missing file is not an error: the defaults are used. One
+    /// that exists but can't be read is.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!(
+                "Could not read config {}: {}",
+                path.as_ref().display(),
+                e
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            scale = 4
+            volume = 0.5
+            boot_rom_path = "roms/dmg_rom.bin"
+            save_dir = "saves"
+            socd = "last-wins"
+            audio_buffer_cap = 4096
+            audio_overflow = "block"
+            palette = [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]]
+
+            [keys]
+            a = "J"
+            b = "K"
+        "#;
+
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config.scale, 4);
+        assert_eq!(config.volume, 0.5);
+        assert_eq!(config.boot_rom_path, "roms/dmg_rom.bin");
+        assert_eq!(config.save_dir, Some(String::from("saves")));
+        assert_eq!(config.socd, Socd::LastWins);
+        assert_eq!(config.audio_buffer_cap, 4096);
+        assert_eq!(config.audio_overflow, AudioOverflow::Block);
+        assert_eq!(config.palette[0], (255, 255, 255));
+        assert_eq!(config.palette[3], (0, 0, 0));
+
+        // Bindings that were given are replaced, the rest keep their defaults.
+        assert_eq!(
+            config.keys.as_array(),
+            ["Right", "Left", "Up", "Down", "J", "K", "X", "Z"]
+        );
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let config = Config::load("this/path/does/not/exist.toml").unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_load_unreadable_file() {
+        // A directory exists, but can't be read as a file.
+        assert!(Config::load(std::env::temp_dir()).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Config::parse("scale = \"big\"").is_err());
+    }
+}
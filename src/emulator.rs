@@ -1,12 +1,17 @@
-use crate::guest::systems::{Gamepad, Timer, APU, CPU, PPU};
+use crate::guest::systems::{Gamepad, Timer, APU, CPU, CYCLES_PER_FRAME, CYCLES_PER_LINE, PPU};
 use crate::guest::MMU;
-use crate::host::{Audio, Input, InputEvent, Screen};
+use crate::host::{speed_up, time_stretch, Audio, Hud, Input, InputEvent, PaletteMode, Screen};
+use crate::options::EmulatorOptions;
 use sdl2;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub use crate::guest::systems::{Button, FrameStats, LcdcOverrides, Sprite};
+pub use crate::guest::{AccessStats, GuestState, PpuMode, RegionCounts};
 
 pub const CPU_FREQ: usize = 4194304; // 4MHz for DMG-01.
 pub const AUDIO_FREQ: usize = 48_000; // 48KHz audio sample target.
 pub const AUDIO_BUFFER: usize = 256; // Needs to be a power of 2.
-pub const DIVIDER_FREQ: usize = CPU_FREQ / 16384; // Divider always runs at 16KHz.
 
 // Emulate audio a fraction as often as the actual frequency.
 // If a single CPU instruction occurs, it is a minimum of 4 CPU clock cycles. We could emulate 4 APU
@@ -19,7 +24,25 @@ pub const APU_DIVISOR: usize = 4;
 // This is how many APU samples should be used to generate a single audio device sample.
 const APU_SAMPLES_PER_AUDIO_SAMPLE: f64 = (CPU_FREQ / APU_DIVISOR) as f64 / AUDIO_FREQ as f64;
 
-const FRAMERATE: usize = 60;
+const TURBO_FRAMES: usize = 4; // Frames emulated per presented frame while turbo is held.
+
+/// What happened during a run, for scripts evaluating it (eg. in CI).
+pub struct Summary {
+    pub instructions: u64,     // CPU instructions executed.
+    pub frames: u64,           // Frames rendered: how many times the PPU entered VBlank.
+    pub serial_output: String, // Everything sent over the serial port.
+}
+
+/// What happened during one `Emulator::step`, for frontends that want to react to events rather
+/// than poll the guest's state.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StepOutcome {
+    pub cycles: u8,             // How long the step took.
+    pub interrupt: Option<u16>, // The vector jumped to, if an interrupt was serviced.
+    pub vblank: bool,           // The PPU entered VBlank.
+    pub serial: Option<u8>,     // A byte sent over the serial port.
+    pub frame_completed: bool,  // A whole frame has been emulated. See `Emulator::step`.
+}
 
 pub struct Emulator {
     // Guest components.
@@ -29,76 +52,435 @@ pub struct Emulator {
     apu: APU,
     gamepad: Gamepad,
     timer: Timer,
-    // Host components.
-    input: Input,
-    screen: Screen,
-    audio: Audio,
+    frame_count: u64,
+    frame_cycles: usize,  // Cycles emulated since the last frame was completed.
+    autorelease: [u8; 8], // Frames left to hold each scripted button press, indexed by `Button`.
+    // Host components. These are all `None` when running headless.
+    input: Option<Input>,
+    screen: Option<Screen>,
+    audio: Option<Audio>,
+    hud: Hud,
+    turbo_preserve_pitch: bool,
+    palette: [(u8, u8, u8); 4], // The configured palette, used by `PaletteMode::Configured`.
+    palette_mode: PaletteMode,
+    contrast: f32,
+    high_contrast_threshold: u8,
+    idle_sleep: bool,
 }
 
 impl Emulator {
-    pub fn new(cartridge_path: Option<&String>, use_bootrom: bool) -> Result<Self, String> {
-        // SDL-based host: graphics, sound, audio.
-        let sdl_context = sdl2::init()?;
-        let input = Input::new(&sdl_context)?;
-        let screen = Screen::new(&sdl_context, 8)?;
-        let audio = Audio::new(&sdl_context)?;
+    pub fn new(options: EmulatorOptions) -> Result<Self, String> {
+        // SDL-based host: graphics, sound, audio. Skipped entirely when headless.
+        let (input, screen, audio) = if options.headless {
+            (None, None, None)
+        } else {
+            let sdl_context = sdl2::init()?;
+            let input = Input::new(&sdl_context, &options.key_bindings)?;
+            let screen = Screen::new(
+                &sdl_context,
+                options.scale,
+                options.palette,
+                options.upscale,
+            )?;
+            let audio = Audio::new(&sdl_context, options.volume)?;
+            (Some(input), Some(screen), Some(audio))
+        };
+
+        let mut cpu = CPU::new(options.strict_opcodes)?;
+        if options.profile_opcodes {
+            cpu.enable_opcode_histogram();
+        }
+        if options.pause_on_unknown_opcode {
+            cpu.enable_pause_on_unknown_opcode();
+        }
+
+        let mut mmu = MMU::with_boot_rom_path(
+            options.rom_path.as_ref(),
+            options.use_bootrom,
+            &options.boot_rom_path,
+            options.patch_path.as_ref(),
+        )?;
+        if options.log_ram_banks {
+            mmu.enable_ram_bank_log();
+        }
+        if options.patch_boot_logo {
+            mmu.enable_boot_logo_patch();
+        }
+        if let Some(dir) = &options.save_dir {
+            mmu.set_save_dir(dir);
+        }
+        mmu.load_cartridge()?;
+        if options.trace_serial {
+            mmu.set_serial_sink(Box::new(io::stdout()));
+        }
+        if options.profile_memory {
+            mmu.enable_access_stats();
+        }
+
+        let mut gamepad = Gamepad::new();
+        gamepad.set_socd(options.socd);
+
+        let mut apu = APU::new();
+        apu.set_buffer_cap(options.audio_buffer_cap, options.audio_overflow);
+        if options.mute_boot_rom {
+            apu.enable_boot_rom_mute();
+        }
 
         Ok(Self {
-            cpu: CPU::new(),
-            mmu: MMU::new(cartridge_path, use_bootrom),
+            cpu,
+            mmu,
             ppu: PPU::new(),
-            apu: APU::new(),
+            apu,
             timer: Timer::new(),
-            gamepad: Gamepad::new(),
+            frame_count: 0,
+            frame_cycles: 0,
+            autorelease: [0; 8],
+            gamepad,
             input,
             audio,
             screen,
+            hud: Hud::new(),
+            turbo_preserve_pitch: options.turbo_preserve_pitch,
+            palette: options.palette,
+            palette_mode: PaletteMode::Configured,
+            contrast: options.contrast,
+            high_contrast_threshold: options.high_contrast_threshold,
+            idle_sleep: options.idle_sleep,
         })
     }
 
+    /// How many times each opcode has executed, indexed by opcode. All CB-prefixed instructions are
+    /// counted under 0xCB. Only populated when `profile_opcodes` was enabled in the options.
+    pub fn opcode_histogram(&self) -> [u64; 256] {
+        self.cpu.opcode_histogram()
+    }
+
+    /// Memory reads and writes per region. Only populated when `profile_memory` was enabled in the
+    /// options.
+    pub fn access_stats(&self) -> AccessStats {
+        self.mmu.access_stats()
+    }
+
+    /// Emulate a number of whole frames as fast as possible, without presenting them. Any audio is
+    /// discarded. Useful for running headless, eg. for automated testing.
+    pub fn run_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.emulate_frame();
+            self.apu.output_buffer.clear();
+        }
+    }
+
+    /// Run a single CPU instruction (or interrupt dispatch), and the timer, PPU and APU for as long
+    /// as it took. A frame is completed on entering VBlank or, so that frames still end with the
+    /// LCD off, once a frame and a line's worth of cycles have passed without it. The gamepad's
+    /// keys are only read between frames, by `run_frames` and `run_forever`.
+    pub fn step(&mut self) -> StepOutcome {
+        let mmu = &mut self.mmu;
+        let serial_len = mmu.serial_output.len();
+
+        self.gamepad.step(mmu);
+        let cycles = self.cpu.step(mmu);
+        self.timer.step(mmu, cycles);
+        let vblank = self.ppu.step(mmu, cycles);
+        self.apu.step(mmu, cycles);
+
+        self.frame_cycles += cycles as usize;
+        if vblank {
+            self.frame_count += 1;
+        }
+        let frame_completed = vblank || self.frame_cycles >= CYCLES_PER_FRAME + CYCLES_PER_LINE;
+        if frame_completed {
+            self.frame_cycles = 0;
+        }
+
+        StepOutcome {
+            cycles,
+            interrupt: self.cpu.serviced_interrupt(),
+            vblank,
+            serial: mmu.serial_output.get(serial_len).cloned(),
+            frame_completed,
+        }
+    }
+
+    /// The most recently drawn frame: 160x144 palette indices (0-3), in row-major order.
+    pub fn frame_buffer(&self) -> &[u8; 160 * 144] {
+        &self.ppu.image_buffer
+    }
+
+    /// The ROM bank the cartridge currently has mapped to 0x4000-0x7FFF.
+    pub fn current_rom_bank(&self) -> u16 {
+        self.mmu.current_banks().0
+    }
+
+    /// The RAM bank the cartridge currently has mapped to 0xA000-0xBFFF.
+    pub fn current_ram_bank(&self) -> u8 {
+        self.mmu.current_banks().1
+    }
+
+    /// Name the memory region or register an address belongs to, eg. "OAM" or "IO: LCDC".
+    pub fn describe_address(&self, address: u16) -> &'static str {
+        self.mmu.describe_address(address)
+    }
+
+    /// The CPU's 16-bit register pairs: AF, BC, DE and HL. Some test ROMs, such as Mooneye's, report
+    /// their result in them.
+    pub fn registers(&self) -> [u16; 4] {
+        [self.mmu.af(), self.mmu.bc(), self.mmu.de(), self.mmu.hl()]
+    }
+
+    /// Disassemble `count` instructions starting at the current PC, without running them. Return
+    /// each instruction's address and text, eg. `(0x0100, "NOP")`. Handy for a debugger's
+    /// instruction view.
+    pub fn peek_disassembly(&self, count: usize) -> Vec<(u16, String)> {
+        let mut address = self.mmu.pc;
+        let mut instructions = Vec::new();
+
+        for _ in 0..count {
+            let (text, length) = self.cpu.disassemble(&self.mmu, address);
+            instructions.push((address, text));
+            address = address.wrapping_add(length as u16);
+        }
+
+        instructions
+    }
+
+    /// What the PPU is doing, the line it's on (LY), and whether that line matches LYC. For
+    /// debuggers and the like that want to follow the PPU's timing.
+    pub fn ppu_status(&self) -> (PpuMode, u8, bool) {
+        let ppu = &self.mmu.ppu;
+        (ppu.mode, ppu.line, ppu.line == ppu.lyc)
+    }
+
+    /// The interrupt master enable (IME) and the DI/EI delays, as `Interrupts::ime_state`. For
+    /// working out why an interrupt isn't firing.
+    pub fn ime_state(&self) -> (bool, u8, u8) {
+        self.mmu.interrupts.ime_state()
+    }
+
+    /// Every sprite in OAM, decoded. For debugging sprite placement.
+    pub fn oam_sprites(&self) -> Vec<Sprite> {
+        PPU::oam_sprites(&self.mmu)
+    }
+
+    /// What the PPU drew in the last complete frame: sprites, window lines and the busiest line.
+    pub fn last_frame_stats(&self) -> FrameStats {
+        self.ppu.last_frame_stats()
+    }
+
+    /// Press a button for exactly one frame: it's released again before the next. For scripts,
+    /// eg. to step through menus. It's held on top of whatever the keyboard is doing.
+    pub fn set_button_with_autorelease(&mut self, button: Button) {
+        self.autorelease[button as usize] = 1;
+    }
+
+    /// Force LCDC bits for rendering, eg. to turn sprites off and see just the background.
+    pub fn set_lcdc_overrides(&mut self, overrides: LcdcOverrides) {
+        self.ppu.set_lcdc_overrides(overrides);
+    }
+
+    /// Capture the guest's entire state, eg. for a save state. It can be serialized with serde.
+    pub fn snapshot(&self) -> GuestState {
+        GuestState {
+            mmu: self.mmu.snapshot(),
+            ppu: self.ppu.snapshot(),
+            apu: self.apu.snapshot(),
+            gamepad: self.gamepad.snapshot(),
+        }
+    }
+
+    /// Put the guest back to a snapshot taken while running the same ROM. States usually come
+    /// from a file, so one that doesn't fit this ROM (or is corrupt) is an error rather than a
+    /// panic. The guest is left as it was.
+    pub fn restore(&mut self, state: &GuestState) -> Result<(), String> {
+        let previous = self.snapshot();
+        if let Err(e) = self.restore_parts(state) {
+            self.restore_parts(&previous)
+                .expect("A snapshot of the running guest should restore.");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn restore_parts(&mut self, state: &GuestState) -> Result<(), String> {
+        self.mmu.restore(&state.mmu)?;
+        self.ppu.restore(&state.ppu)?;
+        self.apu.restore(&state.apu);
+        self.gamepad.restore(&state.gamepad);
+        Ok(())
+    }
+
+    /// Every byte the guest has sent over the serial port so far.
+    pub fn serial_output(&self) -> &[u8] {
+        &self.mmu.serial_output
+    }
+
+    /// Counts of what's been emulated so far, along with the serial log.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            instructions: self.cpu.instruction_count(),
+            frames: self.frame_count,
+            serial_output: String::from_utf8_lossy(&self.mmu.serial_output).into_owned(),
+        }
+    }
+
     pub fn run_forever(&mut self) {
         'program: loop {
             // Handle program I/O (events that affect the emulator). This needs to be
-            match self.input.get_event() {
-                InputEvent::Exit => break 'program,
-                InputEvent::Panic => panic!("Panic caused by user."),
-                _ => (),
+            if let Some(input) = &mut self.input {
+                match input.get_event() {
+                    InputEvent::Exit => break 'program,
+                    InputEvent::Panic => panic!("Panic caused by user."),
+                    InputEvent::ToggleHud => self.hud.toggle(),
+                    InputEvent::CyclePalette => self.cycle_palette(),
+                    InputEvent::Screenshot => self.screenshot(),
+                    InputEvent::Continue => self.cpu.resume(),
+                    InputEvent::Step if self.cpu.is_paused() => self.step_paused(),
+                    _ => (),
+                }
+            }
+
+            // Paused on an unimplemented opcode: wait for the user to step or continue.
+            if self.cpu.is_paused() {
+                self.draw_frame();
+                std::thread::sleep(std::time::Duration::from_millis(16));
+                continue 'program;
+            }
+
+            // While turbo is held, emulate several frames for every one that's presented.
+            let turbo = match &self.input {
+                Some(input) => input.is_turbo_held(),
+                None => false,
+            };
+            let frames = if turbo { TURBO_FRAMES } else { 1 };
+            let frame_start = Instant::now();
+
+            let mut samples = Vec::new();
+            for _ in 0..frames {
+                self.emulate_frame();
+                samples.extend(self.drain_audio());
+            }
+
+            self.play_audio(samples, frames);
+            self.draw_frame();
+
+            // A game halted until VBlank has nothing more to do this frame. Rather than racing on
+            // to the next one, sleep until it's due. (Without vsync, nothing else would wait.)
+            if self.idle_sleep && !turbo && self.mmu.interrupts.is_waiting_for_vblank() {
+                let frame_time = Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / CPU_FREQ as f64);
+                if let Some(remaining) = frame_time.checked_sub(frame_start.elapsed()) {
+                    std::thread::sleep(remaining);
+                }
             }
-            self.emulate_frame();
         }
+
+        self.shutdown();
     }
 
-    /// Emulate one whole frame work of CPU, PPU, Timer work. Given 60fps, 1 frame is 1/60 of the
-    /// CPU clock speed worth of work:
-    fn emulate_frame(&mut self) {
-        let mmu = &mut self.mmu;
+    /// Everything that needs doing before exiting: persist battery-backed cartridge RAM (games
+    /// without any don't leave an empty save behind) and let queued audio finish playing.
+    pub fn shutdown(&mut self) {
+        if let Err(e) = self.mmu.save_cartridge() {
+            eprintln!("{}", e);
+        }
+
+        if let Some(audio) = &self.audio {
+            audio.flush();
+        }
+    }
+
+    /// Switch to the next palette mode: configured, grayscale, then high contrast.
+    fn cycle_palette(&mut self) {
+        self.palette_mode = self.palette_mode.next();
+        println!("Palette: {:?}", self.palette_mode);
+
+        if let Some(screen) = &mut self.screen {
+            screen.set_palette(self.palette_mode.palette(
+                self.palette,
+                self.contrast,
+                self.high_contrast_threshold,
+            ));
+        }
+    }
+
+    /// Save the current frame (without the HUD) to a PNG in the working directory, named after
+    /// when it was taken.
+    fn screenshot(&self) {
+        if let Some(screen) = &self.screen {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0);
+            let path = format!("screenshot-{}.png", millis);
+
+            match screen.save_screenshot(&self.ppu.image_buffer, &path) {
+                Ok(()) => println!("Saved screenshot to {}", path),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+
+    /// While paused, run a single instruction and show where that leaves the CPU.
+    fn step_paused(&mut self) {
+        self.cpu.resume();
+        let cycles = self.cpu.step(&mut self.mmu);
+        self.timer.step(&mut self.mmu, cycles);
+        self.ppu.step(&mut self.mmu, cycles);
+        self.apu.step(&mut self.mmu, cycles);
+        self.apu.output_buffer.clear();
+
+        // Stepping onto another unimplemented opcode has already paused and dumped the state.
+        if !self.cpu.is_paused() {
+            eprintln!("{}", self.cpu.dump_context(&self.mmu, self.mmu.pc));
+            self.cpu.pause();
+        }
+    }
+
+    /// Emulate one whole frame work of CPU, PPU, Timer work: everything up until the PPU enters
+    /// VBlank. A DMG frame is 70224 cycles, which is slightly less than 1/60 of the CPU clock speed
+    /// (the real refresh rate is ~59.73Hz). Return the number of cycles emulated.
+    fn emulate_frame(&mut self) -> usize {
         let mut cycle_count: usize = 0;
 
-        // Update gamepad input state. Do this at 60hz to save on CPU.
-        let gamepad_state = self.input.get_gamepad_state();
-        self.gamepad.update_state(gamepad_state);
-
-        'frame: loop {
-            // Advance each emulator system one opcode (step).
-            // The length of the step depends on what opcode is executed.
-            self.gamepad.step(mmu);
-            let cycles = self.cpu.step(mmu);
-            self.timer.step(mmu, cycles);
-            self.ppu.step(mmu, cycles);
-            self.apu.step(mmu, cycles);
-
-            // 4Mhz cpu at 60fps.
-            cycle_count += cycles as usize;
-            if cycle_count >= (CPU_FREQ / FRAMERATE) {
-                break 'frame;
+        // Update gamepad input state. Do this at 60hz to save on CPU. Scripted presses are added
+        // to whatever keys the host has held.
+        let mut keys = match &self.input {
+            Some(input) => input.get_gamepad_state(),
+            None => [false; 8],
+        };
+        for (key, frames) in keys.iter_mut().zip(self.autorelease.iter()) {
+            *key |= *frames > 0;
+        }
+        self.gamepad.update_state(keys);
+
+        // Advance each emulator system one opcode (step) at a time, until the frame is done. Stop
+        // early if the CPU paused on an unimplemented opcode.
+        loop {
+            let outcome = self.step();
+            cycle_count += outcome.cycles as usize;
+            if outcome.frame_completed || self.cpu.is_paused() {
+                break;
             }
         }
 
+        for frames in self.autorelease.iter_mut() {
+            *frames = frames.saturating_sub(1);
+        }
+
+        cycle_count
+    }
+
+    /// Downsample the emulator's audio sample buffer into host-rate (mono) samples.
+    fn drain_audio(&mut self) -> Vec<f32> {
+        // Without an audio device there's nothing to drain the samples into, so discard them.
+        if self.audio.is_none() {
+            self.apu.output_buffer.clear();
+            return Vec::new();
+        }
+
+        let mut samples = Vec::new();
         let mut remainder: f64 = 0.0;
 
-        // Drain the entire contents of the emulator's audio sample buffer into the host's buffer.
-        // Recall: the host accepts a vector of any size, but it feeds that vector into an MPSC
-        // that will block when full.  The audio device will drain this buffer in a separate thread.
+        // Drain the entire contents of the emulator's audio sample buffer.
         while self.apu.output_buffer.len() >= APU_SAMPLES_PER_AUDIO_SAMPLE.floor() as usize {
             remainder += APU_SAMPLES_PER_AUDIO_SAMPLE.fract();
 
@@ -108,7 +490,7 @@ impl Emulator {
                 .drain(0..APU_SAMPLES_PER_AUDIO_SAMPLE.floor() as usize)
                 .collect();
             let y: f32 = x.iter().map(|n| n[0]).sum::<f32>() / x.len() as f32;
-            self.audio.enqueue([y / 4.0, y / 4.0]);
+            samples.push(y);
             // TODO: doing a lot of probably inefficient work here, and cutting out audio channel.
 
             // The number of samples that makes up 1 APU sample isn't necessarily evenly divisible.
@@ -120,13 +502,384 @@ impl Emulator {
             }
         }
 
-        // Draw the frame.  Note that vsync is enabled so this is ultimately what governs the
-        // rate of this emulator. The SDL drawing routine will block for the next frame. This also
-        // means that if the framerate goverened by v-sync isn't 60fps, this emulator won't work
-        // right. That's okay for my purposes. Check out some other emulators for other ways to
-        // handle this.  the rboy Rust emulator uses a thread to ping on a regular interval. The
-        // main loop can block on awaiting that ping. There's probably also a really smart way
-        // to handle it using async/await.
-        self.screen.update(&self.ppu.image_buffer);
+        samples
+    }
+
+    /// Send samples to the host's audio device. `frames` is how many emulated frames the samples
+    /// cover: when it's more than one (turbo), they're squeezed into one frame's worth of time.
+    /// Recall: the host accepts samples of any amount, but it feeds them into a queue that the
+    /// audio device will drain in a separate thread.
+    fn play_audio(&self, samples: Vec<f32>, frames: usize) {
+        let audio = match &self.audio {
+            Some(audio) => audio,
+            None => return,
+        };
+
+        let samples = if frames <= 1 {
+            samples
+        } else if self.turbo_preserve_pitch {
+            time_stretch(&samples, frames as f64)
+        } else {
+            speed_up(&samples, frames)
+        };
+
+        for y in samples {
+            audio.enqueue([y, y]);
+        }
+    }
+
+    /// Draw the frame.  Note that vsync is enabled so this is ultimately what governs the
+    /// rate of this emulator. The SDL drawing routine will block for the next frame. This also
+    /// means that if the framerate goverened by v-sync isn't 60fps, this emulator won't work
+    /// right. That's okay for my purposes. Check out some other emulators for other ways to
+    /// handle this.  the rboy Rust emulator uses a thread to ping on a regular interval. The
+    /// main loop can block on awaiting that ping. There's probably also a really smart way
+    /// to handle it using async/await.
+    fn draw_frame(&mut self) {
+        if let Some(screen) = &mut self.screen {
+            self.hud.tick();
+
+            if self.hud.enabled {
+                // Draw the HUD over a copy so the emulated frame itself is left untouched.
+                let mut buffer = self.ppu.image_buffer;
+                let (rom_bank, _) = self.mmu.current_banks();
+                self.hud
+                    .draw(&mut buffer, self.mmu.ppu.mode.bits(), rom_bank);
+                screen.update(&buffer);
+            } else {
+                screen.update(&self.ppu.image_buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest::NINTENDO_LOGO;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_emulate_frame_is_one_ppu_frame() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+
+        // Spin on `JR -2` in work RAM (12 cycles, which divides a frame evenly) with no interrupts.
+        emulator.mmu.wb(0xC000, 0x18);
+        emulator.mmu.wb(0xC001, 0xFE);
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0;
+        emulator.mmu.ppu.lcd_on = true;
+
+        // The first frame runs up to the first VBlank, wherever the PPU started.
+        emulator.emulate_frame();
+        assert_eq!(emulator.mmu.ppu.line, 144);
+        assert_eq!(emulator.mmu.ppu.mode, PpuMode::VBlank);
+
+        // Every frame after that is exactly one whole PPU frame.
+        for _ in 0..3 {
+            assert_eq!(emulator.emulate_frame(), CYCLES_PER_FRAME);
+            assert_eq!(emulator.mmu.ppu.line, 144);
+            assert_eq!(emulator.mmu.ppu.mode, PpuMode::VBlank);
+        }
+    }
+
+    #[test]
+    fn test_step_outcome() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        let program = [
+            0x3E, 0x81, // LD A,0x81
+            0xE0, 0x02, // LDH (SC),A: send SB over serial.
+            0x18, 0xFE, // JR -2
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            emulator.mmu.wb(0xC000 + i as u16, *byte);
+        }
+        emulator.mmu.wb(0xFF01, b'!');
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0x01; // VBlank.
+        emulator.mmu.interrupts.intf = 0;
+        emulator.mmu.ppu.lcd_on = true;
+
+        let outcome = emulator.step();
+        assert_eq!(outcome.cycles, 8);
+        assert_eq!(outcome.serial, None);
+        assert_eq!(emulator.step().serial, Some(b'!'));
+
+        // Spin until the PPU enters VBlank, which also completes the frame.
+        let outcome = loop {
+            let outcome = emulator.step();
+            if outcome.vblank {
+                break outcome;
+            }
+            assert_eq!(
+                outcome,
+                StepOutcome {
+                    cycles: 12,
+                    ..StepOutcome::default()
+                }
+            );
+        };
+        assert!(outcome.frame_completed);
+        assert_eq!(outcome.interrupt, None);
+        assert_eq!(emulator.mmu.ppu.line, 144);
+        assert_eq!(emulator.summary().frames, 1);
+
+        // The VBlank interrupt is serviced on the next step.
+        assert_eq!(emulator.step().interrupt, Some(0x0040));
+        assert_eq!(emulator.step().interrupt, None);
+    }
+
+    #[test]
+    fn test_ppu_status() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.mmu.wb(0xC000, 0x18); // JR -2
+        emulator.mmu.wb(0xC001, 0xFE);
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0;
+        emulator.mmu.ppu.lcd_on = true;
+        emulator.mmu.ppu.lyc = 144;
+
+        // A frame ends as the PPU enters VBlank on line 144.
+        emulator.emulate_frame();
+        assert_eq!(emulator.ppu_status(), (PpuMode::VBlank, 144, true));
+
+        emulator.mmu.ppu.lyc = 0;
+        assert_eq!(emulator.ppu_status(), (PpuMode::VBlank, 144, false));
+    }
+
+    #[test]
+    fn test_ime_state() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        for (i, opcode) in [0xF3, 0x00, 0xFB, 0x00, 0x00].iter().enumerate() {
+            emulator.mmu.wb(0xC000 + i as u16, *opcode); // DI, NOP, EI, NOP, NOP
+        }
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0;
+
+        // Both DI and EI take effect after the opcode that follows them.
+        let expected = [
+            (true, 2, 0),
+            (true, 1, 0),
+            (false, 0, 2),
+            (false, 0, 1),
+            (true, 0, 0),
+        ];
+        for state in expected.iter() {
+            emulator.cpu.step(&mut emulator.mmu);
+            assert_eq!(emulator.ime_state(), *state);
+        }
+    }
+
+    #[test]
+    fn test_button_autorelease() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.mmu.wb(0xC000, 0x18); // JR -2
+        emulator.mmu.wb(0xC001, 0xFE);
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0;
+        emulator.mmu.ppu.lcd_on = true;
+        emulator.mmu.wb(0xFF00, 0x10); // Select the buttons row.
+
+        emulator.set_button_with_autorelease(Button::Start);
+        emulator.emulate_frame();
+        assert_eq!(emulator.mmu.rb(0xFF00) & 0x0F, 0x07); // Start is bit 3, pressed is 0.
+
+        emulator.emulate_frame();
+        assert_eq!(emulator.mmu.rb(0xFF00) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn test_shutdown_saves() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1 with RAM and a battery.
+        rom[0x149] = 0x02; // 8KB of RAM.
+        let rom_path = env::temp_dir().join("gameboy_test_shutdown.gb");
+        fs::write(&rom_path, rom).unwrap();
+        let sav = env::temp_dir().join("gameboy_test_shutdown.sav");
+        let _ = fs::remove_file(&sav);
+
+        let options = EmulatorOptions::default()
+            .rom_path(rom_path.to_str().unwrap())
+            .use_bootrom(false)
+            .headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.mmu.wb(0x0000, 0x0A); // Enable RAM.
+        emulator.mmu.wb(0xA000, 0x42);
+        emulator.shutdown();
+        assert_eq!(fs::read(&sav).unwrap()[0], 0x42);
+
+        // And it's loaded again next time.
+        let options = EmulatorOptions::default()
+            .rom_path(rom_path.to_str().unwrap())
+            .use_bootrom(false)
+            .headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.mmu.wb(0x0000, 0x0A);
+        assert_eq!(emulator.mmu.rb(0xA000), 0x42);
+        fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn test_summary() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        assert_eq!(emulator.summary().instructions, 0);
+
+        emulator.mmu.wb(0xC000, 0x18); // JR -2
+        emulator.mmu.wb(0xC001, 0xFE);
+        emulator.mmu.pc = 0xC000;
+        emulator.mmu.interrupts.inte = 0;
+        emulator.mmu.ppu.lcd_on = true;
+        emulator.run_frames(3);
+
+        let summary = emulator.summary();
+        assert_eq!(summary.frames, 3);
+        assert!(summary.instructions > 0);
+        assert_eq!(summary.serial_output, "");
+    }
+
+    #[test]
+    fn test_peek_disassembly() {
+        let options = EmulatorOptions::default().use_bootrom(false).headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        let program = [0x3E, 0x42, 0xEA, 0x00, 0xD0, 0x18, 0xF9]; // LD A, LD (a16),A, JR -7.
+        for (n, byte) in program.iter().enumerate() {
+            emulator.mmu.wb(0xC000 + n as u16, *byte);
+        }
+        emulator.mmu.pc = 0xC000;
+
+        let instructions = emulator.peek_disassembly(3);
+        assert_eq!(instructions[0], (0xC000, String::from("LD A,0x42")));
+        assert_eq!(instructions[1], (0xC002, String::from("LD (0xd000),A")));
+        assert_eq!(instructions[2], (0xC005, String::from("JR -7")));
+        assert_eq!(emulator.mmu.pc, 0xC000); // Nothing was run.
+    }
+
+    /// Boot a ROM with a blank header using a boot ROM that, like the real one, compares the
+    /// cartridge's logo against its own copy and locks up if they differ. Return where PC ends up.
+    fn boot_blank_rom(name: &str, patch_boot_logo: bool) -> u16 {
+        #[rustfmt::skip]
+        let program = [
+            0x21, 0x04, 0x01, // LD HL,0x0104: the cartridge's logo.
+            0x11, 0xA8, 0x00, // LD DE,0x00A8: the boot ROM's logo.
+            0x1A,             // LD A,(DE)
+            0x13,             // INC DE
+            0xBE,             // CP (HL)
+            0x20, 0x09,       // JR NZ,lock
+            0x23,             // INC HL
+            0x7D,             // LD A,L
+            0xFE, 0x34,       // CP 0x34
+            0x20, 0xF5,       // JR NZ,-11: compare the next byte.
+            0xC3, 0xFC, 0x00, // JP 0x00FC: the logo matched.
+            0x18, 0xFE,       // lock: JR -2
+        ];
+        let mut boot_rom = [0; 0x100];
+        boot_rom[..program.len()].copy_from_slice(&program);
+        boot_rom[0xA8..0xD8].copy_from_slice(&NINTENDO_LOGO);
+        boot_rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]); // Disable the boot ROM.
+
+        let boot_rom_path = env::temp_dir().join(format!("gameboy_{}_boot.bin", name));
+        let rom_path = env::temp_dir().join(format!("gameboy_{}.gb", name));
+        fs::write(&boot_rom_path, &boot_rom[..]).unwrap();
+        fs::write(&rom_path, vec![0; 0x8000]).unwrap();
+
+        let options = EmulatorOptions::default()
+            .rom_path(rom_path.to_str().unwrap())
+            .boot_rom_path(boot_rom_path.to_str().unwrap())
+            .patch_boot_logo(patch_boot_logo)
+            .headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.mmu.interrupts.inte = 0;
+
+        for _ in 0..1000 {
+            if emulator.mmu.pc == 0x0100 {
+                break;
+            }
+            emulator.cpu.step(&mut emulator.mmu);
+        }
+        emulator.mmu.pc
+    }
+
+    #[test]
+    fn test_patch_boot_logo() {
+        assert_eq!(boot_blank_rom("bad_logo_patched", true), 0x0100);
+        assert_eq!(boot_blank_rom("bad_logo", false), 0x0014); // Locked up.
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        // Endlessly increment every byte of tile data. The background is all tile 0, so the screen
+        // keeps changing.
+        let program = [
+            0x21, 0x00, 0x80, // LD HL,0x8000
+            0x34, // INC (HL)
+            0x23, // INC HL
+            0x7C, // LD A,H
+            0xFE, 0x98, // CP 0x98
+            0x20, 0xF9, // JR NZ,-7
+            0x18, 0xF4, // JR -12
+        ];
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let path = env::temp_dir().join("gameboy_snapshot_restore.gb");
+        fs::write(&path, rom).unwrap();
+
+        let options = EmulatorOptions::default()
+            .rom_path(path.to_str().unwrap())
+            .use_bootrom(false)
+            .headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        emulator.run_frames(3);
+
+        let state = emulator.snapshot();
+        emulator.run_frames(1);
+        let frame = emulator.frame_buffer().to_vec();
+        let registers = emulator.registers();
+
+        emulator.run_frames(2);
+        assert_ne!(emulator.frame_buffer().to_vec(), frame);
+
+        // Go through serialization, like a save state file would.
+        let json = serde_json::to_string(&state).unwrap();
+        emulator
+            .restore(&serde_json::from_str(&json).unwrap())
+            .unwrap();
+        emulator.run_frames(1);
+        assert_eq!(emulator.frame_buffer().to_vec(), frame);
+        assert_eq!(emulator.registers(), registers);
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_state() {
+        let rom_path = env::temp_dir().join("gameboy_test_bad_state.gb");
+        fs::write(&rom_path, vec![0; 0x8000]).unwrap();
+        let options = EmulatorOptions::default()
+            .rom_path(rom_path.to_str().unwrap())
+            .use_bootrom(false)
+            .headless(true);
+        let mut emulator = Emulator::new(options).unwrap();
+        let state = emulator.snapshot();
+        emulator.run_frames(1);
+        let registers = emulator.registers();
+        let pc = emulator.mmu.pc;
+
+        let mut truncated = state.clone();
+        truncated.mmu.vram.truncate(100);
+        assert!(emulator.restore(&truncated).is_err());
+
+        // The MMU's part fits but the PPU's doesn't: the MMU isn't left restored.
+        let mut truncated = state.clone();
+        truncated.ppu.image_buffer.clear();
+        assert!(emulator.restore(&truncated).is_err());
+        assert_eq!(emulator.registers(), registers);
+        assert_eq!(emulator.mmu.pc, pc);
+
+        assert!(emulator.restore(&state).is_ok());
     }
 }
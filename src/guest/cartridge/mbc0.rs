@@ -1,4 +1,4 @@
-use super::Mbc;
+use super::{read_rom, Mbc};
 
 pub struct Mbc0 {
     data: Vec<u8>,
@@ -13,9 +13,9 @@ impl Mbc0 {
 /// MBC 0 is a simple controller for cartridges with 16KB of ROM and no RAM. The one and only
 /// memory bank is fully addressable so nothing fancy has to happen.
 impl Mbc for Mbc0 {
-    /// Read 0x000 - 0x7FFF directly.
+    /// Read 0x000 - 0x7FFF directly. Small (eg. test) ROMs might not fill the space.
     fn rb(&self, address: u16) -> u8 {
-        self.data[address as usize]
+        read_rom(&self.data, address as usize)
     }
 
     fn wb(&mut self, _address: u16, _value: u8) {}
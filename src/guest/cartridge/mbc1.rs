@@ -1,18 +1,79 @@
-use super::Mbc;
+use super::{check_mbc_state, read_rom, wrap_rom_bank, Mbc, NINTENDO_LOGO};
 
 pub struct Mbc1 {
     data: Vec<u8>,
-    ram: [u8; 0x2000],
+    ram: Vec<u8>,
+    ram_enabled: bool,   // Writing 0x_A to 0x0000-0x1FFF enables RAM.
     rom_bank_number: u8, // A 5-bit register that selects which ROM bank (0x01-0x1F)
+    ram_bank_number: u8, // A 2-bit register that selects which RAM bank (0x00-0x03) in mode 1.
+    banking_mode: bool,  // 0: RAM bank 0 is always mapped. 1: RAM banking via ram_bank_number.
+    multicart: bool,     // MBC1M wiring: the 2-bit register is ROM bank bits 4-5, not 5-6.
 }
 
 impl Mbc1 {
     pub fn new(data: Vec<u8>) -> Self {
+        let multicart = is_multicart(&data);
+        if multicart {
+            println!("Detected an MBC1 multicart (MBC1M).");
+        }
+
+        Self::with_multicart(data, multicart)
+    }
+
+    /// Initialise with explicit MBC1M wiring rather than detecting it from the ROM.
+    pub fn with_multicart(data: Vec<u8>, multicart: bool) -> Self {
+        // Up to 4 banks (32KB) of RAM. Always have at least one bank, so that games which don't
+        // declare RAM but touch it anyway don't crash.
+        let ram_size = match data[0x149] {
+            0x03 => 0x8000,
+            _ => 0x2000,
+        };
+
         Self {
             data,
-            ram: [0; 0x2000], // TODO: this can actually be up to 4 banks (32KB).
+            ram: vec![0; ram_size],
+            ram_enabled: false,
             rom_bank_number: 0x01,
+            ram_bank_number: 0x00,
+            banking_mode: false,
+            multicart,
+        }
+    }
+
+    /// The ROM bank mapped to 0x4000-0x7FFF. The 5-bit register can't select bank 0 (writing 0
+    /// selects bank 1 instead), and the 2-bit register supplies bits 5-6 for ROMs over 512KB.
+    ///
+    /// MBC1M multicarts don't connect the 5-bit register's top bit, and wire the 2-bit register to
+    /// bits 4-5 instead, so that each game in the collection gets its own 16 banks. Bank 0 is still
+    /// detected using all 5 bits.
+    fn rom_bank(&self) -> usize {
+        let lower = match self.rom_bank_number {
+            0 => 1,
+            n => n as usize,
+        };
+
+        let bank = if self.multicart {
+            (self.ram_bank_number as usize) << 4 | (lower & 0x0F)
+        } else {
+            (self.ram_bank_number as usize) << 5 | lower
+        };
+
+        wrap_rom_bank(&self.data, bank)
+    }
+
+    /// The ROM bank mapped to 0x0000-0x3FFF. Usually bank 0, but in mode 1 the 2-bit register
+    /// also applies here, selecting bank 0x20/0x40/0x60 (or 0x10/0x20/0x30 on a multicart).
+    fn zero_bank(&self) -> usize {
+        if !self.banking_mode {
+            return 0;
         }
+
+        let shift = if self.multicart { 4 } else { 5 };
+        wrap_rom_bank(&self.data, (self.ram_bank_number as usize) << shift)
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        (self.ram_bank() * 0x2000 + (address - 0xA000) as usize) % self.ram.len()
     }
 }
 
@@ -20,20 +81,19 @@ impl Mbc for Mbc1 {
     /// Read 0x0000 - 0x3FFF directly. Read 0x4000 - 0x7FFF from the currently active memory bank.
     fn rb(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0x3FFF => self.data[address as usize],
+            0x0000..=0x3FFF => read_rom(&self.data, 0x4000 * self.zero_bank() + address as usize),
             0x4000..=0x7FFF => {
                 // Offset the ROM bank addressing based on which bank is active.
                 // For example, if ROM bank 2 is selected (the third 16KB), the offset is 32KB.
                 // The address begins at 0x4000 so we subtract 1 bank.  Bank 0 cannot be accessed
                 // from here.
 
-                let offset = 0x4000 * self.rom_bank_number as usize;
-                self.data[(address as usize - 0x4000) + offset]
-            }
-            0xA000..=0xBFFF => {
-                println!("Read RAM");
-                self.ram[(address - 0xA000) as usize]
+                let offset = 0x4000 * self.rom_bank();
+                read_rom(&self.data, (address as usize - 0x4000) + offset)
             }
+            // Disabled RAM isn't driving the bus, so reads are open bus.
+            0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
+            0xA000..=0xBFFF => self.ram[self.ram_address(address)],
             _ => {
                 panic!("Tried to read from {:#x} which is not mapped.", address);
             }
@@ -42,13 +102,17 @@ impl Mbc for Mbc1 {
 
     fn wb(&mut self, address: u16, value: u8) {
         match address {
-            0x0000..=0x1FFF => panic!("Tried to write to RAM enable bit."),
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
             0x2000..=0x3FFF => {
                 let bank = value & 0x1F; // Mask out top 3 bits.
                 self.rom_bank_number = bank;
             }
+            0x4000..=0x5FFF => self.ram_bank_number = value & 0x03,
+            0x6000..=0x7FFF => self.banking_mode = value & 0x01 == 1,
+            0xA000..=0xBFFF if !self.ram_enabled => (),
             0xA000..=0xBFFF => {
-                self.ram[(address - 0xA000) as usize] = value;
+                let address = self.ram_address(address);
+                self.ram[address] = value;
             }
             _ => panic!(
                 "Unsupported write to MBC1. Address {:#x}. Value {:#x}",
@@ -56,4 +120,181 @@ impl Mbc for Mbc1 {
             ),
         }
     }
+
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.banking_mode {
+            self.ram_bank_number as usize
+        } else {
+            0
+        }
+    }
+
+    fn current_banks(&self) -> (u16, u8) {
+        (self.rom_bank() as u16, self.ram_bank() as u8)
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        vec![
+            self.rom_bank_number,
+            self.ram_bank_number,
+            self.banking_mode as u8,
+            self.ram_enabled as u8,
+        ]
+    }
+
+    fn restore(&mut self, registers: &[u8], ram: &[u8]) -> Result<(), String> {
+        check_mbc_state(registers, 4..=4, ram, self.ram.len())?;
+        self.rom_bank_number = registers[0];
+        self.ram_bank_number = registers[1];
+        self.banking_mode = registers[2] == 1;
+        self.ram_enabled = registers[3] == 1;
+        self.ram.copy_from_slice(ram);
+        Ok(())
+    }
+}
+
+/// MBC1M multicarts can't be told apart by their header: they claim to be a normal 1MB MBC1 ROM.
+/// Each game in the collection has its own header though, so look for a second Nintendo logo at
+/// the start of bank 0x10, where the second game would begin.
+fn is_multicart(data: &[u8]) -> bool {
+    data.len() == 0x100000 && data[0x40104..0x40134] == NINTENDO_LOGO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered_rom;
+    use super::*;
+
+    #[test]
+    fn test_multicart_bank_math() {
+        let mut mbc1 = Mbc1::with_multicart(numbered_rom(64), false);
+        let mut mbc1m = Mbc1::with_multicart(numbered_rom(64), true);
+
+        for mbc in [&mut mbc1, &mut mbc1m].iter_mut() {
+            mbc.wb(0x2000, 0x12);
+            mbc.wb(0x4000, 0x01);
+        }
+
+        // Standard: 0x01 << 5 | 0x12. Multicart: 0x01 << 4 | (0x12 & 0x0F).
+        assert_eq!(mbc1.rb(0x4000), 0x32);
+        assert_eq!(mbc1m.rb(0x4000), 0x12);
+
+        // In mode 1 the lower area is switched too: to the start of the selected game on MBC1M.
+        for mbc in [&mut mbc1, &mut mbc1m].iter_mut() {
+            mbc.wb(0x6000, 0x01);
+        }
+        assert_eq!(mbc1.rb(0x0000), 0x20);
+        assert_eq!(mbc1m.rb(0x0000), 0x10);
+
+        // Writing 0x10 is not bank 0 (all 5 bits count), so no bump to bank 1 on MBC1M.
+        mbc1m.wb(0x2000, 0x10);
+        assert_eq!(mbc1m.rb(0x4000), 0x10);
+    }
+
+    #[test]
+    fn test_ram_enable() {
+        let mut data = numbered_rom(128);
+        data[0x149] = 0x03;
+        let mut mbc = Mbc1::new(data);
+
+        // Disabled to begin with: writes are lost and reads are open bus.
+        mbc.wb(0xA000, 0x42);
+        assert_eq!(mbc.rb(0xA000), 0xFF);
+
+        // Only the low nibble counts, and only 0xA enables.
+        mbc.wb(0x1FFF, 0xFA);
+        mbc.wb(0xA000, 0x42);
+        assert_eq!(mbc.rb(0xA000), 0x42);
+        mbc.wb(0x0000, 0x0B);
+        assert_eq!(mbc.rb(0xA000), 0xFF);
+        mbc.wb(0x0000, 0x0A);
+        assert_eq!(mbc.rb(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_banking_mode() {
+        let mut data = numbered_rom(128);
+        data[0x149] = 0x03;
+        let mut mbc = Mbc1::new(data);
+        mbc.wb(0x0000, 0x0A);
+        mbc.wb(0xA000, 0x11);
+
+        // Mode 0: the 2-bit register is ROM bank bits 5-6, and RAM bank 0 stays mapped.
+        mbc.wb(0x2000, 0x01);
+        mbc.wb(0x4000, 0x02);
+        assert_eq!(mbc.rb(0x4000), 0x41);
+        assert_eq!(mbc.rb(0x0000), 0x00);
+        assert_eq!(mbc.rb(0xA000), 0x11);
+
+        // Mode 1: it selects the RAM bank too (and switches the lower ROM area).
+        mbc.wb(0x6000, 0x01);
+        assert_eq!(mbc.rb(0x4000), 0x41);
+        assert_eq!(mbc.rb(0x0000), 0x40);
+        mbc.wb(0xA000, 0x22);
+        assert_eq!(mbc.rb(0xA000), 0x22);
+
+        mbc.wb(0x6000, 0x00);
+        assert_eq!(mbc.rb(0xA000), 0x11);
+    }
+
+    #[test]
+    fn test_bank_zero_aliasing() {
+        let mut mbc = Mbc1::new(numbered_rom(128));
+
+        // Asking for bank 0x20, 0x40 or 0x60 writes 0 to the 5-bit register, which becomes 1. So
+        // the upper area gets 0x21, 0x41 or 0x61 instead.
+        for &upper in [0x00, 0x01, 0x02, 0x03].iter() {
+            mbc.wb(0x4000, upper);
+            mbc.wb(0x2000, 0x00);
+            assert_eq!(mbc.rb(0x4000), upper << 5 | 1);
+            mbc.wb(0x2000, 0x20); // Bit 5 isn't part of the 5-bit register.
+            assert_eq!(mbc.rb(0x4000), upper << 5 | 1);
+        }
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut mbc = Mbc1::new(numbered_rom(128));
+        mbc.wb(0x2000, 0x05);
+        let registers = mbc.registers();
+        let ram = mbc.ram().unwrap().to_vec();
+
+        // RAM was disabled when the state was taken, so it stays disabled.
+        let mut restored = Mbc1::new(numbered_rom(128));
+        restored.wb(0x0000, 0x0A);
+        assert!(restored.restore(&registers, &ram).is_ok());
+        assert_eq!(restored.rb(0x4000), 0x05);
+        assert_eq!(restored.rb(0xA000), 0xFF);
+
+        // An MBC2 or MBC3 state has a different number of registers, and RAM has to be the same
+        // size.
+        assert!(restored.restore(&registers[..3], &ram).is_err());
+        assert!(restored.restore(&[0; 9], &ram).is_err());
+        assert!(restored.restore(&registers, &ram[..0x100]).is_err());
+
+        // With RAM enabled it reads back.
+        mbc.wb(0x0000, 0x0A);
+        assert!(restored.restore(&mbc.registers(), &ram).is_ok());
+        assert_eq!(restored.rb(0xA000), 0x00);
+    }
+
+    #[test]
+    fn test_detect_multicart() {
+        let mut data = numbered_rom(64);
+        data[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        assert!(!is_multicart(&data));
+
+        // A second game's header, with its own logo, at bank 0x10.
+        data[0x40104..0x40134].copy_from_slice(&NINTENDO_LOGO);
+        assert!(is_multicart(&data));
+        assert!(!is_multicart(&data[..0x80000]));
+    }
 }
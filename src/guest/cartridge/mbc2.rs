@@ -0,0 +1,152 @@
+use super::{check_mbc_state, nonzero_rom_bank, read_rom, read_rom_bank, Mbc};
+
+const RAM_SIZE: usize = 0x200; // 512 half-bytes, built into the controller.
+
+pub struct Mbc2 {
+    data: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,   // Writing 0x_A with address bit 8 clear enables RAM.
+    rom_bank_number: u8, // A 4-bit register that selects the ROM bank (0x01-0x0F).
+}
+
+impl Mbc2 {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank_number: 0x01,
+        }
+    }
+
+    /// The ROM bank mapped to 0x4000-0x7FFF.
+    fn rom_bank(&self) -> usize {
+        nonzero_rom_bank(&self.data, self.rom_bank_number)
+    }
+
+    /// Only 9 address lines go to the RAM, so it repeats every 512 bytes through 0xA000-0xBFFF.
+    fn ram_address(address: u16) -> usize {
+        (address - 0xA000) as usize % RAM_SIZE
+    }
+}
+
+/// MBC2 has up to 16 ROM banks and 512x4 bits of RAM of its own. There's only one register range:
+/// bit 8 of the address decides whether a write to 0x0000-0x3FFF enables RAM (clear) or selects
+/// the ROM bank (set). Only the low 4 bits of each RAM byte exist.
+impl Mbc for Mbc2 {
+    fn rb(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => read_rom(&self.data, address as usize),
+            0x4000..=0x7FFF => read_rom_bank(&self.data, self.rom_bank(), address),
+            0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
+            0xA000..=0xBFFF => self.ram[Self::ram_address(address)] & 0x0F,
+            _ => panic!("Tried to read from {:#x} which is not mapped.", address),
+        }
+    }
+
+    fn wb(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank_number = value & 0x0F,
+            0x4000..=0x7FFF => (),
+            0xA000..=0xBFFF if !self.ram_enabled => (),
+            0xA000..=0xBFFF => self.ram[Self::ram_address(address)] = value & 0x0F,
+            _ => panic!(
+                "Unsupported write to MBC2. Address {:#x}. Value {:#x}",
+                address, value
+            ),
+        }
+    }
+
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    fn current_banks(&self) -> (u16, u8) {
+        (self.rom_bank() as u16, 0)
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        vec![self.rom_bank_number, self.ram_enabled as u8]
+    }
+
+    fn restore(&mut self, registers: &[u8], ram: &[u8]) -> Result<(), String> {
+        check_mbc_state(registers, 2..=2, ram, RAM_SIZE)?;
+        self.rom_bank_number = registers[0];
+        self.ram_enabled = registers[1] == 1;
+        self.ram.copy_from_slice(ram);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered_rom;
+    use super::*;
+
+    #[test]
+    fn test_address_bit_8_selects_register() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+        assert_eq!(mbc.rb(0x4000), 0x01);
+
+        // Bit 8 set: the ROM bank, anywhere in 0x0000-0x3FFF.
+        mbc.wb(0x2100, 0x05);
+        assert_eq!(mbc.rb(0x4000), 0x05);
+        mbc.wb(0x0100, 0x0A);
+        assert_eq!(mbc.rb(0x4000), 0x0A);
+        mbc.wb(0x3FFF, 0xF3); // Only 4 bits.
+        assert_eq!(mbc.rb(0x4000), 0x03);
+        mbc.wb(0x2100, 0x00);
+        assert_eq!(mbc.rb(0x4000), 0x01);
+
+        // Bank selection didn't enable RAM, even though 0x0A was written.
+        mbc.wb(0xA000, 0x07);
+        assert_eq!(mbc.rb(0xA000), 0xFF);
+
+        // Bit 8 clear: RAM enable, even above 0x2000. The ROM bank doesn't change.
+        mbc.wb(0x2000, 0x0A);
+        assert_eq!(mbc.rb(0x4000), 0x01);
+        mbc.wb(0xA000, 0x07);
+        assert_eq!(mbc.rb(0xA000), 0x07);
+        mbc.wb(0x00FF, 0x00);
+        assert_eq!(mbc.rb(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_ram_nibbles() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+        mbc.wb(0x0000, 0x0A);
+
+        // Only the low 4 bits are kept.
+        mbc.wb(0xA000, 0xAB);
+        assert_eq!(mbc.rb(0xA000), 0x0B);
+        mbc.wb(0xA1FF, 0xF0);
+        assert_eq!(mbc.rb(0xA1FF), 0x00);
+
+        // The 512 bytes repeat through 0xA000-0xBFFF.
+        assert_eq!(mbc.rb(0xA200), 0x0B);
+        mbc.wb(0xBE05, 0x3C);
+        assert_eq!(mbc.rb(0xA005), 0x0C);
+        assert_eq!(mbc.ram().unwrap().len(), 512);
+    }
+
+    #[test]
+    fn test_restore_rejects_other_cartridges() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+
+        // An MBC1 state: 4 registers and 8KB of RAM.
+        assert!(mbc
+            .restore(&[0x05, 0x00, 0x00, 0x01], &[0; 0x2000])
+            .is_err());
+        assert!(mbc.restore(&[0x05, 0x01], &[0; 0x2000]).is_err());
+        assert_eq!(mbc.rb(0x4000), 0x01);
+
+        assert!(mbc.restore(&[0x05, 0x01], &[0x0C; 512]).is_ok());
+        assert_eq!(mbc.rb(0x4000), 0x05);
+        assert_eq!(mbc.rb(0xA000), 0x0C);
+    }
+}
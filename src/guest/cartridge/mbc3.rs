@@ -0,0 +1,298 @@
+use super::rtc::{Clock, Rtc, SystemClock};
+use super::{check_mbc_state, nonzero_rom_bank, read_rom, read_rom_bank, Mbc};
+
+pub struct Mbc3 {
+    data: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Rtc,
+    ram_enabled: bool,   // Writing 0x_A to 0x0000-0x1FFF enables RAM and the RTC.
+    rom_bank_number: u8, // A 7-bit register that selects the ROM bank (0x01-0x7F).
+    ram_select: u8,      // Maps RAM bank 0x00-0x03 or RTC register 0x08-0x0C to 0xA000-0xBFFF.
+    latch_armed: bool,   // 0x00 was written to 0x6000-0x7FFF, so writing 0x01 next latches.
+    latched: [u8; 5],    // The RTC registers as of the last latch. Reads see these.
+}
+
+impl Mbc3 {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self::with_clock(data, Box::new(SystemClock))
+    }
+
+    /// Initialise with the RTC running off of `clock`, eg. one that tests can control.
+    pub fn with_clock(data: Vec<u8>, clock: Box<dyn Clock>) -> Self {
+        // Up to 4 banks (32KB) of RAM, with at least one, as for MBC1.
+        let ram_size = match data[0x149] {
+            0x03 => 0x8000,
+            _ => 0x2000,
+        };
+
+        Self {
+            data,
+            ram: vec![0; ram_size],
+            rtc: Rtc::new(clock),
+            ram_enabled: false,
+            rom_bank_number: 0x01,
+            ram_select: 0x00,
+            latch_armed: false,
+            latched: [0; 5],
+        }
+    }
+
+    /// The ROM bank mapped to 0x4000-0x7FFF. Unlike MBC1, all 7 bits are checked for bank 0, so
+    /// 0x20, 0x40 and 0x60 can be selected.
+    fn rom_bank(&self) -> usize {
+        nonzero_rom_bank(&self.data, self.rom_bank_number)
+    }
+
+    fn ram_address(&self, address: u16) -> usize {
+        (self.ram_bank() * 0x2000 + (address - 0xA000) as usize) % self.ram.len()
+    }
+}
+
+/// MBC3 banks like MBC1 (without its banking modes), and adds a real-time clock. The clock's
+/// registers are mapped to 0xA000-0xBFFF in place of RAM. The clock keeps running, so it's read
+/// by first latching: writing 0x00 then 0x01 to 0x6000-0x7FFF copies it to the registers.
+impl Mbc for Mbc3 {
+    fn rb(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => read_rom(&self.data, address as usize),
+            0x4000..=0x7FFF => read_rom_bank(&self.data, self.rom_bank(), address),
+            0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
+            0xA000..=0xBFFF => match self.ram_select {
+                0x00..=0x03 => self.ram[self.ram_address(address)],
+                0x08..=0x0C => self.latched[(self.ram_select - 0x08) as usize],
+                _ => 0xFF,
+            },
+            _ => panic!("Tried to read from {:#x} which is not mapped.", address),
+        }
+    }
+
+    fn wb(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank_number = value & 0x7F,
+            0x4000..=0x5FFF => self.ram_select = value,
+            0x6000..=0x7FFF => {
+                if self.latch_armed && value == 0x01 {
+                    self.latched = self.rtc.registers();
+                }
+                self.latch_armed = value == 0x00;
+            }
+            0xA000..=0xBFFF if !self.ram_enabled => (),
+            0xA000..=0xBFFF => match self.ram_select {
+                0x00..=0x03 => {
+                    let address = self.ram_address(address);
+                    self.ram[address] = value;
+                }
+                0x08..=0x0C => {
+                    let register = (self.ram_select - 0x08) as usize;
+                    self.rtc.write(register, value);
+                    self.latched[register] = value;
+                }
+                _ => (),
+            },
+            _ => panic!(
+                "Unsupported write to MBC3. Address {:#x}. Value {:#x}",
+                address, value
+            ),
+        }
+    }
+
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    /// The RAM bank last selected. While an RTC register is selected, that's still the bank that
+    /// will be mapped again once RAM is selected.
+    fn ram_bank(&self) -> usize {
+        match self.ram_select {
+            0x00..=0x03 => self.ram_select as usize,
+            _ => 0,
+        }
+    }
+
+    fn current_banks(&self) -> (u16, u8) {
+        (self.rom_bank() as u16, self.ram_bank() as u8)
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        let mut registers = vec![
+            self.rom_bank_number,
+            self.ram_select,
+            self.ram_enabled as u8,
+            self.latch_armed as u8,
+        ];
+        registers.extend_from_slice(&self.latched);
+        registers.extend_from_slice(&self.rtc.elapsed().to_le_bytes());
+        registers.push(self.rtc.is_halted() as u8);
+        registers
+    }
+
+    fn restore(&mut self, registers: &[u8], ram: &[u8]) -> Result<(), String> {
+        check_mbc_state(registers, 18..=18, ram, self.ram.len())?;
+        self.rom_bank_number = registers[0];
+        self.ram_select = registers[1];
+        self.ram_enabled = registers[2] == 1;
+        self.latch_armed = registers[3] == 1;
+        self.latched.copy_from_slice(&registers[4..9]);
+
+        let mut elapsed = [0; 8];
+        elapsed.copy_from_slice(&registers[9..17]);
+        self.rtc
+            .restore(u64::from_le_bytes(elapsed), registers[17] == 1);
+
+        self.ram.copy_from_slice(ram);
+        Ok(())
+    }
+
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        Some(self.rtc.save(&self.latched))
+    }
+
+    fn load_rtc(&mut self, data: &[u8]) {
+        self.latched = self.rtc.load(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered_rom;
+    use super::super::rtc::FixedClock;
+    use super::*;
+
+    #[test]
+    fn test_bank_selection() {
+        let mut data = numbered_rom(128);
+        data[0x149] = 0x03; // 4 banks of RAM.
+        let mut mbc = Mbc3::new(data);
+        assert_eq!(mbc.rb(0x4000), 0x01);
+
+        // All 7 bits select the bank: no skipping 0x20, 0x40 and 0x60 like MBC1.
+        for &bank in [0x02, 0x20, 0x40, 0x60, 0x7F].iter() {
+            mbc.wb(0x2000, bank);
+            assert_eq!(mbc.rb(0x4000), bank);
+        }
+        mbc.wb(0x2000, 0x00);
+        assert_eq!(mbc.rb(0x4000), 0x01);
+        mbc.wb(0x2000, 0x85); // The top bit isn't connected.
+        assert_eq!(mbc.rb(0x4000), 0x05);
+        assert_eq!(mbc.rb(0x0000), 0x00);
+
+        // RAM banks are separate, and there's no RAM until it's enabled.
+        mbc.wb(0xA000, 0x11);
+        assert_eq!(mbc.rb(0xA000), 0xFF);
+        mbc.wb(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.wb(0x4000, bank);
+            mbc.wb(0xA000, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            mbc.wb(0x4000, bank);
+            assert_eq!(mbc.rb(0xA000), 0x10 + bank);
+            assert_eq!(mbc.current_banks(), (0x05, bank));
+        }
+    }
+
+    #[test]
+    fn test_rtc_latch() {
+        let clock = FixedClock::default();
+        let mut data = numbered_rom(4);
+        data[0x149] = 0x03; // 4 banks of RAM.
+        let mut mbc = Mbc3::with_clock(data, Box::new(clock.clone()));
+        mbc.wb(0x0000, 0x0A);
+        clock.advance(3600 + 2 * 60 + 3);
+
+        // Nothing's been latched yet.
+        mbc.wb(0x4000, 0x08);
+        assert_eq!(mbc.rb(0xA000), 0);
+
+        mbc.wb(0x6000, 0x00);
+        mbc.wb(0x6000, 0x01);
+        let read = |mbc: &mut Mbc3| -> Vec<u8> {
+            (0x08..=0x0C)
+                .map(|register| {
+                    mbc.wb(0x4000, register);
+                    mbc.rb(0xA000)
+                })
+                .collect()
+        };
+        assert_eq!(read(&mut mbc), [3, 2, 1, 0, 0]);
+
+        // The latched value stays frozen while the clock runs on...
+        clock.advance(10);
+        assert_eq!(read(&mut mbc), [3, 2, 1, 0, 0]);
+
+        // ...and 0x01 alone doesn't latch: it has to follow 0x00.
+        mbc.wb(0x6000, 0x01);
+        assert_eq!(read(&mut mbc), [3, 2, 1, 0, 0]);
+        mbc.wb(0x6000, 0x00);
+        mbc.wb(0x6000, 0x01);
+        assert_eq!(read(&mut mbc), [13, 2, 1, 0, 0]);
+
+        // Writing a register sets the clock itself.
+        mbc.wb(0x4000, 0x0A);
+        mbc.wb(0xA000, 5);
+        clock.advance(3600);
+        mbc.wb(0x6000, 0x00);
+        mbc.wb(0x6000, 0x01);
+        assert_eq!(read(&mut mbc), [13, 2, 6, 0, 0]);
+    }
+
+    #[test]
+    fn test_restore_rejects_other_cartridges() {
+        let mut data = numbered_rom(4);
+        data[0x149] = 0x03; // 4 banks of RAM.
+        let mut mbc = Mbc3::new(data);
+        mbc.wb(0x2000, 0x03);
+        let registers = mbc.registers();
+        let ram = mbc.ram().unwrap().to_vec();
+
+        // An MBC1 state has 4 registers, and RAM has to be the same size.
+        let mut data = numbered_rom(4);
+        data[0x149] = 0x03; // 4 banks of RAM.
+        let mut restored = Mbc3::new(data);
+        assert!(restored.restore(&registers[..4], &ram).is_err());
+        assert!(restored.restore(&registers, &ram[..0x2000]).is_err());
+        assert_eq!(restored.rb(0x4000), 0x01);
+
+        assert!(restored.restore(&registers, &ram).is_ok());
+        assert_eq!(restored.rb(0x4000), 0x03);
+    }
+
+    #[test]
+    fn test_restore_rtc() {
+        let clock = FixedClock::default();
+        let mut mbc = Mbc3::with_clock(numbered_rom(4), Box::new(clock.clone()));
+        mbc.wb(0x0000, 0x0A);
+        mbc.wb(0x4000, 0x0A);
+        mbc.wb(0xA000, 2); // 2 hours in.
+        let registers = mbc.registers();
+        let ram = mbc.ram().unwrap().to_vec();
+
+        // A state taken 2 hours in carries on from there, however long ago it was taken.
+        clock.advance(86400);
+        let mut restored = Mbc3::with_clock(numbered_rom(4), Box::new(clock.clone()));
+        assert!(restored.restore(&registers, &ram).is_ok());
+        clock.advance(60);
+        restored.wb(0x6000, 0x00);
+        restored.wb(0x6000, 0x01);
+        assert_eq!(restored.rb(0xA000), 2);
+        restored.wb(0x4000, 0x09);
+        assert_eq!(restored.rb(0xA000), 1);
+
+        // So does a halted clock, which stays halted.
+        mbc.wb(0x4000, 0x0C);
+        mbc.wb(0xA000, 0x40);
+        assert!(restored.restore(&mbc.registers(), &ram).is_ok());
+        clock.advance(60);
+        restored.wb(0x6000, 0x00);
+        restored.wb(0x6000, 0x01);
+        restored.wb(0x4000, 0x0C);
+        assert_eq!(restored.rb(0xA000), 0x40);
+        restored.wb(0x4000, 0x09);
+        assert_eq!(restored.rb(0xA000), 1); // Still a minute past, as when it was halted.
+    }
+}
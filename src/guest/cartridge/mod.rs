@@ -1,76 +1,805 @@
-// mod mbc0;
-use std::fs::{metadata, File};
-use std::io::prelude::*;
-use std::str;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
 mod empty;
 mod mbc0;
 mod mbc1;
+mod mbc2;
+mod mbc3;
+mod patch;
+mod rtc;
 use empty::MbcEmpty;
 use mbc0::Mbc0;
 use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+
+const HEADER_END: usize = 0x150; // The cartridge header is 0x100-0x14F.
+
+/// The Nintendo logo every cartridge header contains at 0x104-0x133.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
 
 pub trait Mbc {
     fn rb(&self, address: u16) -> u8;
     fn wb(&mut self, address: u16, value: u8);
+
+    /// The cartridge's external RAM, if the controller has any.
+    fn ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The same RAM, to load a save into.
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// The RAM bank currently mapped to 0xA000-0xBFFF.
+    fn ram_bank(&self) -> usize {
+        0
+    }
+
+    /// The (ROM, RAM) banks currently mapped to 0x4000-0x7FFF and 0xA000-0xBFFF. Controllers
+    /// without banking always have ROM bank 1 and RAM bank 0 there.
+    fn current_banks(&self) -> (u16, u8) {
+        (1, self.ram_bank() as u8)
+    }
+
+    /// The controller's registers (eg. bank selection), in whatever order it likes, for saving
+    /// state. Controllers without any have nothing to save.
+    fn registers(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Put the registers and RAM back to what `registers` and `ram` returned. A state that's from
+    /// another kind of cartridge (or is corrupt) is an error, and leaves the controller as it was.
+    fn restore(&mut self, _registers: &[u8], _ram: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The real-time clock, as battery saves keep it after RAM. Controllers without one have
+    /// nothing to add.
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Set the real-time clock from what `save_rtc` returned.
+    fn load_rtc(&mut self, _data: &[u8]) {}
+}
+
+/// The cartridge's part of a `GuestState`. ROM isn't included: it never changes.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CartridgeState {
+    pub registers: Vec<u8>,
+    pub ram: Vec<u8>,
 }
 
 pub struct Cartridge {
     mbc: Box<dyn Mbc>,
+    has_battery: bool, // Header says RAM is battery-backed, so it should be saved.
+    has_rtc: bool,     // MBC3 with a timer: the battery keeps its clock running too.
+    save_path: Option<String>, // Where RAM is saved: the ROM's name with a .sav extension.
+    ram_bank_log: Option<Vec<String>>, // RAM bank switches, if logging them for debugging.
 }
 
 /// For now the cartridge is not inserted.
 impl Cartridge {
     /// Initialize the cartridge by determining from the header what memory bank controller to use.
     /// It is possible that no cartridge is installed.
-    pub fn new(cartridge_path: Option<&String>) -> Self {
-        let mbc: Box<dyn Mbc> = match cartridge_path {
+    #[cfg(test)] // The emulator always goes through `with_patch`.
+    pub fn new(cartridge_path: Option<&String>) -> Result<Self, String> {
+        Self::with_patch(cartridge_path, None)
+    }
+
+    /// Initialize the cartridge, first applying an IPS patch (eg. a ROM hack) to the ROM if there
+    /// is one. The patch can change the header, so it's applied before choosing the controller.
+    pub fn with_patch(
+        cartridge_path: Option<&String>,
+        patch_path: Option<&String>,
+    ) -> Result<Self, String> {
+        match cartridge_path {
             Some(path) => {
-                let data = Self::load_cartridge_data(path);
+                let mut data = Self::load_cartridge_data(path)?;
+                if let Some(patch_path) = patch_path {
+                    let patch = fs::read(patch_path)
+                        .map_err(|e| format!("Could not read patch {}: {}", patch_path, e))?;
+                    data = patch::apply_ips(&data, &patch)
+                        .map_err(|e| format!("Could not apply patch {}: {}", patch_path, e))?;
+                    if data.len() < HEADER_END {
+                        return Err(format!(
+                            "Patch {} leaves the ROM too small to contain a cartridge header.",
+                            patch_path
+                        ));
+                    }
+                }
+                let data = normalize_rom_size(data);
                 Self::report_cartridge_header(&data);
 
-                match &data[0x147] {
+                // A battery is only useful if there's also RAM for it to keep alive. MBC2 has RAM
+                // built in, so its header doesn't declare any.
+                let has_ram = data[0x149] != 0 || data[0x147] == 0x06;
+                let has_battery = is_battery_backed(data[0x147]) && has_ram;
+                let has_rtc = matches!(data[0x147], 0x0F | 0x10);
+
+                let mbc: Box<dyn Mbc> = match &data[0x147] {
                     0x00 => Box::new(Mbc0::new(data)),
-                    0x01 => Box::new(Mbc1::new(data)),
-                    // 0x03 => Box::new(Mbc3::new(data)),
-                    m => panic!("Tried to initialize non-supported MBC: {:x}", m),
-                }
+                    0x01..=0x03 => Box::new(Mbc1::new(data)),
+                    0x05 | 0x06 => Box::new(Mbc2::new(data)),
+                    0x0F..=0x13 => Box::new(Mbc3::new(data)),
+                    m => return Err(format!("Tried to initialize non-supported MBC: {:x}", m)),
+                };
+
+                let cartridge = Self {
+                    mbc,
+                    has_battery,
+                    has_rtc,
+                    save_path: Some(save_path(path, None)),
+                    ram_bank_log: None,
+                };
+                cartridge.verify_header_checksum();
+                Ok(cartridge)
             }
             None => {
                 println!("No cartridge provided.");
-                Box::new(MbcEmpty::new())
+                Ok(Self {
+                    mbc: Box::new(MbcEmpty::new()),
+                    has_battery: false,
+                    has_rtc: false,
+                    save_path: None,
+                    ram_bank_log: None,
+                })
             }
-        };
-
-        Self { mbc }
+        }
     }
 
     pub fn rb(&self, address: u16) -> u8 {
         self.mbc.rb(address)
     }
 
+    /// Read as though the cartridge header was valid: the Nintendo logo and header checksum read
+    /// as the values the boot ROM checks for. Lets homebrew without a proper header get past the
+    /// boot ROM, which otherwise locks up at the logo.
+    pub fn rb_valid_header(&self, address: u16) -> u8 {
+        match address {
+            0x0104..=0x0133 => NINTENDO_LOGO[(address - 0x0104) as usize],
+            0x014D => self.header_checksum(),
+            _ => self.rb(address),
+        }
+    }
+
+    /// The header checksum the boot ROM computes: `x = x - byte - 1` over 0x0134-0x014C.
+    fn header_checksum(&self) -> u8 {
+        (0x0134..=0x014C).fold(0u8, |checksum, address| {
+            checksum.wrapping_sub(self.rb(address)).wrapping_sub(1)
+        })
+    }
+
+    /// Does the header checksum at 0x014D match the header? The boot ROM locks up if it doesn't,
+    /// so a mismatch usually means a truncated or corrupt dump. Warn about it if so.
+    pub fn verify_header_checksum(&self) -> bool {
+        let expected = self.header_checksum();
+        let actual = self.rb(0x014D);
+        if expected != actual {
+            println!(
+                "Warning: header checksum is {:#04x} but the header sums to {:#04x}. The ROM may \
+                 be corrupt.",
+                actual, expected
+            );
+        }
+        expected == actual
+    }
+
     /// Write to ROM.  This isn't actually a write, but the attempt to write will control
     /// on-cartridge ROM banking systems that will make a different bank of data available in the
     // top 16KB of ROM addressable space.
     pub fn wb(&mut self, address: u16, value: u8) {
+        let ram_bank = self.mbc.ram_bank();
         self.mbc.wb(address, value);
+
+        if let Some(log) = &mut self.ram_bank_log {
+            let new_ram_bank = self.mbc.ram_bank();
+            if new_ram_bank != ram_bank {
+                let entry = format!(
+                    "RAM bank {} -> {} (wrote {:#04x} to {:#06x})",
+                    ram_bank, new_ram_bank, value, address
+                );
+                println!("{}", entry);
+                log.push(entry);
+            }
+        }
+    }
+
+    pub fn current_banks(&self) -> (u16, u8) {
+        self.mbc.current_banks()
+    }
+
+    pub fn snapshot(&self) -> CartridgeState {
+        CartridgeState {
+            registers: self.mbc.registers(),
+            ram: self.mbc.ram().map(|ram| ram.to_vec()).unwrap_or_default(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &CartridgeState) -> Result<(), String> {
+        self.mbc.restore(&state.registers, &state.ram)
+    }
+
+    /// Start logging every switch of the mapped RAM bank. Useful for debugging save issues.
+    pub fn enable_ram_bank_log(&mut self) {
+        self.ram_bank_log = Some(Vec::new());
+    }
+
+    /// Keep the save file in a specific directory rather than next to the ROM, eg. because the
+    /// ROM's directory is read-only.
+    pub fn set_save_dir(&mut self, dir: &str) {
+        if let Some(path) = &self.save_path {
+            self.save_path = Some(save_path(path, Some(dir)));
+        }
+    }
+
+    /// Write battery-backed RAM to the save file. Return whether a file was written.
+    pub fn save(&self) -> Result<bool, String> {
+        match &self.save_path {
+            Some(path) => self.save_ram(path),
+            None => Ok(false),
+        }
+    }
+
+    /// Load battery-backed RAM from the save file. Return whether there was one to load.
+    pub fn load(&mut self) -> Result<bool, String> {
+        match self.save_path.clone() {
+            Some(path) => self.load_ram(&path),
+            None => Ok(false),
+        }
+    }
+
+    /// Write battery-backed RAM to `path`, followed by the real-time clock if there is one.
+    /// Cartridges without a battery, or without RAM or a clock for it to keep, have nothing worth
+    /// keeping, so no file is written for them. Return whether a file was written.
+    pub fn save_ram(&self, path: &str) -> Result<bool, String> {
+        let mut data = match self.mbc.ram() {
+            Some(ram) if self.has_battery => ram.to_vec(),
+            _ => Vec::new(),
+        };
+        if self.has_rtc {
+            data.extend(self.mbc.save_rtc().unwrap_or_default());
+        }
+        if data.is_empty() {
+            return Ok(false);
+        }
+
+        fs::write(path, data).map_err(|e| format!("Could not write save file {}: {}", path, e))?;
+        println!("Saved cartridge RAM to {}", path);
+        Ok(true)
+    }
+
+    /// Fill battery-backed RAM, and the real-time clock if there is one, from the save file at
+    /// `path`. No file just means the game hasn't been saved yet. A file of the wrong size (eg.
+    /// from another emulator) is loaded as far as it fits. Return whether anything was loaded.
+    pub fn load_ram(&mut self, path: &str) -> Result<bool, String> {
+        if !(self.has_battery || self.has_rtc) || !Path::new(path).exists() {
+            return Ok(false);
+        }
+        let mut data =
+            fs::read(path).map_err(|e| format!("Could not read save file {}: {}", path, e))?;
+
+        // The clock goes after RAM, in 48 bytes or an older 44.
+        let ram_len = match self.mbc.ram() {
+            Some(ram) if self.has_battery => ram.len(),
+            _ => 0,
+        };
+        let rtc_len = match data.len().checked_sub(ram_len) {
+            Some(len @ 44) | Some(len @ 48) if self.has_rtc => len,
+            _ => 0,
+        };
+        if rtc_len > 0 {
+            let rtc = data.split_off(data.len() - rtc_len);
+            self.mbc.load_rtc(&rtc);
+        }
+
+        let ram = match self.mbc.ram_mut() {
+            Some(ram) if self.has_battery => ram,
+            _ => return Ok(rtc_len > 0),
+        };
+        if data.len() != ram.len() {
+            println!(
+                "Warning: save file {} is {} bytes, but the cartridge has {} bytes of RAM.",
+                path,
+                data.len(),
+                ram.len()
+            );
+        }
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        println!("Loaded cartridge RAM from {}", path);
+        Ok(true)
     }
 
     fn report_cartridge_header(data: &Vec<u8>) {
-        let rom_size = 32 << &data[0x148];
-        let bank_count = rom_size / 16;
-        println!("Name: {}", str::from_utf8(&data[0x134..0x143]).unwrap());
+        println!("Name: {}", Self::title(data));
         println!("MBC: {}", &data[0x147]);
-        println!("ROM Size: {} KB ({} banks)", rom_size, bank_count);
+
+        // Only codes 0x00-0x08 are sizes. Anything else is unknown, so don't shift by it.
+        match data[0x148] {
+            code @ 0x00..=0x08 => {
+                let rom_size = 32 << code;
+                println!("ROM Size: {} KB ({} banks)", rom_size, rom_size / 16);
+            }
+            code => println!("ROM Size: unknown ({:#04x})", code),
+        }
+    }
+
+    /// The title is upper case ASCII, padded with 0x00. Later cartridges use the last few bytes
+    /// for a manufacturer code and the CGB flag, so anything else is shown as a replacement
+    /// character rather than trusted to be valid UTF-8.
+    fn title(data: &[u8]) -> String {
+        let title = &data[0x134..0x143];
+        let end = title.iter().position(|&b| b == 0).unwrap_or(title.len());
+        String::from_utf8_lossy(&title[..end]).into_owned()
     }
 
     /// Load a cartridge into memory.
     /// A vector is allocated because we don't know until runtime how large the cartridge is.
-    fn load_cartridge_data(path: &String) -> Vec<u8> {
-        let mut f = File::open(path).expect("No file found.");
-        let metadata = metadata(path).expect("Unable to read metadata.");
-        let mut buffer = vec![0; metadata.len() as usize];
-        f.read(&mut buffer)
-            .expect("Buffer overflow! was metaadta wrong?");
-        buffer
+    /// Anything too small to even have a header (which ends at 0x14F) is rejected. Smaller than
+    /// usual ROMs are otherwise fine: reads past the end of the data return 0xFF.
+    fn load_cartridge_data(path: &str) -> Result<Vec<u8>, String> {
+        let data = fs::read(path).map_err(|e| format!("Could not read ROM {}: {}", path, e))?;
+
+        if data.len() < HEADER_END {
+            return Err(format!(
+                "ROM {} is too small ({} bytes) to contain a cartridge header ({} bytes).",
+                path,
+                data.len(),
+                HEADER_END
+            ));
+        }
+
+        Ok(data)
+    }
+}
+
+/// The save file for a ROM: the ROM's name with a .sav extension, in `save_dir` if there is one,
+/// otherwise alongside the ROM.
+fn save_path(rom_path: &str, save_dir: Option<&str>) -> String {
+    let rom_path = Path::new(rom_path);
+    let path = match (save_dir, rom_path.file_name()) {
+        (Some(dir), Some(name)) => Path::new(dir).join(name).with_extension("sav"),
+        _ => rom_path.with_extension("sav"),
+    };
+    path.to_string_lossy().into_owned()
+}
+
+/// Pad or truncate the ROM to the size its header (byte 0x148) declares, so that bank numbers wrap
+/// the way they would on the real cartridge. Some dumps have junk on the end, or are cut short.
+/// Sizes run from 32KB (0x00) to 8MB (0x08). Anything else is left alone: homebrew doesn't always
+/// fill in the header.
+fn normalize_rom_size(mut data: Vec<u8>) -> Vec<u8> {
+    if data[0x148] > 0x08 {
+        return data;
+    }
+
+    let size = 0x8000 << data[0x148];
+    if data.len() != size {
+        println!(
+            "Warning: ROM is {} bytes but its header says {} bytes. Resizing to match.",
+            data.len(),
+            size
+        );
+        data.resize(size, 0xFF);
+    }
+    data
+}
+
+/// Read from ROM data. Small (eg. test) ROMs might not fill a whole bank, so past the end is 0xFF.
+fn read_rom(data: &[u8], index: usize) -> u8 {
+    *data.get(index).unwrap_or(&0xFF)
+}
+
+/// Read an address in 0x4000-0x7FFF, with `bank` mapped there.
+fn read_rom_bank(data: &[u8], bank: usize, address: u16) -> u8 {
+    read_rom(data, 0x4000 * bank + (address as usize - 0x4000))
+}
+
+/// Wrap a ROM bank number to the banks there are. Smaller ROMs ignore the bank bits they don't
+/// have pins for.
+fn wrap_rom_bank(data: &[u8], bank: usize) -> usize {
+    bank % (data.len() / 0x4000).max(1)
+}
+
+/// The bank selected by a ROM bank register that can't select bank 0: writing 0 gets bank 1.
+fn nonzero_rom_bank(data: &[u8], register: u8) -> usize {
+    wrap_rom_bank(data, register.max(1) as usize)
+}
+
+/// A ROM of `banks` 16KB banks, where the first byte of every bank is the bank number.
+#[cfg(test)]
+fn numbered_rom(banks: usize) -> Vec<u8> {
+    let mut data = vec![0; 0x4000 * banks];
+    for bank in 0..banks {
+        data[bank * 0x4000] = bank as u8;
+    }
+    data
+}
+
+/// Check that saved registers and RAM could be this controller's: a number of registers it has
+/// saved (in any version of its format), and as much RAM as it has. A state from another kind of
+/// cartridge usually fails one or the other.
+fn check_mbc_state(
+    registers: &[u8],
+    register_counts: RangeInclusive<usize>,
+    ram: &[u8],
+    ram_size: usize,
+) -> Result<(), String> {
+    if !register_counts.contains(&registers.len()) {
+        return Err(format!(
+            "Save state has {} cartridge registers, expected {} to {}. Is it from another ROM?",
+            registers.len(),
+            register_counts.start(),
+            register_counts.end()
+        ));
+    }
+    if ram.len() != ram_size {
+        return Err(format!(
+            "Save state has {} bytes of cartridge RAM, expected {}. Is it from another ROM?",
+            ram.len(),
+            ram_size
+        ));
+    }
+    Ok(())
+}
+
+/// Does the cartridge type (header byte 0x147) include a battery?
+fn is_battery_backed(cartridge_type: u8) -> bool {
+    matches!(
+        cartridge_type,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Write a blank ROM with the given cartridge type, RAM size, and number of 16KB ROM banks to
+    /// a temporary file.
+    fn write_rom(name: &str, cartridge_type: u8, ram_size: u8, rom_banks: usize) -> String {
+        let mut data = vec![0; 0x4000 * rom_banks];
+        data[0x147] = cartridge_type;
+        data[0x148] = (rom_banks / 2).trailing_zeros() as u8;
+        data[0x149] = ram_size;
+
+        let path = env::temp_dir().join(name);
+        fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_rom_only_has_no_save() {
+        let path = write_rom("gameboy_test_rom_only.gb", 0x00, 0x00, 2);
+        let sav = Path::new(&path).with_extension("sav");
+        let _ = fs::remove_file(&sav);
+
+        let cartridge = Cartridge::new(Some(&path)).unwrap();
+        assert_eq!(cartridge.save(), Ok(false));
+        assert!(!sav.exists());
+    }
+
+    #[test]
+    fn test_ram_without_battery_has_no_save() {
+        let path = write_rom("gameboy_test_mbc1_ram.gb", 0x02, 0x02, 2);
+        let sav = Path::new(&path).with_extension("sav");
+        let _ = fs::remove_file(&sav);
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        cartridge.wb(0xA000, 0x42);
+        assert_eq!(cartridge.save(), Ok(false));
+        assert!(!sav.exists());
+    }
+
+    #[test]
+    fn test_battery_cartridge_saves() {
+        let path = write_rom("gameboy_test_mbc1_battery.gb", 0x03, 0x02, 2);
+        let sav = Path::new(&path).with_extension("sav");
+        let _ = fs::remove_file(&sav);
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        cartridge.wb(0xA000, 0x42);
+        assert_eq!(cartridge.save(), Ok(true));
+
+        let saved = fs::read(&sav).unwrap();
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(saved[0], 0x42);
+        fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn test_mbc2_battery_saves() {
+        // MBC2's RAM is in the controller, so the header's RAM size is 0.
+        let path = write_rom("gameboy_test_mbc2_battery.gb", 0x06, 0x00, 4);
+        let sav = Path::new(&path).with_extension("sav");
+        let _ = fs::remove_file(&sav);
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        cartridge.wb(0xA000, 0x42);
+        assert_eq!(cartridge.save(), Ok(true));
+        assert_eq!(fs::read(&sav).unwrap()[..2], [0x02, 0x00]);
+        fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load_ram() {
+        let path = write_rom("gameboy_test_mbc1_load.gb", 0x03, 0x03, 2);
+        let sav = env::temp_dir().join("gameboy_test_mbc1_load_ram.sav");
+        let sav = sav.to_string_lossy().into_owned();
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        cartridge.wb(0xA000, 0x42);
+        cartridge.wb(0x6000, 0x01);
+        cartridge.wb(0x4000, 0x03);
+        cartridge.wb(0xBFFF, 0x24); // The very last byte of the last bank.
+        assert_eq!(cartridge.save_ram(&sav), Ok(true));
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        assert_eq!(cartridge.load_ram(&sav), Ok(true));
+        assert_eq!(cartridge.rb(0xA000), 0x42);
+        cartridge.wb(0x6000, 0x01);
+        cartridge.wb(0x4000, 0x03);
+        assert_eq!(cartridge.rb(0xBFFF), 0x24);
+        fs::remove_file(&sav).unwrap();
+
+        // Nothing saved yet is fine.
+        assert_eq!(cartridge.load_ram(&sav), Ok(false));
+    }
+
+    #[test]
+    fn test_save_and_load_rtc() {
+        let sav = env::temp_dir().join("gameboy_test_mbc3_rtc.sav");
+        let sav = sav.to_string_lossy().into_owned();
+
+        // MBC3 with a timer, RAM and a battery. The clock is halted so it reads the same later.
+        let path = write_rom("gameboy_test_mbc3_rtc.gb", 0x10, 0x02, 4);
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM and the clock.
+        cartridge.wb(0xA000, 0x42);
+        cartridge.wb(0x4000, 0x0C);
+        cartridge.wb(0xA000, 0x40); // Halt.
+        cartridge.wb(0x4000, 0x0A);
+        cartridge.wb(0xA000, 7); // 7 hours.
+        assert_eq!(cartridge.save_ram(&sav), Ok(true));
+        assert_eq!(fs::read(&sav).unwrap().len(), 0x2000 + 48);
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A);
+        assert_eq!(cartridge.load_ram(&sav), Ok(true));
+        cartridge.wb(0x6000, 0x00);
+        cartridge.wb(0x6000, 0x01);
+        cartridge.wb(0x4000, 0x0A);
+        assert_eq!(cartridge.rb(0xA000), 7);
+        cartridge.wb(0x4000, 0x00);
+        assert_eq!(cartridge.rb(0xA000), 0x42);
+
+        // With a timer but no RAM, the save is just the clock.
+        let path = write_rom("gameboy_test_mbc3_rtc_only.gb", 0x0F, 0x00, 4);
+        let cartridge = Cartridge::new(Some(&path)).unwrap();
+        assert_eq!(cartridge.save_ram(&sav), Ok(true));
+        assert_eq!(fs::read(&sav).unwrap().len(), 48);
+
+        // Without a timer, it's just RAM.
+        let path = write_rom("gameboy_test_mbc3_no_rtc.gb", 0x13, 0x02, 4);
+        let cartridge = Cartridge::new(Some(&path)).unwrap();
+        assert_eq!(cartridge.save_ram(&sav), Ok(true));
+        assert_eq!(fs::read(&sav).unwrap().len(), 0x2000);
+        fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn test_save_path() {
+        assert_eq!(save_path("roms/tetris.gb", None), "roms/tetris.sav");
+        assert_eq!(
+            save_path("roms/tetris.gb", Some("saves")),
+            "saves/tetris.sav"
+        );
+        assert_eq!(
+            save_path("zelda.gbc", Some("/tmp/saves")),
+            "/tmp/saves/zelda.sav"
+        );
+        assert_eq!(save_path("pokemon", Some("saves")), "saves/pokemon.sav");
+    }
+
+    #[test]
+    fn test_save_dir() {
+        let path = write_rom("gameboy_test_save_dir.gb", 0x03, 0x02, 2);
+        let dir = env::temp_dir().join("gameboy_test_saves");
+        fs::create_dir_all(&dir).unwrap();
+        let sav = dir.join("gameboy_test_save_dir.sav");
+        let _ = fs::remove_file(&sav);
+
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+        cartridge.set_save_dir(&dir.to_string_lossy());
+        cartridge.wb(0xA000, 0x42);
+        assert_eq!(cartridge.save(), Ok(true));
+
+        assert_eq!(fs::read(&sav).unwrap()[0], 0x42);
+        assert!(!Path::new(&path).with_extension("sav").exists());
+        fs::remove_file(&sav).unwrap();
+    }
+
+    #[test]
+    fn test_ram_bank_log() {
+        let path = write_rom("gameboy_test_mbc1_banks.gb", 0x03, 0x03, 2);
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.enable_ram_bank_log();
+
+        cartridge.wb(0x4000, 0x02); // Select bank 2, but it isn't mapped until mode 1.
+        cartridge.wb(0x6000, 0x01); // Mode 1: bank 2 is now mapped.
+        cartridge.wb(0x4000, 0x02); // Same bank again: not a switch.
+        cartridge.wb(0x4000, 0x03);
+        cartridge.wb(0x6000, 0x00); // Back to mode 0: bank 0.
+
+        assert_eq!(
+            cartridge.ram_bank_log.unwrap(),
+            vec![
+                "RAM bank 0 -> 2 (wrote 0x01 to 0x6000)",
+                "RAM bank 2 -> 3 (wrote 0x03 to 0x4000)",
+                "RAM bank 3 -> 0 (wrote 0x00 to 0x6000)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_title() {
+        let mut data = vec![0; 0x150];
+        data[0x134..0x138].copy_from_slice(b"TEST");
+        assert_eq!(Cartridge::title(&data), "TEST");
+
+        data[0x134..0x143].copy_from_slice(b"POKEMON RE\xC0\xFF\x80\x00\x00");
+        assert_eq!(
+            Cartridge::title(&data),
+            "POKEMON RE\u{FFFD}\u{FFFD}\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn test_verify_header_checksum() {
+        let mut data = vec![0; 0x8000];
+        data[0x134..0x138].copy_from_slice(b"TEST");
+        data[0x14D] = 0xA7; // 0 - (0x54 + 0x45 + 0x53 + 0x54) - 25, wrapped.
+        let path = env::temp_dir().join("gameboy_test_checksum.gb");
+        fs::write(&path, &data).unwrap();
+        let cartridge = Cartridge::new(Some(&path.to_string_lossy().into_owned())).unwrap();
+        assert!(cartridge.verify_header_checksum());
+
+        // Change a byte of the title, as a corrupt dump might.
+        data[0x135] = b'A';
+        fs::write(&path, &data).unwrap();
+        let cartridge = Cartridge::new(Some(&path.to_string_lossy().into_owned())).unwrap();
+        assert!(!cartridge.verify_header_checksum());
+    }
+
+    #[test]
+    fn test_tiny_rom() {
+        let path = env::temp_dir().join("gameboy_test_tiny.gb");
+        fs::write(&path, [0; 16]).unwrap();
+
+        let error = Cartridge::new(Some(&path.to_string_lossy().into_owned())).err();
+        assert!(error.unwrap().contains("too small (16 bytes)"));
+    }
+
+    #[test]
+    fn test_rom_smaller_than_a_bank() {
+        let mut data = vec![0; 0x200];
+        data[0x150] = 0x42;
+        let path = env::temp_dir().join("gameboy_test_small.gb");
+        fs::write(&path, data).unwrap();
+
+        let cartridge = Cartridge::new(Some(&path.to_string_lossy().into_owned())).unwrap();
+        assert_eq!(cartridge.rb(0x150), 0x42);
+        assert_eq!(cartridge.rb(0x7FFF), 0xFF); // Past the end of the data.
+    }
+
+    #[test]
+    fn test_oversized_rom() {
+        // The header says 32KB, but there's a third bank of junk on the end.
+        let mut data = vec![0; 0xC000];
+        data[0x147] = 0x01;
+        data[0x4200] = 0x42;
+        for byte in data[0x8000..].iter_mut() {
+            *byte = 0xEE;
+        }
+        let path = env::temp_dir().join("gameboy_test_oversized.gb");
+        fs::write(&path, data).unwrap();
+
+        // Bank 2 doesn't exist on a 2 bank cartridge, so it wraps around to bank 0.
+        let mut cartridge = Cartridge::new(Some(&path.to_string_lossy().into_owned())).unwrap();
+        cartridge.wb(0x2000, 0x02);
+        assert_eq!(cartridge.rb(0x4200), 0x00);
+        cartridge.wb(0x2000, 0x01);
+        assert_eq!(cartridge.rb(0x4200), 0x42);
+
+        let mut data = vec![0; 0x8000];
+        data[0x148] = 0x01;
+        assert_eq!(normalize_rom_size(data.clone()).len(), 0x10000);
+        data[0x148] = 0x52; // Not a real size: leave it be.
+        assert_eq!(normalize_rom_size(data).len(), 0x8000);
+    }
+
+    #[test]
+    fn test_with_patch() {
+        let path = write_rom("gameboy_test_patched.gb", 0x00, 0x00, 2);
+        let patch_path = env::temp_dir().join("gameboy_test_patched.ips");
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x01, 0x50, 0x00, 0x02, 0x12, 0x34]);
+        patch.extend_from_slice(b"EOF");
+        fs::write(&patch_path, patch).unwrap();
+        let patch_path = patch_path.to_string_lossy().into_owned();
+
+        let cartridge = Cartridge::with_patch(Some(&path), Some(&patch_path)).unwrap();
+        assert_eq!(cartridge.rb(0x150), 0x12);
+        assert_eq!(cartridge.rb(0x151), 0x34);
+        assert_eq!(cartridge.rb(0x152), 0x00);
+
+        // A bad patch is an error rather than a partly patched ROM.
+        fs::write(&patch_path, b"PATCH\x00\x01\x50").unwrap();
+        assert!(Cartridge::with_patch(Some(&path), Some(&patch_path)).is_err());
+    }
+
+    #[test]
+    fn test_missing_rom() {
+        let path = String::from("this/rom/does/not/exist.gb");
+        assert!(Cartridge::new(Some(&path)).is_err());
+    }
+
+    #[test]
+    fn test_current_banks() {
+        let path = write_rom("gameboy_test_mbc1_current_banks.gb", 0x03, 0x03, 64);
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        assert_eq!(cartridge.current_banks(), (1, 0));
+
+        cartridge.wb(0x2000, 0x05);
+        assert_eq!(cartridge.current_banks(), (5, 0));
+
+        // Bank 0 can't be selected in the upper area: it's bank 1 instead.
+        cartridge.wb(0x2000, 0x00);
+        assert_eq!(cartridge.current_banks(), (1, 0));
+
+        // The 2-bit register supplies the upper ROM bank bits, and the RAM bank in mode 1.
+        cartridge.wb(0x2000, 0x03);
+        cartridge.wb(0x4000, 0x01);
+        assert_eq!(cartridge.current_banks(), (0x23, 0));
+        cartridge.wb(0x6000, 0x01);
+        assert_eq!(cartridge.current_banks(), (0x23, 1));
+
+        // ROM-only cartridges don't bank.
+        let path = write_rom("gameboy_test_rom_only_banks.gb", 0x00, 0x00, 2);
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x2000, 0x05);
+        assert_eq!(cartridge.current_banks(), (1, 0));
+    }
+
+    #[test]
+    fn test_ram_banks_are_separate() {
+        let path = write_rom("gameboy_test_mbc1_ram_banks.gb", 0x03, 0x03, 2);
+        let mut cartridge = Cartridge::new(Some(&path)).unwrap();
+        cartridge.wb(0x0000, 0x0A); // Enable RAM.
+
+        cartridge.wb(0x6000, 0x01);
+        cartridge.wb(0xA000, 0x11);
+        cartridge.wb(0x4000, 0x01);
+        cartridge.wb(0xA000, 0x22);
+        assert_eq!(cartridge.rb(0xA000), 0x22);
+
+        cartridge.wb(0x4000, 0x00);
+        assert_eq!(cartridge.rb(0xA000), 0x11);
     }
 }
@@ -0,0 +1,117 @@
+/// Apply an IPS patch (the usual format for ROM hacks and translations) to ROM data, returning the
+/// patched ROM.
+///
+/// An IPS file is "PATCH", then records until "EOF". Each record is a 3-byte offset and a 2-byte
+/// size, both big-endian, followed by that many bytes to write at the offset. A size of 0 means
+/// a run instead: a 2-byte count and a single byte to repeat. Records past the end of the ROM grow
+/// it. Some patchers add a 3-byte length after "EOF" to truncate the ROM to.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(b"PATCH") {
+        return Err(String::from(
+            "Not an IPS patch: it doesn't start with PATCH.",
+        ));
+    }
+
+    let mut rom = rom.to_vec();
+
+    let mut position = 5;
+    loop {
+        let offset = read(patch, position, 3)?;
+        position += 3;
+
+        // An offset of 0x454F46 would be "EOF", so a record can never start there.
+        if offset == 0x454F46 {
+            break;
+        }
+
+        let size = read(patch, position, 2)?;
+        position += 2;
+
+        if size == 0 {
+            let count = read(patch, position, 2)?;
+            let value = read(patch, position + 2, 1)? as u8;
+            position += 3;
+            write(&mut rom, offset, &vec![value; count]);
+        } else {
+            if position + size > patch.len() {
+                return Err(format!(
+                    "IPS record at {:#x} runs past the end of the patch.",
+                    position - 5
+                ));
+            }
+            write(&mut rom, offset, &patch[position..position + size]);
+            position += size;
+        }
+    }
+
+    // The optional truncation length. Anything else after "EOF" is invalid.
+    match patch.len() - position {
+        0 => (),
+        3 => {
+            let length = read(patch, position, 3)?;
+            if length > rom.len() {
+                return Err(format!(
+                    "IPS patch truncates to {:#x} bytes, past the end of the ROM.",
+                    length
+                ));
+            }
+            rom.truncate(length);
+        }
+        n => return Err(format!("IPS patch has {} unexpected bytes after EOF.", n)),
+    }
+
+    Ok(rom)
+}
+
+/// Read a big-endian number of `length` bytes at `position`.
+fn read(patch: &[u8], position: usize, length: usize) -> Result<usize, String> {
+    if position + length > patch.len() {
+        return Err(String::from("IPS patch ends in the middle of a record."));
+    }
+
+    Ok(patch[position..position + length]
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as usize))
+}
+
+/// Write bytes to the ROM at an offset, growing it (with 0s) if it's too short.
+fn write(rom: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if offset + bytes.len() > rom.len() {
+        rom.resize(offset + bytes.len(), 0);
+    }
+    rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_ips() {
+        let rom = vec![0; 0x10];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x03, 0xAA, 0xBB, 0xCC]); // 3 bytes at 2.
+        patch.extend_from_slice(&[0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x04, 0x11]); // 4 0x11s at 8.
+        patch.extend_from_slice(&[0x00, 0x00, 0x12, 0x00, 0x01, 0x22]); // Past the end: grows.
+        patch.extend_from_slice(b"EOF");
+
+        assert_eq!(
+            apply_ips(&rom, &patch).unwrap(),
+            vec![0, 0, 0xAA, 0xBB, 0xCC, 0, 0, 0, 0x11, 0x11, 0x11, 0x11, 0, 0, 0, 0, 0, 0, 0x22]
+        );
+
+        // Truncated after EOF.
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply_ips(&rom, &patch).unwrap(), vec![0, 0, 0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn test_invalid_ips() {
+        let rom = vec![0; 0x10];
+        assert!(apply_ips(&rom, b"NOTAPATCH").is_err());
+        assert!(apply_ips(&rom, b"PATCH\x00\x00\x02\x00\x05\xAA").is_err()); // Cut short.
+        assert!(apply_ips(&rom, b"PATCH").is_err()); // No EOF.
+        assert!(apply_ips(&rom, b"PATCHEOF\x01").is_err());
+        assert!(apply_ips(&rom, b"PATCHEOF\x00\x01\x00").is_err()); // Truncates past the end.
+    }
+}
@@ -0,0 +1,270 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the real-time clock gets the time from, in whole seconds. Only differences between two
+/// readings matter, so it doesn't have to be any particular epoch. Battery saves record it though,
+/// so other emulators expect the system clock's to be Unix time.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// The host's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to, so tests get the same time every run. Clones share the
+/// same time: keep one to advance after giving the other to an `Rtc`.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct FixedClock {
+    now: std::rc::Rc<std::cell::Cell<u64>>,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn advance(&self, seconds: u64) {
+        self.now.set(self.now.get() + seconds);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// The MBC3's real-time clock. Rather than counting every second, remember when the counter was
+/// zero and work out the registers from the time since then.
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    base: u64,           // The clock's time when the counter was zero.
+    halted: Option<u64>, // The counter, while it's stopped by the halt flag.
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        let base = clock.now();
+        Self {
+            clock,
+            base,
+            halted: None,
+        }
+    }
+
+    /// Seconds counted so far. Past 512 days this keeps going, which is what sets the carry.
+    pub fn elapsed(&self) -> u64 {
+        match self.halted {
+            Some(elapsed) => elapsed,
+            None => self.clock.now().wrapping_sub(self.base),
+        }
+    }
+
+    /// The RTC registers, as selected by writing 0x08-0x0C to 0x4000-0x5FFF: seconds, minutes,
+    /// hours, the low 8 bits of the day counter, then its 9th bit in bit 0, the halt flag in bit 6
+    /// and the day counter's carry (it went past 511 days) in bit 7.
+    pub fn registers(&self) -> [u8; 5] {
+        let elapsed = self.elapsed();
+        let days = elapsed / 86400;
+        let carry = if days > 0x1FF { 0x80 } else { 0 };
+        let halt = if self.halted.is_some() { 0x40 } else { 0 };
+
+        [
+            (elapsed % 60) as u8,
+            (elapsed / 60 % 60) as u8,
+            (elapsed / 3600 % 24) as u8,
+            days as u8,
+            (days >> 8 & 0x01) as u8 | halt | carry,
+        ]
+    }
+
+    /// Write one of the registers (0-4, in the order `registers` returns them). Setting the halt
+    /// flag stops the counter and clearing it starts it again. Clearing the carry is how games
+    /// acknowledge it.
+    pub fn write(&mut self, register: usize, value: u8) {
+        let mut registers = self.registers();
+        registers[register] = value;
+        self.set_registers(registers);
+    }
+
+    fn set_registers(&mut self, registers: [u8; 5]) {
+        let days = (registers[4] as u64 & 0x01) << 8 | registers[3] as u64;
+        let carry = if registers[4] & 0x80 != 0 { 512 } else { 0 };
+        let elapsed = (registers[0] as u64 & 0x3F)
+            + (registers[1] as u64 & 0x3F) * 60
+            + (registers[2] as u64 & 0x1F) * 3600
+            + (days + carry) * 86400;
+
+        self.restore(elapsed, registers[4] & 0x40 != 0);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.is_some()
+    }
+
+    /// Put the counter back to what `elapsed` and `is_halted` returned, for save states. A running
+    /// counter carries on from there.
+    pub fn restore(&mut self, elapsed: u64, halted: bool) {
+        if halted {
+            self.halted = Some(elapsed);
+        } else {
+            self.halted = None;
+            self.base = self.clock.now().wrapping_sub(elapsed);
+        }
+    }
+
+    /// The clock as battery saves keep it after RAM, in the layout BGB and VBA-M share: the
+    /// registers then `latched`, each as a 32-bit little endian value, then the time it was saved
+    /// as a 64-bit one.
+    pub fn save(&self, latched: &[u8; 5]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(SAVE_LEN);
+        for &register in self.registers().iter().chain(latched.iter()) {
+            data.extend_from_slice(&(register as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&self.clock.now().to_le_bytes());
+        data
+    }
+
+    /// Load what `save` wrote (or the older 44 byte layout with a 32-bit time) and return the
+    /// latched registers. Unless the clock was halted, the time since it was saved is counted.
+    pub fn load(&mut self, data: &[u8]) -> [u8; 5] {
+        self.set_registers(unpack_registers(&data[..20]));
+
+        let mut saved_at = [0; 8];
+        saved_at[..data.len() - 40].copy_from_slice(&data[40..]);
+        let since_saved = self
+            .clock
+            .now()
+            .saturating_sub(u64::from_le_bytes(saved_at));
+        if self.halted.is_none() {
+            self.base = self.base.wrapping_sub(since_saved);
+        }
+
+        unpack_registers(&data[20..40])
+    }
+}
+
+/// How many bytes `Rtc::save` writes. Some emulators write 44, with a 32-bit time.
+pub const SAVE_LEN: usize = 48;
+
+/// Five registers from a save, stored as 32-bit values.
+fn unpack_registers(data: &[u8]) -> [u8; 5] {
+    let mut registers = [0; 5];
+    for (register, bytes) in registers.iter_mut().zip(data.chunks(4)) {
+        *register = bytes[0];
+    }
+    registers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_rollover() {
+        let clock = FixedClock::default();
+        clock.advance(1_000_000); // Not starting at zero shouldn't matter.
+        let rtc = Rtc::new(Box::new(clock.clone()));
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0]);
+
+        clock.advance(59);
+        assert_eq!(rtc.registers(), [59, 0, 0, 0, 0]);
+
+        clock.advance(1);
+        assert_eq!(rtc.registers(), [0, 1, 0, 0, 0]);
+
+        clock.advance(58 * 60 + 59);
+        assert_eq!(rtc.registers(), [59, 59, 0, 0, 0]);
+
+        clock.advance(1);
+        assert_eq!(rtc.registers(), [0, 0, 1, 0, 0]);
+
+        // On to 23:59:59 on day 255, then a second later the day counter needs its 9th bit.
+        clock.advance(255 * 86400 + 22 * 3600 + 59 * 60 + 59);
+        assert_eq!(rtc.registers(), [59, 59, 23, 255, 0]);
+        clock.advance(1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 1]);
+
+        // Past day 511 the counter wraps and the carry is set.
+        clock.advance(256 * 86400);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn test_rtc_write_and_halt() {
+        let clock = FixedClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+
+        // Set the time to 23:59:30 on day 511, with the carry cleared.
+        rtc.write(2, 23);
+        rtc.write(1, 59);
+        rtc.write(0, 30);
+        rtc.write(3, 0xFF);
+        rtc.write(4, 0x01);
+        assert_eq!(rtc.registers(), [30, 59, 23, 0xFF, 0x01]);
+
+        clock.advance(30);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0x80]);
+
+        // While halted the counter doesn't move.
+        rtc.write(4, 0xC0);
+        clock.advance(100);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0xC0]);
+
+        // Acknowledging the carry and restarting.
+        rtc.write(4, 0x00);
+        clock.advance(61);
+        assert_eq!(rtc.registers(), [1, 1, 0, 0, 0x00]);
+    }
+
+    #[test]
+    fn test_rtc_save_and_load() {
+        let clock = FixedClock::default();
+        clock.advance(1_600_000_000);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(2, 5);
+        let data = rtc.save(&[1, 2, 3, 4, 5]);
+        assert_eq!(data.len(), SAVE_LEN);
+        assert_eq!(data[8..12], [5, 0, 0, 0]);
+
+        // The clock kept running while the game was off.
+        clock.advance(90);
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        assert_eq!(loaded.load(&data), [1, 2, 3, 4, 5]);
+        assert_eq!(loaded.registers(), [30, 1, 5, 0, 0]);
+
+        // The older layout, with a 32-bit time.
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        loaded.load(&data[..44]);
+        assert_eq!(loaded.registers(), [30, 1, 5, 0, 0]);
+
+        // A halted clock doesn't count the time.
+        rtc.write(4, 0x40);
+        let data = rtc.save(&[0; 5]);
+        clock.advance(90);
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        loaded.load(&data);
+        assert_eq!(loaded.registers(), [30, 1, 5, 0, 0x40]);
+    }
+
+    #[test]
+    fn test_rtc_restore() {
+        let clock = FixedClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        clock.advance(100);
+        rtc.restore(3661, false);
+        clock.advance(1);
+        assert_eq!(rtc.registers(), [2, 1, 1, 0, 0]);
+
+        rtc.restore(3661, true);
+        clock.advance(1);
+        assert_eq!((rtc.elapsed(), rtc.is_halted()), (3661, true));
+    }
+}
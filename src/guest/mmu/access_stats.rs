@@ -0,0 +1,43 @@
+/// How many times each region of memory was accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RegionCounts {
+    pub rom: u64,           // 0x0000-0x7FFF, including the boot ROM and MBC registers.
+    pub vram: u64,          // 0x8000-0x9FFF
+    pub cartridge_ram: u64, // 0xA000-0xBFFF
+    pub wram: u64,          // 0xC000-0xFDFF, including the echo.
+    pub oam: u64,           // 0xFE00-0xFEFF, including the unusable area after it.
+    pub io: u64,            // 0xFF00-0xFF7F and IE (0xFFFF).
+    pub hram: u64,          // 0xFF80-0xFFFE
+}
+
+impl RegionCounts {
+    fn count(&mut self, address: u16) {
+        match address {
+            0x0000..=0x7FFF => self.rom += 1,
+            0x8000..=0x9FFF => self.vram += 1,
+            0xA000..=0xBFFF => self.cartridge_ram += 1,
+            0xC000..=0xFDFF => self.wram += 1,
+            0xFE00..=0xFEFF => self.oam += 1,
+            0xFF80..=0xFFFE => self.hram += 1,
+            _ => self.io += 1,
+        }
+    }
+}
+
+/// Memory reads and writes per region, for profiling. Every access through the MMU is counted,
+/// including the emulator's own: the PPU reading VRAM and OAM to draw, and OAM DMA.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AccessStats {
+    pub reads: RegionCounts,
+    pub writes: RegionCounts,
+}
+
+impl AccessStats {
+    pub fn count_read(&mut self, address: u16) {
+        self.reads.count(address);
+    }
+
+    pub fn count_write(&mut self, address: u16) {
+        self.writes.count(address);
+    }
+}
@@ -1,5 +1,7 @@
 use super::is_bit_set;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ApuRegisters {
     // Square (with sweep)
     pub square1_sweep_time: u8,
@@ -10,7 +12,8 @@ pub struct ApuRegisters {
     pub square1_frequency: u16,
     pub square1_initialize: bool,
     pub square1_length_enabled: bool,
-    nr12: u8, // 0xFF12: Sound mode 1 envelope.
+    pub square1_playing: bool, // Triggered with the DAC on, and not stopped since.
+    pub nr12: u8,              // 0xFF12: Sound mode 1 envelope.
 
     // Square
     pub square2_wave_duty: u8,
@@ -18,25 +21,29 @@ pub struct ApuRegisters {
     pub square2_frequency: u16,
     pub square2_initialize: bool,
     pub square2_length_enabled: bool,
-    nr22: u8, // 0xFF17: Sound mode 2 register, envelope.
+    pub square2_playing: bool, // Triggered with the DAC on, and not stopped since.
+    pub nr22: u8,              // 0xFF17: Sound mode 2 register, envelope.
 
     // Wave
     pub wave_on: bool,
-    wave_length: u8,
+    wave_length: u16, // Counts up to 256, so one more than fits in NR31.
     wave_length_enabled: bool,
     pub wave_output: u8, // 00: mute, 01: as-is, 10: shift right, 11: shift right twice.
     pub wave_frequency: u16, // Two 8-bit registers acting as a frequency value.
     pub wave_ram: [u8; 32], // 32 4-bit wave pattern samples.
     wave_initialize: bool, // When set high, the sound restarts, then flag is set low.
+    wave_playing: bool,  // Triggered with the DAC on, and not stopped since.
 
     // Noise
-    nr41: u8, // 0xFF20: Sound mode 4 register, length.
-    nr42: u8, // 0xFF21: Sound mode 4 register, envelope.
-    nr43: u8, // 0xFF22: Sound mode 4 register, polynomial counter.
-    nr44: u8, // 0xFF23: Sound mode 4 register, counter/consecutive.
-    nr50: u8, // 0xFF24: Channel control, on/off, volume.
-    nr51: u8, // 0xFF25: Selection of Sound output terminal.
-    nr52: u8, // 0xFF26: Power to sound.
+    pub noise_initialize: bool, // Bit 7 of NR44: when set high, the sound restarts.
+
+    nr41: u8,     // 0xFF20: Sound mode 4 register, length.
+    pub nr42: u8, // 0xFF21: Sound mode 4 register, envelope.
+    nr43: u8,     // 0xFF22: Sound mode 4 register, polynomial counter.
+    nr44: u8,     // 0xFF23: Sound mode 4 register, counter/consecutive.
+    nr50: u8,     // 0xFF24: Channel control, on/off, volume.
+    nr51: u8,     // 0xFF25: Selection of Sound output terminal.
+    nr52: u8,     // 0xFF26: Power to sound.
 }
 
 impl ApuRegisters {
@@ -50,12 +57,14 @@ impl ApuRegisters {
             square1_frequency: 0,
             square1_initialize: false,
             square1_length_enabled: false,
+            square1_playing: false,
             nr12: 0,
             square2_wave_duty: 0,
             square2_length: 0,
             square2_frequency: 0,
             square2_initialize: false,
             square2_length_enabled: false,
+            square2_playing: false,
             nr22: 0,
             wave_on: true,
             wave_length: 0,
@@ -63,10 +72,12 @@ impl ApuRegisters {
             wave_output: 0,
             wave_frequency: 0,
             wave_initialize: false,
+            wave_playing: false,
             nr41: 0,
             nr42: 0,
             nr43: 0,
             nr44: 0,
+            noise_initialize: false,
             nr50: 0,
             nr51: 0,
             nr52: 0,
@@ -83,9 +94,16 @@ impl ApuRegisters {
             }
             0xFF11 => {
                 self.square1_wave_duty = value >> 6; // Highest 2 bits.
-                self.square1_length = value & 0x3F; // Lowest 6 bits.
+                self.square1_length = 64 - (value & 0x3F); // Lowest 6 bits count up to 64.
+            }
+            0xFF12 => {
+                self.nr12 = value;
+
+                // The DAC is off when the top 5 bits are clear, which stops the channel.
+                if value & 0xF8 == 0 {
+                    self.square1_playing = false;
+                }
             }
-            0xFF12 => self.nr12 = value,
             0xFF13 => {
                 self.square1_frequency = (self.square1_frequency & 0xFF00) | (value & 0xFF) as u16
             }
@@ -95,12 +113,27 @@ impl ApuRegisters {
                     (self.square1_frequency & 0xFF) | (((value & 0x07) as u16) << 8);
                 self.square1_initialize = is_bit_set(value, 7);
                 self.square1_length_enabled = is_bit_set(value, 6);
+
+                // Triggering starts the channel if its DAC is on, with a full length if it ran out.
+                if self.square1_initialize {
+                    if self.square1_length == 0 {
+                        self.square1_length = 64;
+                    }
+                    self.square1_playing = self.nr12 & 0xF8 != 0;
+                }
             }
             0xFF16 => {
                 self.square2_wave_duty = value >> 6; // Highest 2 bits.
-                self.square2_length = value & 0x3F; // Lowest 6 bits.
+                self.square2_length = 64 - (value & 0x3F); // Lowest 6 bits count up to 64.
+            }
+            0xFF17 => {
+                self.nr22 = value;
+
+                // The DAC is off when the top 5 bits are clear, which stops the channel.
+                if value & 0xF8 == 0 {
+                    self.square2_playing = false;
+                }
             }
-            0xFF17 => self.nr22 = value,
             0xFF18 => {
                 self.square2_frequency = (self.square2_frequency & 0xFF00) | (value & 0xFF) as u16
             }
@@ -110,9 +143,24 @@ impl ApuRegisters {
                     (self.square2_frequency & 0xFF) | (((value & 0x07) as u16) << 8);
                 self.square2_initialize = is_bit_set(value, 7);
                 self.square2_length_enabled = is_bit_set(value, 6);
+
+                // Triggering starts the channel if its DAC is on, with a full length if it ran out.
+                if self.square2_initialize {
+                    if self.square2_length == 0 {
+                        self.square2_length = 64;
+                    }
+                    self.square2_playing = self.nr22 & 0xF8 != 0;
+                }
             }
-            0xFF1A => self.wave_on = is_bit_set(value, 7),
-            0xFF1B => self.wave_length = value,
+            0xFF1A => {
+                self.wave_on = is_bit_set(value, 7);
+
+                // Turning the DAC off stops the channel. Turning it back on doesn't restart it.
+                if !self.wave_on {
+                    self.wave_playing = false;
+                }
+            }
+            0xFF1B => self.wave_length = 256 - value as u16,
             0xFF1C => self.wave_output = (value >> 5) & 0x3, // Only bits 5 and 6 matter.
             0xFF1D => self.wave_frequency = (self.wave_frequency & 0xFF00) | (value & 0xFF) as u16,
             0xFF1E => {
@@ -120,45 +168,188 @@ impl ApuRegisters {
                 self.wave_frequency = (self.wave_frequency & 0xFF) | (((value & 0x07) as u16) << 8);
                 self.wave_initialize = is_bit_set(value, 7);
                 self.wave_length_enabled = is_bit_set(value, 6);
+
+                // Triggering starts the channel if its DAC is on, with a full length if it ran out.
+                if self.wave_initialize {
+                    if self.wave_length == 0 {
+                        self.wave_length = 256;
+                    }
+                    self.wave_playing = self.wave_on;
+                }
             }
             0xFF20 => self.nr41 = value,
             0xFF21 => self.nr42 = value,
             0xFF22 => self.nr43 = value,
-            0xFF23 => self.nr44 = value,
+            0xFF23 => {
+                self.nr44 = value;
+                self.noise_initialize = is_bit_set(value, 7);
+            }
             0xFF24 => self.nr50 = value,
             0xFF25 => {
                 self.nr51 = value;
                 println!("{}", value);
             }
-            0xFF26 => self.nr52 = value,
+            0xFF26 => {
+                self.nr52 = value;
+
+                // Powering the APU off stops every channel.
+                if !is_bit_set(value, 7) {
+                    self.square1_playing = false;
+                    self.square2_playing = false;
+                    self.wave_playing = false;
+                }
+            }
             0xFF30..=0xFF3F => {
+                // Writes are ignored while the channel plays. See `rb`.
+                if self.wave_playing {
+                    return;
+                }
+
                 // Incoming 8-bit value is two 4-bit samples. Split it and set it to wave_ram.
-                self.wave_ram[(address as usize - 0xFF30) / 2] = value >> 4;
-                self.wave_ram[(address as usize - 0xFF30) / 2 + 1] = value & 0xF;
+                let index = (address as usize - 0xFF30) * 2;
+                self.wave_ram[index] = value >> 4;
+                self.wave_ram[index + 1] = value & 0xF;
             }
-            _ => panic!(
-                "Tried to write to an APU register that was not implemented: {:x}",
-                address
-            ),
+            _ => (), // Unused: 0xFF15, 0xFF1F and 0xFF27-0xFF2F.
         }
     }
 
+    /// Count down the square and wave channels' lengths, for the frame sequencer's 256Hz steps. A
+    /// channel with its length enabled stops when it runs out.
+    pub fn clock_lengths(&mut self) {
+        if self.square1_length_enabled && self.square1_length > 0 {
+            self.square1_length -= 1;
+            self.square1_playing &= self.square1_length > 0;
+        }
+
+        if self.square2_length_enabled && self.square2_length > 0 {
+            self.square2_length -= 1;
+            self.square2_playing &= self.square2_length > 0;
+        }
+
+        if self.wave_length_enabled && self.wave_length > 0 {
+            self.wave_length -= 1;
+            self.wave_playing &= self.wave_length > 0;
+        }
+    }
+
+    /// Only some bits of the sound registers can be read back. The rest read as 1.
     pub fn rb(&self, address: u16) -> u8 {
-        // println!("{:#}", address);
-        0
-        // TODO: Implement.
+        match address {
+            // NRx4: only the length enable bit (6) is readable. Trigger and frequency aren't.
+            0xFF14 => 0xBF | ((self.square1_length_enabled as u8) << 6),
+            0xFF19 => 0xBF | ((self.square2_length_enabled as u8) << 6),
+            0xFF1E => 0xBF | ((self.wave_length_enabled as u8) << 6),
+            0xFF23 => 0xBF | (self.nr44 & 0x40),
+
+            // The wave channel owns wave RAM while it plays, so the CPU reads 0xFF. On a DMG the
+            // CPU can get through in the few cycles right as the channel reads a sample, but the
+            // channel isn't clocked yet so that window is never open here.
+            0xFF30..=0xFF3F if self.wave_playing => 0xFF,
+            0xFF30..=0xFF3F => {
+                let index = (address as usize - 0xFF30) * 2;
+                self.wave_ram[index] << 4 | self.wave_ram[index + 1]
+            }
+
+            // Unused addresses. Nothing is there, so every bit reads as 1.
+            0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => 0xFF,
+            _ => 0, // TODO: Implement.
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_length_enable() {
+        let mut registers = ApuRegisters::new();
+
+        for &address in [0xFF14, 0xFF19, 0xFF1E, 0xFF23].iter() {
+            registers.wb(address, 0xC7); // Trigger, length enable, frequency bits.
+            assert_eq!(registers.rb(address), 0xFF, "{:#06x}", address);
+
+            registers.wb(address, 0x87); // Trigger without length enable.
+            assert_eq!(registers.rb(address), 0xBF, "{:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn test_read_unused() {
+        let mut registers = ApuRegisters::new();
+
+        for address in [0xFF15, 0xFF1F].iter().cloned().chain(0xFF27..=0xFF2F) {
+            assert_eq!(registers.rb(address), 0xFF, "{:#06x}", address);
+            registers.wb(address, 0x00); // Ignored.
+            assert_eq!(registers.rb(address), 0xFF, "{:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn test_wave_ram_blocked_while_playing() {
+        let mut registers = ApuRegisters::new();
+        registers.wb(0xFF30, 0x12);
+        registers.wb(0xFF3F, 0xEF);
+        assert_eq!(registers.rb(0xFF30), 0x12);
+        assert_eq!(registers.rb(0xFF3F), 0xEF);
+        assert_eq!(registers.wave_ram[0..2], [0x1, 0x2]);
 
-    // pub fn rb(&self, address: u16) -> u8 {
-    //     match address {
-    //         0xFF14 => self.nr14, // TODO: not correct. Only bit 6 can be read?
-    //         0xFF19 => self.nr24,
-    //         0xFF1E => self.nr34,
-    //         0xFF23 => self.nr44,
-    //         _ => panic!(
-    //             "Tried to get a hardware register wtih invalid address {:x}",
-    //             address
-    //         ),
-    //     }
-    // }
+        // Trigger with the DAC on: the channel plays and wave RAM is off limits.
+        registers.wb(0xFF1A, 0x80);
+        registers.wb(0xFF1E, 0x80);
+        assert_eq!(registers.rb(0xFF30), 0xFF);
+        assert_eq!(registers.rb(0xFF3F), 0xFF);
+        registers.wb(0xFF30, 0x34);
+
+        // Turning the DAC off stops the channel. The write while it played was ignored.
+        registers.wb(0xFF1A, 0x00);
+        assert_eq!(registers.rb(0xFF30), 0x12);
+
+        // Triggering with the DAC off doesn't start it.
+        registers.wb(0xFF1E, 0x80);
+        assert_eq!(registers.rb(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn test_wave_length_stops_channel() {
+        let mut registers = ApuRegisters::new();
+        registers.wb(0xFF30, 0x12);
+
+        // A length of 256 - 254 = 2 steps, with the length enabled.
+        registers.wb(0xFF1A, 0x80);
+        registers.wb(0xFF1B, 254);
+        registers.wb(0xFF1E, 0xC0);
+        registers.clock_lengths();
+        assert_eq!(registers.rb(0xFF30), 0xFF);
+
+        // Once it runs out the channel stops and wave RAM is readable again.
+        registers.clock_lengths();
+        assert_eq!(registers.rb(0xFF30), 0x12);
+
+        // Retriggering starts over with a full 256 steps.
+        registers.wb(0xFF1E, 0xC0);
+        for _ in 0..255 {
+            registers.clock_lengths();
+        }
+        assert_eq!(registers.rb(0xFF30), 0xFF);
+        registers.clock_lengths();
+        assert_eq!(registers.rb(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn test_power_off_stops_channels() {
+        let mut registers = ApuRegisters::new();
+        registers.wb(0xFF30, 0x12);
+        registers.wb(0xFF1A, 0x80);
+        registers.wb(0xFF1E, 0x80);
+        registers.wb(0xFF12, 0xF0);
+        registers.wb(0xFF14, 0x80);
+        assert_eq!(registers.rb(0xFF30), 0xFF);
+        assert!(registers.square1_playing);
+
+        registers.wb(0xFF26, 0x00);
+        assert_eq!(registers.rb(0xFF30), 0x12);
+        assert!(!registers.square1_playing);
+    }
 }
@@ -2,7 +2,9 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-const BOOT_ROM_PATH: &'static str = "data/dmg_rom.bin";
+// Outside of tests the boot ROM path comes from the config.
+#[cfg(test)]
+pub const BOOT_ROM_PATH: &'static str = "data/dmg_rom.bin";
 
 /// The values applied to the final state of the MMU once the boot rom has been run.
 
@@ -46,25 +48,27 @@ pub struct BootLoader {
 }
 
 impl BootLoader {
-    pub fn new(use_bootrom: bool) -> Self {
+    pub fn new(use_bootrom: bool, boot_rom_path: &str) -> Result<Self, String> {
         if use_bootrom {
-            Self {
-                data: Self::load_boot_rom().unwrap(),
+            let data = Self::load_boot_rom(boot_rom_path)
+                .map_err(|e| format!("Could not load boot ROM {}: {}", boot_rom_path, e))?;
+            Ok(Self {
+                data,
                 is_enabled: true,
-            }
+            })
         } else {
-            Self {
+            Ok(Self {
                 data: [0; 0x100],
                 is_enabled: false,
-            }
+            })
         }
     }
 
     /// Load the boot loader ROM from file.
     /// This is a 256byte ROM referencable at 0x00 - 0xFF, containing the logic for validating
     /// that the cartridge is legitimate, scolling the Nintendo logo and playing the chime.
-    pub fn load_boot_rom() -> io::Result<[u8; 0x100]> {
-        let mut f = File::open(BOOT_ROM_PATH)?;
+    pub fn load_boot_rom(path: &str) -> io::Result<[u8; 0x100]> {
+        let mut f = File::open(path)?;
         let mut buffer = [0; 0x100];
         f.read(&mut buffer[..])?;
         Ok(buffer)
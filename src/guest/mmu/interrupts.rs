@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Interrupts {
     // Both `inte` and `intf` have the same meaning for bits 0-4.  Bits 5-7 are unused.
     // Bit 4: Gamepad high to low
@@ -8,6 +11,8 @@ pub struct Interrupts {
     pub inte: u8, // Address 0xFFFF. Interrupt Enable Switches (is the interrupt enabled?)
     pub intf: u8, // Address 0xFF0F. Interrupt Flags (is the interrupt triggered?)
     pub is_halted: bool,
+    pub is_stopped: bool, // STOP: like HALT, but only a button press wakes the CPU.
+    pub halt_bug: bool,   // HALT didn't halt, and the next opcode byte is to be read twice.
 
     // Interrupt Master Enable. Modified via  EI and DI ops, not accessible by address.
     // When a call to disable or enable IME is made, it is done _after_ the _next_ opcode. This
@@ -24,6 +29,8 @@ impl Interrupts {
     pub fn new() -> Self {
         Self {
             is_halted: false,
+            is_stopped: false,
+            halt_bug: false,
             inte: 0,
             intf: 0,
             ime: true,
@@ -40,6 +47,29 @@ impl Interrupts {
         self.enable_ime_counter = delay;
     }
 
+    /// IME, then how many more steps a pending DI and EI wait before taking effect (0 if there's
+    /// none pending). IME isn't readable by the guest, so this is for debugging interrupts.
+    pub fn ime_state(&self) -> (bool, u8, u8) {
+        (self.ime, self.disable_ime_counter, self.enable_ime_counter)
+    }
+
+    /// Halted with VBlank the only interrupt enabled: nothing will happen until the next frame.
+    /// Games commonly wait like this once they've done a frame's work.
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.is_halted && self.inte & 0x1F == 0x01
+    }
+
+    /// HALT: stop running opcodes until an interrupt is pending. If IME is disabled and one already
+    /// is, the CPU doesn't halt at all. Instead there's the halt bug: PC fails to increment after
+    /// reading the next opcode byte, so that byte is read twice.
+    pub fn halt(&mut self) {
+        if !self.ime && self.inte & self.intf & 0x1F != 0 {
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
+    }
+
     /// Called at the start of every cycle to tick IME timers down and possibly modify the IME.
     pub fn tick_ime_timer(&mut self) {
         self.disable_ime_counter = match self.disable_ime_counter {
@@ -65,11 +95,6 @@ impl Interrupts {
     /// This happens on every CPU step, but most of the time returns 0 as there's no interrupt
     /// to handle. Returns an interrupt index if an interrupt that is to be handled.
     pub fn try_interrupt(&mut self) -> Option<u8> {
-        // If IME is disabled and we're not halted, there isnt any interrupt handling to do.
-        if !self.ime && !self.is_halted {
-            return None;
-        }
-
         // Get the bitwise intersection of interrupts that are enabled AND have their flag set.
         let active_interrupts = self.inte & self.intf;
 
@@ -78,9 +103,13 @@ impl Interrupts {
             return None;
         }
 
-        // Reset halted.  There's more complexity here that we aren't handling right now. See:
+        // A pending interrupt ends HALT, even if IME is disabled. In that case the CPU carries on
+        // after the HALT without handling the interrupt. See:
         // https://rednex.github.io/rgbds/gbz80.7.html#HALT
         self.is_halted = false;
+        if !self.ime {
+            return None;
+        }
 
         if self.intf > 0b11111 {
             panic!(
@@ -136,6 +165,24 @@ mod tests {
         assert_eq!(interrupts.intf, 0b00010100);
     }
 
+    #[test]
+    fn test_is_waiting_for_vblank() {
+        let mut interrupts = Interrupts::new();
+        interrupts.inte = 0x01;
+        assert!(!interrupts.is_waiting_for_vblank());
+
+        interrupts.halt();
+        assert!(interrupts.is_waiting_for_vblank());
+
+        // The unused bits don't matter, but any other interrupt could wake the CPU sooner.
+        interrupts.inte = 0xE1;
+        assert!(interrupts.is_waiting_for_vblank());
+        interrupts.inte = 0x05;
+        assert!(!interrupts.is_waiting_for_vblank());
+        interrupts.inte = 0x00;
+        assert!(!interrupts.is_waiting_for_vblank());
+    }
+
     #[test]
     fn test_disable_ime() {
         let mut interrupts = Interrupts::new();
@@ -1,14 +1,19 @@
+mod access_stats;
 mod apu;
 mod bootloader;
 mod interrupts;
 mod ppu;
 mod registers;
 mod timer;
-use super::cartridge::Cartridge;
+use super::cartridge::{Cartridge, CartridgeState};
+pub use access_stats::{AccessStats, RegionCounts};
 use apu::ApuRegisters;
 use bootloader::{BootLoader, BOOTROM_MMU_VALUES};
 use interrupts::Interrupts;
-use ppu::PpuRegisters;
+pub use ppu::{PpuMode, PpuRegisters};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::io::Write;
 use timer::TimerRegisters;
 
 pub struct MMU {
@@ -18,12 +23,18 @@ pub struct MMU {
     sram: [u8; 0x2000], // 8KB (no GBC banking support).
     vram: [u8; 0x2000], // 8KB graphics RAM.
     bootloader: BootLoader,
+    patch_boot_logo: bool, // Show the boot ROM a valid cartridge logo and header checksum.
     pub ppu: PpuRegisters,
     pub apu: ApuRegisters,
     pub timer: TimerRegisters,
 
     cartridge: Cartridge, // Cartridge contains the MBC logic.
     pub gamepad: u8,
+    serial_data: u8, // 0xFF01 (SB): the byte to send, replaced by the byte received.
+    serial_control: u8, // 0xFF02 (SC): bit 7 starts a transfer, bit 0 selects the clock.
+    pub serial_output: Vec<u8>, // Every byte sent over the serial port.
+    serial_sink: Option<Box<dyn Write>>, // Also gets every byte as it's sent, eg. stdout.
+    access_stats: Option<Cell<AccessStats>>, // `None` unless profiling. `rb` takes `&self`.
     pub interrupts: Interrupts,
     pub pc: u16,
     pub sp: u16,
@@ -37,12 +48,55 @@ pub struct MMU {
     f: u8,
 }
 
+/// The MMU's part of a `GuestState`: the CPU registers, RAM, and every hardware register.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MmuState {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub f: u8,
+    pub hram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub sram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub boot_rom_enabled: bool,
+    pub ppu: PpuRegisters,
+    pub apu: ApuRegisters,
+    pub timer: TimerRegisters,
+    pub interrupts: Interrupts,
+    pub cartridge: CartridgeState,
+    pub gamepad: u8,
+    pub serial_data: u8,
+    pub serial_control: u8,
+}
+
 impl MMU {
-    /// Initialize the MMU by loading the boot_rom into the first 256 addressable bytes.
+    /// Initialize the MMU by loading the boot_rom into the first 256 addressable bytes. The
+    /// emulator always passes its boot ROM path, so this shorthand is only for tests.
+    #[cfg(test)]
     pub fn new(cartridge_path: Option<&String>, use_bootrom: bool) -> Self {
+        let path = bootloader::BOOT_ROM_PATH;
+        Self::with_boot_rom_path(cartridge_path, use_bootrom, path, None).unwrap()
+    }
+
+    /// Initialize the MMU, loading the boot ROM from a specific file rather than the default, and
+    /// optionally applying an IPS patch to the cartridge ROM.
+    pub fn with_boot_rom_path(
+        cartridge_path: Option<&String>,
+        use_bootrom: bool,
+        boot_rom_path: &str,
+        patch_path: Option<&String>,
+    ) -> Result<Self, String> {
         let mut mmu = Self {
-            bootloader: BootLoader::new(use_bootrom),
-            cartridge: Cartridge::new(cartridge_path),
+            bootloader: BootLoader::new(use_bootrom, boot_rom_path)?,
+            cartridge: Cartridge::with_patch(cartridge_path, patch_path)?,
+            patch_boot_logo: false,
             ppu: PpuRegisters::new(),
             apu: ApuRegisters::new(),
             interrupts: Interrupts::new(),
@@ -52,6 +106,11 @@ impl MMU {
             sram: [0; 0x2000],
             vram: [0; 0x2000],
             gamepad: 0x2F, // Initialize with nothing pressed, bit 5 (buttons) selected.
+            serial_data: 0,
+            serial_control: 0,
+            serial_output: Vec::new(),
+            serial_sink: None,
+            access_stats: None,
             pc: 0,
             sp: 0, // Initialized by the software.
             a: 0,
@@ -91,11 +150,13 @@ impl MMU {
             // mmu.ppu.obj_palette_1 = 0;
         };
 
-        mmu
+        Ok(mmu)
     }
 
     /// Read a byte from address.
     pub fn rb(&self, address: u16) -> u8 {
+        self.count_read(address);
+
         match address {
             // the first 256KB that's usually addressing the cartridge main memory bank initially
             // addresses the BootLoader.
@@ -106,59 +167,281 @@ impl MMU {
                     self.cartridge.rb(address)
                 }
             }
+            0x0104..=0x014D if self.patch_boot_logo && self.bootloader.is_enabled => {
+                self.cartridge.rb_valid_header(address)
+            }
             0x0000..=0x7FFF => self.cartridge.rb(address),
+            // While the PPU is drawing (mode 3) it has VRAM to itself, and OAM from the start of
+            // the line's OAM search (mode 2). The CPU can't get at them: reads see 0xFF.
+            0x8000..=0x9FFF if self.ppu.mode == PpuMode::Drawing => 0xFF,
+            0xFE00..=0xFE9F if matches!(self.ppu.mode, PpuMode::OamScan | PpuMode::Drawing) => 0xFF,
             0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize],
+            0xA000..=0xBFFF => self.cartridge.rb(address), // Possible cartridge RAM.
             0xC000..=0xDFFF => self.sram[(address - 0xC000) as usize],
             0xE000..=0xFDFF => self.sram[(address - 0xC000 - 0x2000) as usize], // Mirror 0xC000.
             0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize],
             0xFEA0..=0xFEFF => 0xFF,
             0xFF00 => self.gamepad,
-            0xFF0f => self.interrupts.intf,
-            0xFF01 => 0, // TODO: serial write.
-            0xFF02 => 0, // TODO: serial control.
+            0xFF0F => self.interrupts.intf | 0xE0, // Only 5 interrupts: the top 3 bits read as 1.
+            0xFF01 => self.serial_data,
+            0xFF02 => self.serial_control | 0x7E, // Unused bits read as 1.
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF10..=0xFF3F => self.apu.rb(address),
-            0xFF46 => panic!("0xff46: OAM DMA cannot be read from."),
+            0xFF46 => 0xFF, // OAM DMA can't be read back.
             0xFF40..=0xFF4B => self.ppu.rb(address),
+            0xFF50 => 0xFF, // Write-only.
             0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
             0xFFFF => self.interrupts.inte,
-            _ => {
-                panic!("Tried to read from {:#x} which is not mapped.", address);
+            _ => 0xFF, // Nothing is mapped here (eg. unused I/O), so the bus reads high.
+        }
+    }
+
+    /// Read a byte as the PPU does, for rendering. VRAM and OAM are always accessible to it, even
+    /// in the modes where the CPU is locked out of them.
+    pub fn ppu_rb(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => {
+                self.count_read(address);
+                self.vram[(address - 0x8000) as usize]
             }
+            0xFE00..=0xFE9F => {
+                self.count_read(address);
+                self.oam[(address - 0xFE00) as usize]
+            }
+            _ => self.rb(address),
+        }
+    }
+
+    fn count_read(&self, address: u16) {
+        if let Some(stats) = &self.access_stats {
+            let mut counts = stats.get();
+            counts.count_read(address);
+            stats.set(counts);
+        }
+    }
+
+    /// Name the region or register an address belongs to, eg. "VRAM" or "IO: LCDC". For debuggers
+    /// and watchpoint UIs. Follows the same map as `rb`.
+    pub fn describe_address(&self, address: u16) -> &'static str {
+        match address {
+            0x0000..=0x00FF if self.bootloader.is_enabled => "Boot ROM",
+            0x0000..=0x3FFF => "Cartridge ROM bank 0",
+            0x4000..=0x7FFF => "Cartridge ROM (switchable bank)",
+            0x8000..=0x9FFF => "VRAM",
+            0xA000..=0xBFFF => "Cartridge RAM",
+            0xC000..=0xDFFF => "Work RAM",
+            0xE000..=0xFDFF => "Echo RAM (mirror of work RAM)",
+            0xFE00..=0xFE9F => "OAM",
+            0xFEA0..=0xFEFF => "Unusable",
+            0xFF00 => "IO: P1 (joypad)",
+            0xFF01 => "IO: SB (serial data)",
+            0xFF02 => "IO: SC (serial control)",
+            0xFF04 => "IO: DIV",
+            0xFF05 => "IO: TIMA",
+            0xFF06 => "IO: TMA",
+            0xFF07 => "IO: TAC",
+            0xFF0F => "IO: IF",
+            0xFF10..=0xFF26 => "IO: Sound",
+            0xFF30..=0xFF3F => "IO: Wave RAM",
+            0xFF40 => "IO: LCDC",
+            0xFF41 => "IO: STAT",
+            0xFF42 => "IO: SCY",
+            0xFF43 => "IO: SCX",
+            0xFF44 => "IO: LY",
+            0xFF45 => "IO: LYC",
+            0xFF46 => "IO: DMA",
+            0xFF47 => "IO: BGP",
+            0xFF48 => "IO: OBP0",
+            0xFF49 => "IO: OBP1",
+            0xFF4A => "IO: WY",
+            0xFF4B => "IO: WX",
+            0xFF50 => "IO: Boot ROM disable",
+            0xFF80..=0xFFFE => "HRAM",
+            0xFFFF => "IE",
+            _ => "Unmapped IO",
         }
     }
 
     /// Write an 8-bit value to an address.
     pub fn wb(&mut self, address: u16, value: u8) {
+        if let Some(stats) = &mut self.access_stats {
+            stats.get_mut().count_write(address);
+        }
+
         match address {
             0x0000..=0x7FFF => self.cartridge.wb(address, value), // Cartridge control registers.
             0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize] = value,
             0xA000..=0xBFFF => self.cartridge.wb(address, value), // Possible cartridge RAM.
             0xC000..=0xDFFF => self.sram[(address - 0xC000) as usize] = value,
+            0xE000..=0xFDFF => self.sram[(address - 0xC000 - 0x2000) as usize] = value, // Mirror.
             0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize] = value,
             0xFEA0..=0xFEFF => (),
             0xFF00 => self.gamepad = value,
-            0xFF01 => (),
-            // 0xFF01 => println!("{}", value as char), // TODO: serial
-            0xFF02 => (), // TODO: serial control.
+            0xFF01 => self.serial_data = value,
+            0xFF02 => self.serial_transfer(value),
             0xFF04..=0xFF07 => self.timer.wb(address, value),
-            0xFF0F => self.interrupts.intf = value,
+            0xFF0F => self.interrupts.intf = value & 0x1F,
             0xFF10..=0xFF3F => self.apu.wb(address, value),
             0xFF46 => self.oam_dma(value),
             0xFF40..=0xFF4B => self.ppu.wb(address, value),
+            // Unmap the boot ROM. It can't be mapped back, so once it's gone (or if it never ran
+            // because of `--noboot`) this does nothing.
             0xFF50 => self.bootloader.is_enabled = false,
             0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize] = value,
-            0xFF7F => (), // tetris.gb off-by-one error.
             0xFFFF => self.interrupts.inte = value,
-            _ => panic!("Tried to write to {:#x} which is not mapped.", address),
+            _ => (), // Nothing is mapped here (eg. 0xFF7F, which tetris.gb writes to by mistake).
+        }
+    }
+
+    /// Write to the serial control register. There's never a link cable attached, so a transfer
+    /// using the internal clock completes immediately: the byte is captured, 0xFF is "received",
+    /// and the serial interrupt is requested. Test ROMs (eg. Blargg's) report their results this
+    /// way. With the external clock selected, the transfer never happens.
+    fn serial_transfer(&mut self, value: u8) {
+        self.serial_control = value & 0x81;
+
+        if value & 0x81 == 0x81 {
+            self.serial_output.push(self.serial_data);
+            if let Some(sink) = &mut self.serial_sink {
+                // Flush every byte: test ROMs often print progress without a newline.
+                if let Err(e) = sink
+                    .write_all(&[self.serial_data])
+                    .and_then(|_| sink.flush())
+                {
+                    eprintln!("Could not write serial output: {}", e);
+                }
+            }
+            self.serial_data = 0xFF;
+            self.serial_control &= 0x01;
+            self.interrupts.intf |= 0x08;
+        }
+    }
+
+    /// Also send every serial byte to `sink` as soon as it's sent, eg. to watch a test ROM's
+    /// progress on stdout.
+    pub fn set_serial_sink(&mut self, sink: Box<dyn Write>) {
+        self.serial_sink = Some(sink);
+    }
+
+    /// Write the cartridge's battery-backed RAM to its save file, if it has any.
+    pub fn save_cartridge(&self) -> Result<bool, String> {
+        self.cartridge.save()
+    }
+
+    /// Load the cartridge's battery-backed RAM from its save file, if there is one.
+    pub fn load_cartridge(&mut self) -> Result<bool, String> {
+        self.cartridge.load()
+    }
+
+    /// Keep the cartridge's save file in a specific directory rather than next to the ROM.
+    pub fn set_save_dir(&mut self, dir: &str) {
+        self.cartridge.set_save_dir(dir);
+    }
+
+    /// The (ROM, RAM) banks the cartridge currently has mapped.
+    pub fn current_banks(&self) -> (u16, u8) {
+        self.cartridge.current_banks()
+    }
+
+    /// Log every cartridge RAM bank switch, for debugging saves.
+    pub fn enable_ram_bank_log(&mut self) {
+        self.cartridge.enable_ram_bank_log();
+    }
+
+    /// While the boot ROM runs, make the cartridge appear to have a valid logo and header checksum
+    /// so that the boot ROM doesn't lock up on homebrew or patched ROMs.
+    pub fn enable_boot_logo_patch(&mut self) {
+        self.patch_boot_logo = true;
+    }
+
+    /// Start counting reads and writes to each region of memory. Until this is called, nothing is
+    /// counted and the only cost per access is checking that the stats are `None`.
+    pub fn enable_access_stats(&mut self) {
+        self.access_stats = Some(Cell::new(AccessStats::default()));
+    }
+
+    /// Reads and writes per region so far. All zeroes if profiling was never enabled.
+    pub fn access_stats(&self) -> AccessStats {
+        match &self.access_stats {
+            Some(stats) => stats.get(),
+            None => AccessStats::default(),
         }
     }
 
+    /// True until the boot ROM unmaps itself by writing 0xFF50. Always false with `--noboot`.
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.bootloader.is_enabled
+    }
+
+    /// Capture the registers and memory. Serial output isn't included: it's a log of what was sent
+    /// rather than something the guest can see.
+    pub fn snapshot(&self) -> MmuState {
+        MmuState {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            f: self.f,
+            hram: self.hram.to_vec(),
+            oam: self.oam.to_vec(),
+            sram: self.sram.to_vec(),
+            vram: self.vram.to_vec(),
+            boot_rom_enabled: self.bootloader.is_enabled,
+            ppu: self.ppu.clone(),
+            apu: self.apu.clone(),
+            timer: self.timer.clone(),
+            interrupts: self.interrupts.clone(),
+            cartridge: self.cartridge.snapshot(),
+            gamepad: self.gamepad,
+            serial_data: self.serial_data,
+            serial_control: self.serial_control,
+        }
+    }
+
+    /// Put the registers and memory back to a snapshot. It must be from the same cartridge. A
+    /// state that doesn't fit (eg. a corrupt file) is an error, and leaves everything as it was.
+    pub fn restore(&mut self, state: &MmuState) -> Result<(), String> {
+        check_state_length("HRAM", &state.hram, self.hram.len())?;
+        check_state_length("OAM", &state.oam, self.oam.len())?;
+        check_state_length("work RAM", &state.sram, self.sram.len())?;
+        check_state_length("VRAM", &state.vram, self.vram.len())?;
+        self.cartridge.restore(&state.cartridge)?;
+
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.a = state.a;
+        self.b = state.b;
+        self.c = state.c;
+        self.d = state.d;
+        self.e = state.e;
+        self.h = state.h;
+        self.l = state.l;
+        self.f = state.f;
+        self.hram.copy_from_slice(&state.hram);
+        self.oam.copy_from_slice(&state.oam);
+        self.sram.copy_from_slice(&state.sram);
+        self.vram.copy_from_slice(&state.vram);
+        self.bootloader.is_enabled = state.boot_rom_enabled;
+        self.ppu = state.ppu.clone();
+        self.apu = state.apu.clone();
+        self.timer = state.timer.clone();
+        self.interrupts = state.interrupts.clone();
+        self.gamepad = state.gamepad;
+        self.serial_data = state.serial_data;
+        self.serial_control = state.serial_control;
+        Ok(())
+    }
+
     /// Read a word from address.
     /// DMG-01 is little endian so the least-significant byte is read first.
     pub fn rw(&self, address: u16) -> u16 {
         let lsb = self.rb(address) as u16;
-        let msb = self.rb(address + 1) as u16;
+        let msb = self.rb(address.wrapping_add(1)) as u16;
         (msb << 8) | lsb
     }
 
@@ -166,13 +449,13 @@ impl MMU {
     /// DMG-01 is little endian so the least-significant byte is written first.
     pub fn ww(&mut self, address: u16, value: u16) {
         self.wb(address, (value & 0xFF) as u8); // Mask only the LSB.
-        self.wb(address + 1, (value >> 8) as u8); // bit-shift until we have only the MSB.
+        self.wb(address.wrapping_add(1), (value >> 8) as u8); // bit-shift until we have only the MSB.
     }
 
     /// Get the next byte and advance the program counter by 1.
     pub fn get_next_byte(&mut self) -> u8 {
         let byte = self.rb(self.pc);
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         byte
     }
 
@@ -184,14 +467,14 @@ impl MMU {
     /// Get the next word in memory and advance the program counter by 2.
     pub fn get_next_word(&mut self) -> u16 {
         let word = self.rw(self.pc);
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         word
     }
 
     /// Push a word (an address of the an instruction) to the stack.
     /// Stack decrements by one first (it grows downward in address space at the top of low RAM).
     pub fn push_stack(&mut self, address: u16) {
-        self.sp -= 2;
+        self.sp = self.sp.wrapping_sub(2);
         self.ww(self.sp, address);
     }
 
@@ -199,17 +482,20 @@ impl MMU {
     /// It will go into a register.
     pub fn pop_stack(&mut self) -> u16 {
         let address = self.rw(self.sp);
-        self.sp += 2;
+        self.sp = self.sp.wrapping_add(2);
         address
     }
 
     /// A very simple write of 160 bytes beginning at an address into OAM memory.
     /// The value is actually the MSB of the address. From there we walk 160 bytes from it and
     /// copy them to OAM.
+    /// The DMA unit can't see OAM, I/O or HRAM. On a DMG, sources from 0xE000 up have bit 13
+    /// ignored just like echo RAM, so 0xE0-0xFD read the echo of work RAM and 0xFE-0xFF read
+    /// 0xDE00 and 0xDF00 rather than OAM and I/O. It isn't locked out of VRAM like the CPU.
     pub fn oam_dma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
+        let base = ((value as u16) << 8) & 0xDFFF;
         for n in 0..0xA0 {
-            let byte = self.rb(base + n);
+            let byte = self.ppu_rb(base + n);
             self.wb(0xFE00 + n, byte);
         }
     }
@@ -233,14 +519,21 @@ impl MMU {
         }
     }
 
-    /// If LY and LYC are equal and if LYC Interrupt enable (0xFF41) is set, set a STAT interrupt.
-    /// Documentation says this is "permanently compared" so we should do it every tick. It's
-    /// possible that it can be optimized. There's also a possibility it also has to be done
-    /// immediately when ppu.lyc is changed.
-    pub fn check_lyc_interrupt(&mut self) {
-        if self.ppu.lyc_int_enable && self.ppu.line == self.ppu.lyc {
+    /// Update the STAT interrupt line and request the interrupt if it has just gone high. The
+    /// line is every source enabled in STAT (0xFF41) ORed together: LY == LYC, and modes 0, 1 and
+    /// 2. Only a rising edge interrupts, so while one source holds the line high, another becoming
+    /// true doesn't interrupt again ("STAT blocking").
+    pub fn update_stat_interrupt(&mut self) {
+        let ppu = &self.ppu;
+        let stat_line = (ppu.lyc_int_enable && ppu.line == ppu.lyc)
+            || (ppu.mode2_int_enable && ppu.mode == PpuMode::OamScan)
+            || (ppu.mode1_int_enable && ppu.mode == PpuMode::VBlank)
+            || (ppu.mode0_int_enable && ppu.mode == PpuMode::HBlank);
+
+        if stat_line && !self.ppu.stat_line {
             self.interrupts.intf |= 0x02;
         }
+        self.ppu.stat_line = stat_line;
     }
 }
 
@@ -250,9 +543,27 @@ pub fn is_bit_set(value: u8, position: u8) -> bool {
     (value & (1 << position)) != 0
 }
 
+/// Check that a buffer from a save state is the size of the one it's restoring.
+pub fn check_state_length<T>(name: &str, buffer: &[T], expected: usize) -> Result<(), String> {
+    if buffer.len() != expected {
+        return Err(format!(
+            "Save state's {} is {} long, expected {}.",
+            name,
+            buffer.len(),
+            expected
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::rc::Rc;
 
     #[test]
     fn test_is_bit_set() {
@@ -302,4 +613,254 @@ mod tests {
         assert_eq!(0x11FF, value);
         assert_eq!(mmu.sp, 0xfffe); // Stack Pointer has been reset.
     }
+
+    #[test]
+    fn test_serial_transfer() {
+        let mut mmu = MMU::new(None, false);
+        mmu.interrupts.intf = 0;
+
+        for byte in b"ok" {
+            mmu.wb(0xFF01, *byte);
+            mmu.wb(0xFF02, 0x81);
+        }
+
+        assert_eq!(mmu.serial_output, b"ok");
+        assert_eq!(mmu.rb(0xFF01), 0xFF);
+        assert_eq!(mmu.rb(0xFF02), 0x7F); // Transfer complete: bit 7 cleared.
+        assert_eq!(mmu.interrupts.intf & 0x08, 0x08);
+
+        // External clock: no link partner, so nothing is sent.
+        mmu.wb(0xFF01, b'!');
+        mmu.wb(0xFF02, 0x80);
+        assert_eq!(mmu.serial_output, b"ok");
+        assert_eq!(mmu.rb(0xFF02), 0xFE);
+    }
+
+    /// A serial sink that can still be inspected once the MMU owns it.
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serial_sink() {
+        let mut mmu = MMU::new(None, false);
+        let received = Rc::new(RefCell::new(Vec::new()));
+        mmu.set_serial_sink(Box::new(SharedSink(received.clone())));
+
+        for (n, byte) in b"Passed".iter().enumerate() {
+            mmu.wb(0xFF01, *byte);
+            mmu.wb(0xFF02, 0x81);
+            assert_eq!(received.borrow()[..], b"Passed"[..n + 1]); // Each byte as it's sent.
+        }
+
+        // Bytes that aren't sent don't reach it.
+        mmu.wb(0xFF01, b'!');
+        mmu.wb(0xFF02, 0x80);
+        assert_eq!(received.borrow()[..], b"Passed"[..]);
+    }
+
+    #[test]
+    fn test_unmapped_io() {
+        let mut mmu = MMU::new(None, false);
+        for &address in [0xFF03, 0xFF4C, 0xFF7F].iter() {
+            mmu.wb(address, 0x12);
+            assert_eq!(mmu.rb(address), 0xFF, "{:#06x}", address);
+        }
+        assert_eq!(mmu.rb(0xFF46), 0xFF);
+
+        // IF only has 5 bits. The rest read as 1.
+        mmu.wb(0xFF0F, 0xFF);
+        assert_eq!(mmu.interrupts.intf, 0x1F);
+        mmu.wb(0xFF0F, 0x01);
+        assert_eq!(mmu.rb(0xFF0F), 0xE1);
+    }
+
+    #[test]
+    fn test_read_tac() {
+        let mut mmu = MMU::new(None, false);
+        assert_eq!(mmu.rb(0xFF07), 0xF8);
+
+        mmu.wb(0xFF07, 0x05); // Started, 262.144 KHz.
+        assert_eq!(mmu.rb(0xFF07), 0xFD);
+
+        mmu.wb(0xFF07, 0xFA); // Stopped, 65.536 KHz. The unused bits aren't stored.
+        assert_eq!(mmu.rb(0xFF07), 0xFA);
+        assert_eq!(mmu.timer.clock, 0x02);
+        assert!(!mmu.timer.started);
+    }
+
+    #[test]
+    fn test_vram_and_oam_locked_by_ppu_mode() {
+        let mut mmu = MMU::new(None, false);
+        mmu.wb(0x8000, 0x11);
+        mmu.wb(0xFE00, 0x22);
+
+        // (mode, VRAM read, OAM read)
+        let expected = [
+            (PpuMode::HBlank, 0x11, 0x22),
+            (PpuMode::VBlank, 0x11, 0x22),
+            (PpuMode::OamScan, 0x11, 0xFF),
+            (PpuMode::Drawing, 0xFF, 0xFF),
+        ];
+        for &(mode, vram, oam) in expected.iter() {
+            mmu.ppu.mode = mode;
+            assert_eq!((mmu.rb(0x8000), mmu.rb(0xFE00)), (vram, oam), "{:?}", mode);
+
+            // The PPU itself can always read them.
+            assert_eq!((mmu.ppu_rb(0x8000), mmu.ppu_rb(0xFE00)), (0x11, 0x22));
+        }
+
+        // With the LCD off, the PPU isn't using either of them.
+        mmu.wb(0xFF40, 0x00);
+        assert_eq!((mmu.rb(0x8000), mmu.rb(0xFE00)), (0x11, 0x22));
+    }
+
+    #[test]
+    fn test_access_stats() {
+        let mut mmu = MMU::new(None, false);
+        mmu.rb(0xC000);
+        assert_eq!(mmu.access_stats(), AccessStats::default()); // Not counted until enabled.
+
+        mmu.enable_access_stats();
+        mmu.rb(0x0150);
+        mmu.wb(0x8000, 1);
+        mmu.wb(0x9FFF, 1);
+        mmu.rb(0xE000); // Echo RAM counts as work RAM.
+        mmu.wb(0xFF80, 1);
+        mmu.rb(0xFF44);
+        mmu.rb(0xFFFF);
+
+        let stats = mmu.access_stats();
+        assert_eq!(stats.reads.rom, 1);
+        assert_eq!(stats.reads.wram, 1);
+        assert_eq!(stats.reads.io, 2);
+        assert_eq!(stats.reads.vram, 0);
+        assert_eq!(stats.writes.vram, 2);
+        assert_eq!(stats.writes.hram, 1);
+        assert_eq!(stats.writes.io, 0);
+
+        // OAM DMA reads its source and writes every byte of OAM.
+        mmu.wb(0xFF46, 0xC0);
+        let stats = mmu.access_stats();
+        assert_eq!(stats.reads.wram, 1 + 0xA0);
+        assert_eq!(stats.writes.oam, 0xA0);
+        assert_eq!(stats.writes.io, 1);
+
+        // So do the PPU's reads, which skip the CPU's lock.
+        mmu.ppu.mode = PpuMode::Drawing;
+        mmu.ppu_rb(0x8000);
+        mmu.ppu_rb(0xFE00);
+        let stats = mmu.access_stats();
+        assert_eq!(stats.reads.vram, 1);
+        assert_eq!(stats.reads.oam, 1);
+    }
+
+    #[test]
+    fn test_describe_address() {
+        let mmu = MMU::new(None, false);
+        assert_eq!(mmu.describe_address(0x0000), "Cartridge ROM bank 0");
+        assert_eq!(
+            mmu.describe_address(0x4000),
+            "Cartridge ROM (switchable bank)"
+        );
+        assert_eq!(mmu.describe_address(0x9800), "VRAM");
+        assert_eq!(
+            mmu.describe_address(0xE000),
+            "Echo RAM (mirror of work RAM)"
+        );
+        assert_eq!(mmu.describe_address(0xFE9F), "OAM");
+        assert_eq!(mmu.describe_address(0xFF40), "IO: LCDC");
+        assert_eq!(mmu.describe_address(0xFF44), "IO: LY");
+        assert_eq!(mmu.describe_address(0xFF35), "IO: Wave RAM");
+        assert_eq!(mmu.describe_address(0xFF7F), "Unmapped IO");
+        assert_eq!(mmu.describe_address(0xFFFF), "IE");
+    }
+
+    #[test]
+    fn test_no_boot_rom_reads_cartridge() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0x42;
+        rom[0x00FF] = 0x24;
+        let path = env::temp_dir().join("gameboy_test_no_boot_rom.gb");
+        fs::write(&path, rom).unwrap();
+
+        let mut mmu = MMU::new(Some(&path.to_string_lossy().into_owned()), false);
+        assert_eq!(mmu.rb(0x0000), 0x42);
+        assert_eq!(mmu.rb(0x00FF), 0x24);
+
+        // Games write 0xFF50 too. With the boot ROM already unmapped it changes nothing.
+        mmu.wb(0xFF50, 0x01);
+        assert_eq!(mmu.rb(0x0000), 0x42);
+        assert_eq!(mmu.rb(0x00FF), 0x24);
+        assert_eq!(mmu.rb(0xFF50), 0xFF);
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mut mmu = MMU::new(None, false);
+
+        mmu.wb(0xE000, 0x42);
+        assert_eq!(mmu.rb(0xC000), 0x42);
+        mmu.wb(0xDDFF, 0x24);
+        assert_eq!(mmu.rb(0xFDFF), 0x24);
+    }
+
+    #[test]
+    fn test_oam_dma_from_echo_ram() {
+        let mut mmu = MMU::new(None, false);
+        for n in 0..0xA0 {
+            mmu.wb(0xE100 + n, n as u8 ^ 0x5A); // Written via echo RAM, landing at 0xC100.
+        }
+
+        mmu.wb(0xFF46, 0xE1);
+        for n in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + n), mmu.rb(0xC100 + n));
+            assert_eq!(mmu.rb(0xFE00 + n), n as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_from_vram_while_drawing() {
+        let mut mmu = MMU::new(None, false);
+        for n in 0..0xA0 {
+            mmu.wb(0x8000 + n, n as u8);
+        }
+
+        mmu.ppu.mode = PpuMode::Drawing;
+        mmu.wb(0xFF46, 0x80);
+        mmu.ppu.mode = PpuMode::HBlank;
+        for n in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + n), n as u8);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_from_high_sources() {
+        let mut mmu = MMU::new(None, false);
+        for n in 0..0xA0 {
+            mmu.wb(0xDE00 + n, n as u8);
+            mmu.wb(0xDF00 + n, !n as u8);
+        }
+
+        // 0xFE would be OAM itself. It reads work RAM at 0xDE00 instead.
+        mmu.wb(0xFF46, 0xFE);
+        for n in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + n), n as u8);
+        }
+
+        // 0xFF would be I/O and HRAM. It reads 0xDF00 instead.
+        mmu.wb(0xFF46, 0xFF);
+        for n in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + n), !n as u8);
+        }
+    }
 }
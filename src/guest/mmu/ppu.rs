@@ -1,5 +1,40 @@
 use super::is_bit_set;
+use serde::{Deserialize, Serialize};
 
+/// What the PPU is doing. Shown in the bottom 2 bits of STAT (0xFF41).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PpuMode {
+    HBlank,  // Mode 0: finished a line.
+    VBlank,  // Mode 1: finished a frame.
+    OamScan, // Mode 2: searching OAM for sprites on this line.
+    Drawing, // Mode 3: transferring pixels to the LCD.
+}
+
+impl PpuMode {
+    /// The mode for a STAT mode value. Only the bottom 2 bits are considered. The mode bits of
+    /// STAT are read-only, so only the tests need this.
+    #[cfg(test)]
+    pub fn from_bits(value: u8) -> Self {
+        match value & 0x03 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            _ => PpuMode::Drawing,
+        }
+    }
+
+    /// The 2-bit value for STAT.
+    pub fn bits(self) -> u8 {
+        match self {
+            PpuMode::HBlank => 0,
+            PpuMode::VBlank => 1,
+            PpuMode::OamScan => 2,
+            PpuMode::Drawing => 3,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PpuRegisters {
     // STAT (0xFF41)
     pub lyc_int_enable: bool,   // 0xFF41 (bit 6) LYC  interrupt enable flag.
@@ -16,7 +51,8 @@ pub struct PpuRegisters {
     pub win_x: u8,              // 0xFF4A: Window x position.
     pub win_y: u8,              // 0xFF4B: Window y position.
     pub lyc: u8,                // 0xFF45: LCD Y Compare.
-    pub mode: u8,
+    pub mode: PpuMode,          // 0xFF41 (bits 0-1): what the PPU is currently doing.
+    pub stat_line: bool,        // The STAT interrupt line: every enabled STAT source, ORed.
 
     // LCDC (0xFF40)
     pub lcd_on: bool,          // Bit7: Draw picture?
@@ -29,6 +65,7 @@ pub struct PpuRegisters {
     pub window_bg_on: bool,    // Bit0: Draw Window and Background?
 
     pub clear_screen: bool, // Emulator flag: get PPU to clear the screen and reset mode clock.
+    pub restart: bool,      // Emulator flag: LCD was turned on, so get PPU to restart the frame.
 }
 
 impl PpuRegisters {
@@ -43,7 +80,8 @@ impl PpuRegisters {
             mode1_int_enable: false,
             mode0_int_enable: false,
             lyc: 0,
-            mode: 0,
+            mode: PpuMode::HBlank,
+            stat_line: false,
             obj_palette_0: 0,
             obj_palette_1: 0,
             scx: 0,
@@ -57,6 +95,7 @@ impl PpuRegisters {
             window_on: false,
             window_tilemap: false,
             clear_screen: false,
+            restart: false,
         }
     }
 
@@ -74,18 +113,24 @@ impl PpuRegisters {
                     | (if self.sprite_on { 0x02 } else { 0 })
                     | (if self.window_bg_on { 0x01 } else { 0 })
             }
+            // Bit 7 is unused and reads as 1. Bits 0-2 are the PPU's state, not what was written.
             0xFF41 => {
-                (if self.lyc_int_enable { 0x40 } else { 0 })
+                0x80 | (if self.lyc_int_enable { 0x40 } else { 0 })
                     | (if self.mode2_int_enable { 0x20 } else { 0 })
                     | (if self.mode1_int_enable { 0x10 } else { 0 })
                     | (if self.mode0_int_enable { 0x08 } else { 0 })
                     | (if self.line == self.lyc { 0x04 } else { 0 })
-                    | self.mode
+                    | self.mode.bits()
             }
             0xFF42 => self.scy,
             0xFF43 => self.scx,
             0xFF44 => self.line,
             0xFF45 => self.lyc,
+            0xFF47 => self.background_palette,
+            0xFF48 => self.obj_palette_0,
+            0xFF49 => self.obj_palette_1,
+            0xFF4A => self.win_y,
+            0xFF4B => self.win_x,
             _ => panic!(
                 "Tried to get a PPU register wtih invalid address {:x}",
                 address
@@ -106,11 +151,22 @@ impl PpuRegisters {
                 self.sprite_on = is_bit_set(value, 1);
                 self.window_bg_on = is_bit_set(value, 0);
 
-                // LCD was turned off. Set a flag telling PPU system to clear screen and reset.
+                // LCD was turned off. LY and the mode go to 0 straight away, so they read back
+                // right even before the PPU next steps. Set a flag telling the PPU system to clear
+                // the screen. A restart still pending from turning it on is no longer wanted.
                 if was_lcd_on && !self.lcd_on {
+                    self.line = 0;
+                    self.mode = PpuMode::HBlank;
                     self.clear_screen = true;
+                    self.restart = false;
+                }
+
+                // LCD was turned on. Set a flag telling PPU system to start again from line 0.
+                if !was_lcd_on && self.lcd_on {
+                    self.restart = true;
                 }
             }
+            // Only the interrupt enables can be written. The mode and coincidence bits are read-only.
             0xFF41 => {
                 self.lyc_int_enable = is_bit_set(value, 6);
                 self.mode2_int_enable = is_bit_set(value, 5);
@@ -119,7 +175,7 @@ impl PpuRegisters {
             }
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
-            0xFF44 => panic!("Cannot set hwreg.line"),
+            0xFF44 => (), // LY is read-only. Some games write to it anyway; the write is ignored.
             0xFF45 => self.lyc = value,
             0xFF47 => self.background_palette = value,
             0xFF48 => self.obj_palette_0 = value,
@@ -133,3 +189,58 @@ impl PpuRegisters {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ppu_mode_bits() {
+        for value in 0..4 {
+            assert_eq!(PpuMode::from_bits(value).bits(), value);
+        }
+        assert_eq!(PpuMode::from_bits(0), PpuMode::HBlank);
+        assert_eq!(PpuMode::from_bits(1), PpuMode::VBlank);
+        assert_eq!(PpuMode::from_bits(2), PpuMode::OamScan);
+        assert_eq!(PpuMode::from_bits(3), PpuMode::Drawing);
+        assert_eq!(PpuMode::from_bits(0x86), PpuMode::OamScan); // Other STAT bits are ignored.
+    }
+
+    #[test]
+    fn test_stat_mode() {
+        let mut registers = PpuRegisters::new();
+        registers.lyc = 1; // Keep the coincidence bit clear.
+        registers.mode = PpuMode::Drawing;
+        assert_eq!(registers.rb(0xFF41), 0x83);
+    }
+
+    #[test]
+    fn test_stat_write() {
+        let mut registers = PpuRegisters::new();
+        registers.lyc = 1;
+        registers.mode = PpuMode::OamScan;
+
+        // The enables read back, but writes can't clobber the mode or coincidence bits.
+        registers.wb(0xFF41, 0x28 | 0x07);
+        assert_eq!(registers.rb(0xFF41), 0x80 | 0x28 | 0x02);
+        assert!(registers.mode2_int_enable && registers.mode0_int_enable);
+        assert_eq!(registers.mode, PpuMode::OamScan);
+
+        registers.line = 1;
+        registers.wb(0xFF41, 0x40);
+        assert_eq!(registers.rb(0xFF41), 0x80 | 0x40 | 0x04 | 0x02);
+
+        registers.wb(0xFF41, 0x00);
+        assert_eq!(registers.rb(0xFF41), 0x80 | 0x04 | 0x02);
+    }
+
+    #[test]
+    fn test_write_ly_is_ignored() {
+        let mut registers = PpuRegisters::new();
+        registers.line = 42;
+
+        registers.wb(0xFF44, 0x99);
+        assert_eq!(registers.line, 42);
+        assert_eq!(registers.rb(0xFF44), 42);
+    }
+}
@@ -1,14 +1,34 @@
 use super::is_bit_set;
+use serde::{Deserialize, Serialize};
 
 /// There are two timers: the Divider Register, and the Timer Counter. The Divider is always running
 /// while the Counter can be started and stopped.
+///
+/// The Divider is really the top 8 bits of a 16-bit system counter that increments every cycle,
+/// so it ticks at 16.384 KHz. Other hardware is clocked off of that counter's bits too: the APU's
+/// frame sequencer steps whenever bit 12 (DIV bit 4) goes from 1 to 0, ie. at 512 Hz. (It's bit 13
+/// on a CGB in double speed mode.) Writing to DIV resets the whole counter, so it can cause that
+/// falling edge early.
+///
+/// The Counter (TIMA) works the same way: it increments when the system counter bit selected by the
+/// clock goes from 1 to 0 (that bit is ANDed with the started flag first). Because it's an edge
+/// detector, anything that makes the selected bit fall early also ticks TIMA: resetting DIV, or
+/// changing the clock select or stopping the timer while the selected bit is 1.
+///
+/// When TIMA overflows it reads 0 for one machine cycle before being reloaded from TMA and raising
+/// the interrupt. Writing TIMA in that window cancels the reload and the interrupt, while writing
+/// TMA changes the value that gets reloaded.
 /// clock (0xFF07) modes:
-/// 00: 4.096 KHz
-/// 01: 262.144 Khz
-/// 10: 65.536 KHz
-/// 11: 16.384 KHz
+/// 00: 4.096 KHz (bit 9)
+/// 01: 262.144 Khz (bit 3)
+/// 10: 65.536 KHz (bit 5)
+/// 11: 16.384 KHz (bit 7)
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TimerRegisters {
-    pub divider: u8,
+    pub system_counter: u16,       // 0xFF04 (DIV) is the upper byte.
+    pub frame_sequencer_ticks: u8, // Falling edges of bit 12 not yet handled by the APU.
+    pub reload_pending: bool,      // TIMA overflowed and is reloaded on the next machine cycle.
+    pub overflowed: bool,          // TIMA was reloaded and the Timer interrupt is yet to be raised.
     pub counter: u8,
     pub modulo: u8,
     pub started: bool, // 0xFF07 (bit 2) Start/Stop timer.
@@ -18,7 +38,10 @@ pub struct TimerRegisters {
 impl TimerRegisters {
     pub fn new() -> Self {
         Self {
-            divider: 0,
+            system_counter: 0,
+            frame_sequencer_ticks: 0,
+            reload_pending: false,
+            overflowed: false,
             counter: 0,
             modulo: 0,
             started: false,
@@ -28,22 +51,86 @@ impl TimerRegisters {
 
     pub fn rb(&self, address: u16) -> u8 {
         match address {
-            0xFF04 => self.divider,
+            0xFF04 => (self.system_counter >> 8) as u8,
             0xFF05 => self.counter,
             0xFF06 => self.modulo,
-            0xFF07 => self.clock | ((self.started as u8) << 2),
+            0xFF07 => 0xF8 | ((self.started as u8) << 2) | self.clock, // Bits 3-7 are unused: 1.
             _ => panic!("Tried to read from invalid Timer register: {:x}", address),
         }
     }
 
+    /// Advance the system counter by some cycles, noting any frame sequencer steps and ticking the
+    /// Counter. It's advanced one machine cycle (4 clocks) at a time: the fastest clock's bit falls
+    /// every 16, so an instruction's worth of cycles can cause more than one Counter tick.
+    pub fn tick(&mut self, cycles: u8) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            if self.reload_pending {
+                self.reload_pending = false;
+                self.counter = self.modulo;
+                self.overflowed = true;
+            }
+
+            let step = remaining.min(4);
+            self.set_system_counter(self.system_counter.wrapping_add(step as u16));
+            remaining -= step;
+        }
+    }
+
+    /// Bit 12 falls every time the counter passes a multiple of 0x2000.
+    fn set_system_counter(&mut self, value: u16) {
+        let bit_12_was_set = self.system_counter & 0x1000 != 0;
+        let timer_bit_was_set = self.timer_bit();
+        self.system_counter = value;
+
+        if bit_12_was_set && self.system_counter & 0x1000 == 0 {
+            self.frame_sequencer_ticks += 1;
+        }
+        if timer_bit_was_set && !self.timer_bit() {
+            self.increment_counter();
+        }
+    }
+
+    /// The system counter bit selected by the clock, ANDed with the started flag. The Counter
+    /// increments whenever this falls.
+    fn timer_bit(&self) -> bool {
+        let bit = match self.clock {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.started && is_bit_set((self.system_counter >> bit) as u8, 0)
+    }
+
+    /// On overflow the Counter is left at 0 for a machine cycle, then reloaded from the Modulo and
+    /// the Timer interrupt is requested.
+    fn increment_counter(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == 0 {
+            self.reload_pending = true;
+        }
+    }
+
     pub fn wb(&mut self, address: u16, value: u8) {
         match address {
-            0xFF04 => self.divider = 0,
-            0xFF05 => self.counter = value,
+            0xFF04 => self.set_system_counter(0),
+            0xFF05 => {
+                // Writing during the reload delay cancels the reload and the interrupt.
+                self.counter = value;
+                self.reload_pending = false;
+            }
             0xFF06 => self.modulo = value,
             0xFF07 => {
+                let timer_bit_was_set = self.timer_bit();
                 self.started = is_bit_set(value, 2);
                 self.clock = value & 0x3; // Bottom two bits represent one of 4 clock options.
+
+                // Switching to a clock whose bit is 0 (or stopping the timer) while the old clock's
+                // bit is 1 looks like a falling edge, so the Counter ticks. A DMG quirk.
+                if timer_bit_was_set && !self.timer_bit() {
+                    self.increment_counter();
+                }
             }
             _ => panic!(
                 "Tried to write {:#x} to invalid Timer register: {:#x}",
@@ -52,3 +139,104 @@ impl TimerRegisters {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_rate() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF07, 0x05); // Started, 262.144 KHz: every 16 cycles.
+
+        for _ in 0..16 {
+            timer.tick(4);
+        }
+        assert_eq!(timer.counter, 4);
+
+        timer.tick(24); // Crosses 2 falling edges in one go.
+        assert_eq!(timer.counter, 5);
+        timer.tick(8);
+        assert_eq!(timer.counter, 6);
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        // The Counter reads 0 for a machine cycle before it's reloaded.
+        timer.tick(16);
+        assert_eq!(timer.counter, 0);
+        assert!(!timer.overflowed);
+        timer.tick(4);
+        assert_eq!(timer.counter, 0xAB);
+        assert!(timer.overflowed);
+    }
+
+    #[test]
+    fn test_counter_write_cancels_reload() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        timer.tick(16);
+        timer.wb(0xFF05, 0x12);
+        timer.tick(4);
+        assert_eq!(timer.counter, 0x12);
+        assert!(!timer.overflowed);
+    }
+
+    #[test]
+    fn test_modulo_write_during_reload() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF06, 0xAB);
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF07, 0x05);
+
+        timer.tick(16);
+        timer.wb(0xFF06, 0x34);
+        timer.tick(4);
+        assert_eq!(timer.counter, 0x34);
+        assert!(timer.overflowed);
+    }
+
+    #[test]
+    fn test_clock_change_glitch() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF07, 0x04); // Started, 4.096 KHz: bit 9.
+
+        // Bit 9 is set, bit 3 isn't. Going from the slow clock to the fast one is a falling edge.
+        timer.system_counter = 0x0200;
+        timer.wb(0xFF07, 0x05);
+        assert_eq!(timer.counter, 1);
+
+        // Bit 3 is set, so going back to bit 9 (clear) ticks again. Stopping does too.
+        timer.system_counter = 0x0008;
+        timer.wb(0xFF07, 0x04);
+        assert_eq!(timer.counter, 2);
+        timer.system_counter = 0x0200;
+        timer.wb(0xFF07, 0x00);
+        assert_eq!(timer.counter, 3);
+
+        // With the selected bit clear there's no edge, so nothing happens.
+        timer.system_counter = 0x0000;
+        timer.wb(0xFF07, 0x04);
+        timer.wb(0xFF07, 0x05);
+        assert_eq!(timer.counter, 3);
+    }
+
+    #[test]
+    fn test_div_reset_ticks_counter() {
+        let mut timer = TimerRegisters::new();
+        timer.wb(0xFF07, 0x04);
+        timer.system_counter = 0x0200;
+
+        timer.wb(0xFF04, 0x12);
+        assert_eq!(timer.system_counter, 0);
+        assert_eq!(timer.counter, 1);
+    }
+}
@@ -1,5 +1,21 @@
+// The guest shouldn't be able to crash the emulator, whatever a ROM does: unmapped reads return
+// 0xFF, unmapped writes are ignored, and unimplemented opcodes are skipped. (tests/fuzz.rs runs
+// random ROMs to check.) The panics that remain are intentional:
+// - `CPU`: an unimplemented opcode when `strict_opcodes` is on. That's what it's for.
+// - `PpuRegisters`, `TimerRegisters` and the MBCs: an address outside their range. The MMU only
+//   routes their own addresses to them.
+// - `Interrupts::try_interrupt` and `MMU::try_interrupt`: an interrupt flag above bit 4. The MMU
+//   masks writes to IF, so only the emulator itself could set one.
+// - `OpCodes`: an opcode missing from data/opcodes.json, which lists all 512.
+// - `MMU::restore` and `Mbc::restore`: a snapshot from a different cartridge.
+// - `MMU::new`: a missing boot ROM. It's a convenience for tests; `with_boot_rom_path` returns an
+//   error instead.
 mod cartridge;
 mod mmu;
 mod opcodes;
+mod state;
 pub mod systems;
-pub use mmu::MMU;
+#[cfg(test)]
+pub use cartridge::NINTENDO_LOGO; // For building boot ROMs in tests.
+pub use mmu::{AccessStats, PpuMode, RegionCounts, MMU};
+pub use state::GuestState;
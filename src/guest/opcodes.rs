@@ -49,7 +49,7 @@ impl OpCodes {
 
     /// Get a string representation of an opcode. Great for debugging.const
     /// Examples:
-    /// ```
+    /// ```text
     /// 0x31 LD   SP    d16    3 12    [- - - -]
     /// 0xAF XOR  A            1 4     [Z 0 0 0]
     /// 0x21 LD   HL    d16    3 12    [- - - -]
@@ -112,8 +112,55 @@ impl OpCodes {
         )
     }
 
-    /// Return the number of m-cycles (not t-states).
-    /// The JSON stores t-states so we divide by four.
+    /// Disassemble the instruction starting at the first of `bytes`. There are always 3 bytes but
+    /// the instruction might not use them all. Return the instruction as text (eg. "LD HL,0xc000")
+    /// and how many bytes long it is.
+    pub fn disassemble(&self, bytes: [u8; 3]) -> (String, u8) {
+        let opcode = if bytes[0] == 0xCB {
+            self.get_opcode(bytes[1], true)
+        } else {
+            self.get_opcode(bytes[0], false)
+        };
+        let n = bytes[1]; // Immediate 8-bit data.
+        let nn = (bytes[2] as u16) << 8 | bytes[1] as u16; // Immediate 16-bit data.
+
+        let operands: Vec<String> = opcode
+            .operands
+            .iter()
+            .map(|operand| {
+                let mut text = match operand.name.as_str() {
+                    "d8" => format!("{:#04x}", n),
+                    "a8" => format!("{:#06x}", 0xFF00 + n as u16),
+                    "r8" => format!("{}", n as i8),
+                    "d16" | "a16" => format!("{:#06x}", nn),
+                    "SP" if operand.increment == Some(true) => format!("SP{:+}", n as i8), // SP+r8
+                    name => String::from(name),
+                };
+
+                if operand.name != "SP" && operand.increment == Some(true) {
+                    text.push('+');
+                }
+                if operand.decrement == Some(true) {
+                    text.push('-');
+                }
+                if !operand.immediate {
+                    text = format!("({})", text);
+                }
+                text
+            })
+            .collect();
+
+        let text = if operands.is_empty() {
+            opcode.mnemonic.clone()
+        } else {
+            format!("{} {}", opcode.mnemonic, operands.join(","))
+        };
+        (text, opcode.bytes)
+    }
+
+    /// Return the number of t-states (not m-cycles), as stored in the JSON. The rest of the emulator
+    /// (timer, PPU, APU) counts t-states too. For CB-prefixed opcodes, this includes fetching the
+    /// prefix: 8 for a register operand, 16 for (HL), or 12 for BIT n,(HL), which doesn't write.
     /// See: https://gbdev.io/gb-opcodes/optables/ for details explaining m-cycles and t-states.
     /// action_taken is true if a conditional operation was undertaken that takes more CPU time to
     /// perform. There is always one cycle count, sometimes two.
@@ -159,4 +206,48 @@ mod tests {
         let cycles = opcodes.get_cycles(0x00, false, false);
         assert_eq!(cycles, 4);
     }
+
+    #[test]
+    fn test_disassemble() {
+        let opcodes = OpCodes::from_path("data/opcodes.json").unwrap();
+        let disassemble = |bytes| opcodes.disassemble(bytes);
+
+        assert_eq!(disassemble([0x00, 0xFF, 0xFF]), (String::from("NOP"), 1));
+        assert_eq!(
+            disassemble([0x3E, 0x42, 0xFF]),
+            (String::from("LD A,0x42"), 2)
+        );
+        assert_eq!(
+            disassemble([0x21, 0x00, 0xC0]),
+            (String::from("LD HL,0xc000"), 3)
+        );
+        assert_eq!(
+            disassemble([0xFA, 0x34, 0x12]),
+            (String::from("LD A,(0x1234)"), 3)
+        );
+        assert_eq!(
+            disassemble([0xE0, 0x44, 0xFF]),
+            (String::from("LDH (0xff44),A"), 2)
+        );
+        assert_eq!(
+            disassemble([0x22, 0xFF, 0xFF]),
+            (String::from("LD (HL+),A"), 1)
+        );
+        assert_eq!(
+            disassemble([0x20, 0xFE, 0xFF]),
+            (String::from("JR NZ,-2"), 2)
+        );
+        assert_eq!(
+            disassemble([0xF8, 0x05, 0xFF]),
+            (String::from("LD HL,SP+5"), 2)
+        );
+        assert_eq!(
+            disassemble([0xCB, 0x7C, 0xFF]),
+            (String::from("BIT 7,H"), 2)
+        );
+        assert_eq!(
+            disassemble([0xD3, 0xFF, 0xFF]),
+            (String::from("ILLEGAL_D3"), 1)
+        );
+    }
 }
@@ -0,0 +1,17 @@
+use super::mmu::MmuState;
+use super::systems::{ApuState, GamepadState, PpuState};
+use serde::{Deserialize, Serialize};
+
+/// Everything about the guest that changes as it runs: the CPU registers, all of RAM, and the
+/// registers and internal state of every peripheral. Restoring one puts the guest back exactly
+/// where it was, which is what save states, rewinding and diffing traces need.
+///
+/// The CPU and timer systems keep all of their state in the MMU, so they have nothing of their own
+/// to add. Cartridge ROM isn't included either, so a state can only be restored with the same ROM.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuestState {
+    pub mmu: MmuState,
+    pub ppu: PpuState,
+    pub apu: ApuState,
+    pub gamepad: GamepadState,
+}
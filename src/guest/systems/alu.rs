@@ -84,17 +84,15 @@ pub fn add(mmu: &mut MMU, value: u8) {
 }
 
 /// Add 16-bit value to HL.
-/// The half-carry is for overflow out of bit 11. That's calculated by isolating bit 11 with a mask
-/// then seeing if the sum is greater than 0x7FF (ie. there's a value in any bit above 11).
-/// The carry is the same concept but for bit 15. Instead of causing an overflow, we just check to
-/// see if there would be one.
+/// The half-carry is for overflow out of bit 11. That's calculated by isolating bits 0-11 with a
+/// mask then seeing if the sum is greater than 0xFFF (ie. there's a value in any bit above 11).
+/// The carry is the same concept but for bit 15.
 /// Flags: [- 0 H C]
 pub fn add_hl_16(mmu: &mut MMU, value: u16) {
     let hl = mmu.hl();
     let (new_hl, overflow) = hl.overflowing_add(value);
     mmu.set_flag_n(false);
-    mmu.set_flag_h((hl & 0x07FF) + (value & 0x07FF) > 0x07FF);
-    mmu.set_flag_c(hl > 0xFFFF - value);
+    mmu.set_flag_h((hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF);
     mmu.set_flag_c(overflow);
     mmu.set_hl(new_hl);
 }
@@ -115,8 +113,17 @@ pub fn sub(mmu: &mut MMU, value: u8) {
 }
 
 /// Subtract value and the carry bit from A.
+/// Same idea as alu::sub, but the carry is part of the operand. It can't be added to value first:
+/// 0xFF plus a carry would overflow, and the carry can be what causes a (half) borrow.
+/// Flags: [Z 1 H C]
 pub fn sbc(mmu: &mut MMU, value: u8) {
-    sub(mmu, value + mmu.flag_c() as u8);
+    let carry = mmu.flag_c() as u8;
+    let new_a = mmu.a.wrapping_sub(value).wrapping_sub(carry);
+    mmu.set_flag_z(new_a == 0);
+    mmu.set_flag_n(true);
+    mmu.set_flag_h((mmu.a & 0x0F) < (value & 0x0F) + carry);
+    mmu.set_flag_c((mmu.a as u16) < value as u16 + carry as u16);
+    mmu.a = new_a;
 }
 
 /// Rotate bits left through carry.
@@ -146,13 +153,13 @@ pub fn rlc(mmu: &mut MMU, value: u8) -> u8 {
 }
 
 /// Rotate bits right through carry.
-/// This means that we shift right, and the MSB becomes the LSB. Except "through carry" means
-/// We act as if the carry is part of that ring: MSB becomes carry, old carry becomes LSB.
+/// This means that we shift right, and the LSB becomes the MSB. Except "through carry" means
+/// We act as if the carry is part of that ring: LSB becomes carry, old carry becomes MSB.
 /// Flags: [Z 0 0 C]
 // Note: The mnemonic is weird.  RR is through carry. RRC is not.
 pub fn rr(mmu: &mut MMU, value: u8) -> u8 {
     let has_carry = value & 0x01 == 0x01;
-    let new_value = value >> 1 | value & if mmu.flag_c() { 0x80 } else { 0x00 };
+    let new_value = value >> 1 | if mmu.flag_c() { 0x80 } else { 0x00 };
     mmu.set_flag_z(new_value == 0);
     mmu.set_flag_h(false);
     mmu.set_flag_n(false);
@@ -168,7 +175,7 @@ pub fn rrc(mmu: &mut MMU, value: u8) -> u8 {
     mmu.set_flag_z(new_value == 0);
     mmu.set_flag_h(false);
     mmu.set_flag_n(false);
-    mmu.set_flag_c(has_carry); // If the value's MSB is 1, there's a carry.
+    mmu.set_flag_c(has_carry); // If the value's LSB is 1, there's a carry.
     new_value
 }
 
@@ -259,12 +266,12 @@ pub fn srl(mmu: &mut MMU, value: u8) -> u8 {
 /// Almost the same as alu::add. Duplicated it here to keep both simple for learning purposes.
 /// Flags: [Z 0 H C]
 pub fn adc(mmu: &mut MMU, value: u8) {
-    let value_with_carry = value.wrapping_add(mmu.flag_c() as u8);
-    let (new_a, overflow) = mmu.a.overflowing_add(value_with_carry);
+    let carry = mmu.flag_c() as u8;
+    let new_a = mmu.a.wrapping_add(value).wrapping_add(carry);
     mmu.set_flag_z(new_a == 0);
     mmu.set_flag_n(false);
-    mmu.set_flag_h((mmu.a & 0xF) + (value & 0xF) > 0xF);
-    mmu.set_flag_c(overflow);
+    mmu.set_flag_h((mmu.a & 0xF) + (value & 0xF) + carry > 0xF);
+    mmu.set_flag_c(mmu.a as u16 + value as u16 + carry as u16 > 0xFF);
     mmu.a = new_a;
 }
 
@@ -278,7 +285,6 @@ pub fn adc(mmu: &mut MMU, value: u8) {
 /// concept of this operation.
 ///
 /// Flags: [Z - 0 C]
-/// TODO: tests
 pub fn daa(mmu: &mut MMU) {
     let subtract = mmu.flag_n();
     let carry = mmu.flag_c();
@@ -552,6 +558,82 @@ mod tests {
         mmu.a = 0xFF;
         adc(mmu, 0xFF);
         assert_eq!(mmu.a, 0xFF);
-        assert_flags!(mmu, false, false, true, false);
+        assert_flags!(mmu, false, false, true, true); // 0xFF + 0xFF + 1 = 0x1FF.
+    }
+
+    /// DAA after a subtraction only ever subtracts, never changes C, and always clears H.
+    #[test]
+    fn test_daa_subtract() {
+        let mmu = &mut MMU::new(None, false);
+
+        // 42 - 15 = 27. Half-borrow from the low digit, no borrow overall.
+        mmu.a = 0x42;
+        sub(mmu, 0x15);
+        daa(mmu);
+        assert_eq!(mmu.a, 0x27);
+        assert_flags!(mmu, false, true, false, false);
+
+        // 15 - 42 = -27, which wraps to 73 with a borrow. C must stay set.
+        mmu.a = 0x15;
+        sub(mmu, 0x42);
+        daa(mmu);
+        assert_eq!(mmu.a, 0x73);
+        assert_flags!(mmu, false, true, false, true);
+
+        // 00 - 01 = 99 with both a half-borrow and a borrow.
+        mmu.a = 0x00;
+        sub(mmu, 0x01);
+        daa(mmu);
+        assert_eq!(mmu.a, 0x99);
+        assert_flags!(mmu, false, true, false, true);
+
+        // 10 - 10 = 00.
+        mmu.a = 0x10;
+        sub(mmu, 0x10);
+        daa(mmu);
+        assert_eq!(mmu.a, 0x00);
+        assert_flags!(mmu, true, true, false, false);
+
+        // A borrow flagged going in is adjusted for and left set, not recomputed from the result.
+        mmu.a = 0x60;
+        mmu.set_flag_n(true);
+        mmu.set_flag_h(false);
+        mmu.set_flag_c(true);
+        daa(mmu);
+        assert_eq!(mmu.a, 0x00);
+        assert_flags!(mmu, true, true, false, true);
+    }
+
+    /// ADD or SUB of two BCD numbers, then DAA, gives the BCD result with C as the decimal carry
+    /// or borrow.
+    #[test]
+    fn test_daa_table() {
+        let mmu = &mut MMU::new(None, false);
+
+        // (subtract, a, value, result, carry)
+        let table = [
+            (false, 0x15, 0x27, 0x42, false), // Half-carry only.
+            (false, 0x19, 0x01, 0x20, false), // Low digit past 9 without a half-carry.
+            (false, 0x45, 0x55, 0x00, true),  // Exactly 100.
+            (false, 0x50, 0x60, 0x10, true),  // High digit past 9 without a carry.
+            (false, 0x99, 0x99, 0x98, true),  // Both carries.
+            (false, 0x00, 0x00, 0x00, false),
+            (true, 0x42, 0x15, 0x27, false),
+            (true, 0x15, 0x42, 0x73, true),
+            (true, 0x00, 0x01, 0x99, true),
+            (true, 0x50, 0x50, 0x00, false),
+        ];
+
+        for &(subtract, a, value, result, carry) in table.iter() {
+            mmu.a = a;
+            if subtract {
+                sub(mmu, value);
+            } else {
+                add(mmu, value);
+            }
+            daa(mmu);
+            assert_eq!(mmu.a, result, "{:02x} {:02x} {}", a, value, subtract);
+            assert_flags!(mmu, result == 0, subtract, false, carry);
+        }
     }
 }
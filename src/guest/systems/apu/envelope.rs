@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+/// A channel's volume envelope, as set up by its NRx2 register: bits 7-4 are the starting volume,
+/// bit 3 is the direction (set to get louder) and bits 2-0 are how many envelope steps (64Hz)
+/// between each change of volume. A period of 0 holds the volume where it is.
+/// See: https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Volume_Envelope
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    volume: u8,     // The current volume, 0-15.
+    increase: bool, // Latched from NRx2 on trigger, like the period.
+    period: u8,     // Envelope steps between changes of volume.
+    timer: u8,      // Envelope steps until the volume next changes.
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            volume: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    /// Start over from NRx2's settings, as happens when the channel is triggered.
+    pub fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.increase = nrx2 & 0x08 != 0;
+        self.period = nrx2 & 0x07;
+        self.timer = self.period;
+    }
+
+    /// One envelope step, from the frame sequencer. Once the volume reaches 0 or 15 it stays.
+    pub fn step(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_directions() {
+        let mut envelope = Envelope::new();
+
+        // Getting louder every step, up to 15.
+        envelope.trigger(0xD9);
+        assert_eq!(envelope.volume(), 13);
+        envelope.step();
+        envelope.step();
+        assert_eq!(envelope.volume(), 15);
+        envelope.step();
+        assert_eq!(envelope.volume(), 15);
+
+        // A period of 0 doesn't change the volume.
+        envelope.trigger(0x70);
+        for _ in 0..20 {
+            envelope.step();
+        }
+        assert_eq!(envelope.volume(), 7);
+    }
+}
@@ -1,57 +1,119 @@
 use std::collections::VecDeque;
+mod envelope;
 mod square;
 mod wave;
 use super::MMU;
-use crate::emulator::{APU_DIVISOR, CPU_FREQ};
+use crate::emulator::APU_DIVISOR;
+use envelope::Envelope;
+use serde::{Deserialize, Serialize};
 use square::SquareVoice;
 use wave::WaveVoice;
 
-// The number of CPU cycles (4MHz) per frame sequencer frame. Each frame is 64hz.
-// Frame sequencer runs at 512hz. There's 1024 CPU cycles per frame. 8 frames per cycle.
-const CYCLES_PER_FRAME: usize = (CPU_FREQ / 512 / 8) * 4;
+/// Which samples the APU loses when its output buffer is full, ie. the host isn't draining
+/// it fast enough (eg. while nothing is being presented).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AudioOverflow {
+    DropOldest, // Make room by discarding the oldest samples, so the latest audio is kept.
+    Block,      // Hold off adding samples until the host has made room.
+}
 
 pub struct APU {
-    clock: usize,
     square1: SquareVoice,
     square2: SquareVoice,
     wave: WaveVoice,
+    noise_envelope: Envelope, // There's no noise channel yet, but its envelope is kept up to date.
     frame_sequence: usize,
+    mute_boot_rom: bool, // Output silence while the boot ROM is mapped, ie. no boot chime.
+    buffer_cap: usize,   // Most samples `output_buffer` holds. Past that, `overflow` applies.
+    overflow: AudioOverflow,
     pub output_buffer: VecDeque<[f32; 2]>,
 }
 
+/// The APU's part of a `GuestState`. Samples waiting in the output buffer aren't included: they
+/// belong to the host's audio device.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApuState {
+    pub square1: SquareVoice,
+    pub square2: SquareVoice,
+    pub wave: WaveVoice,
+    pub noise_envelope: Envelope,
+    pub frame_sequence: usize,
+}
+
 impl APU {
     pub fn new() -> Self {
         Self {
             square1: SquareVoice::new(),
             square2: SquareVoice::new(),
             wave: WaveVoice::new(),
+            noise_envelope: Envelope::new(),
             frame_sequence: 0,
-            clock: 0,
+            mute_boot_rom: false,
+            buffer_cap: usize::MAX,
+            overflow: AudioOverflow::DropOldest,
             output_buffer: VecDeque::new(),
         }
     }
 
+    /// Silence the boot chime: output nothing until the boot ROM has been unmapped. The boot ROM
+    /// still runs (and plays the chime) as normal, it just isn't heard.
+    pub fn enable_boot_rom_mute(&mut self) {
+        self.mute_boot_rom = true;
+    }
+
+    /// Stop the output buffer growing without limit when the host doesn't drain it, eg. while
+    /// paused. Once it holds `cap` samples, `overflow` decides which samples are lost.
+    pub fn set_buffer_cap(&mut self, cap: usize, overflow: AudioOverflow) {
+        self.buffer_cap = cap;
+        self.overflow = overflow;
+    }
+
+    pub fn snapshot(&self) -> ApuState {
+        ApuState {
+            square1: self.square1.clone(),
+            square2: self.square2.clone(),
+            wave: self.wave.clone(),
+            noise_envelope: self.noise_envelope.clone(),
+            frame_sequence: self.frame_sequence,
+        }
+    }
+
+    pub fn restore(&mut self, state: &ApuState) {
+        self.square1 = state.square1.clone();
+        self.square2 = state.square2.clone();
+        self.wave = state.wave.clone();
+        self.noise_envelope = state.noise_envelope.clone();
+        self.frame_sequence = state.frame_sequence;
+    }
+
     pub fn step(&mut self, mmu: &mut MMU, cycles: u8) {
         // TODO: if mmu.apu.enabled is false, don't do anything.
 
-        self.clock += cycles as usize;
+        // A trigger since the last step starts the duty cycle and the envelope over.
+        if mmu.apu.square1_initialize {
+            mmu.apu.square1_initialize = false;
+            self.square1.restart(mmu.apu.nr12);
+        }
+        if mmu.apu.square2_initialize {
+            mmu.apu.square2_initialize = false;
+            self.square2.restart(mmu.apu.nr22);
+        }
+        if mmu.apu.noise_initialize {
+            mmu.apu.noise_initialize = false;
+            self.noise_envelope.trigger(mmu.apu.nr42);
+        }
 
-        // If one "frame" worth of cycles have passed, advance the frame sequencer.
-        if self.clock >= CYCLES_PER_FRAME {
-            self.clock -= CYCLES_PER_FRAME;
+        // The frame sequencer runs at 512hz, clocked by the timer's DIV (see `TimerRegisters`).
+        // Advance it once for every step the timer has signalled.
+        while mmu.timer.frame_sequencer_ticks > 0 {
+            mmu.timer.frame_sequencer_ticks -= 1;
 
             self.frame_sequence = (self.frame_sequence + 1) % 8;
 
             // Decrement length counters?
             if [0, 2, 4, 6].contains(&self.frame_sequence) {
-                // TODO: this doesn't seem right.
-                // if mmu.apu.square1_length > 0 {
-                //     mmu.apu.square1_length -= 1;
-                // }
-
-                if mmu.apu.square2_length > 0 && mmu.apu.square2_length_enabled {
-                    mmu.apu.square2_length -= 1;
-                }
+                mmu.apu.clock_lengths();
             }
 
             // Decrement sweep?
@@ -59,8 +121,12 @@ impl APU {
                 // TODO
             }
 
-            // Decrement volume envelope?
-            if self.frame_sequence == 7 {}
+            // Step the volume envelopes.
+            if self.frame_sequence == 7 {
+                self.square1.envelope.step();
+                self.square2.envelope.step();
+                self.noise_envelope.step();
+            }
         }
 
         // Run at 1MHz for performance reasons. This means that every tick is 4 cycles.
@@ -69,43 +135,230 @@ impl APU {
         // sample that's all one value, when in reality it would have been a mix between multiple
         // values. This affects some voices more than others.
         for _ in 0..(cycles as usize / APU_DIVISOR) {
-            // let wave_sample = self.wave.tick(mmu);
-            // let square1_sample = self.square1.tick(
-            //     mmu.apu.square1_length,
-            //     mmu.apu.square1_frequency,
-            //     mmu.apu.square1_wave_duty,
-            // );
+            let square1_sample = self.square1.tick(
+                mmu.apu.square1_playing,
+                mmu.apu.square1_frequency,
+                mmu.apu.square1_wave_duty,
+            );
+            let square2_sample = self.square2.tick(
+                mmu.apu.square2_playing,
+                mmu.apu.square2_frequency,
+                mmu.apu.square2_wave_duty,
+            );
 
-            // The above sequencer generates a step or not. WHen it does, pass the number Some(0..7)
-            // All other ticks,  pass None()
-            // This means the sequencer state machine needs to be advanced inside this loop.
+            // The wave and noise channels aren't mixed in yet.
+            let sample = (square1_sample + square2_sample) / 2.0;
+            self.output(mmu, [sample, sample]);
+        }
+    }
 
-            // let square2_sample = self.square2.tick(mmu, Some(sequencer_step));
+    /// Append a mixed sample to the output buffer, or silence if the output is muted.
+    fn output(&mut self, mmu: &MMU, sample: [f32; 2]) {
+        if self.mute_boot_rom && mmu.is_boot_rom_mapped() {
+            self.push_sample([0.0, 0.0]);
+        } else {
+            self.push_sample(sample);
+        }
+    }
 
-            // let sample = (wave_sample + square1_sample + square2_sample) / 3.0;
+    /// Append a sample, unless the buffer is full and it's the new samples that are dropped.
+    fn push_sample(&mut self, sample: [f32; 2]) {
+        if self.output_buffer.len() >= self.buffer_cap {
+            match self.overflow {
+                AudioOverflow::DropOldest => {
+                    self.output_buffer.pop_front();
+                }
+                AudioOverflow::Block => return,
+            }
+        }
+        self.output_buffer.push_back(sample);
+    }
+}
 
-            // TODO: combine samples
-            // TODO: append samples to the output.
+#[cfg(test)]
+mod tests {
+    use super::super::Timer;
+    use super::*;
+    use std::env;
+    use std::fs;
 
-            // self.output_buffer
-            //     .push_back([square2_sample, square2_sample]);
+    /// Run the timer and APU together for some cycles, 4 at a time.
+    fn run(mmu: &mut MMU, timer: &mut Timer, apu: &mut APU, cycles: usize) {
+        for _ in 0..cycles / 4 {
+            timer.step(mmu, 4);
+            apu.step(mmu, 4);
         }
+    }
+
+    #[test]
+    fn test_frame_sequencer_follows_div() {
+        let mut mmu = MMU::new(None, false);
+        let mut timer = Timer::new();
+        let mut apu = APU::new();
+        mmu.wb(0xFF04, 0);
+
+        // Steps every 8192 cycles, when DIV bit 4 falls.
+        run(&mut mmu, &mut timer, &mut apu, 8188);
+        assert_eq!(apu.frame_sequence, 0);
+        run(&mut mmu, &mut timer, &mut apu, 4);
+        assert_eq!(apu.frame_sequence, 1);
 
-        // // If 1 audio sample worth of cycles has passed, let's build a sample.
-        // if self.clock >= CYCLES_PER_SAMPLE {
-        //     // let sample_1 = self.tone.tick()
-        //     let wave_sample = self.wave.build_sample(mmu, cycles);
+        // Halfway to the next step, DIV bit 4 is set. Writing DIV clears it: an early step.
+        run(&mut mmu, &mut timer, &mut apu, 4096);
+        assert_eq!(mmu.rb(0xFF04) & 0x10, 0x10);
+        mmu.wb(0xFF04, 0);
+        run(&mut mmu, &mut timer, &mut apu, 4);
+        assert_eq!(apu.frame_sequence, 2);
 
-        //     // Consume a sample's worth off the clock.
-        //     self.clock -= CYCLES_PER_SAMPLE
-        // }
+        // The step after that is a whole period after the write, not when it would have been.
+        run(&mut mmu, &mut timer, &mut apu, 4096);
+        assert_eq!(apu.frame_sequence, 2);
+        run(&mut mmu, &mut timer, &mut apu, 4088);
+        assert_eq!(apu.frame_sequence, 2);
+        run(&mut mmu, &mut timer, &mut apu, 4);
+        assert_eq!(apu.frame_sequence, 3);
+
+        // Writing DIV while bit 4 is clear doesn't step.
+        run(&mut mmu, &mut timer, &mut apu, 100);
+        mmu.wb(0xFF04, 0);
+        run(&mut mmu, &mut timer, &mut apu, 4);
+        assert_eq!(apu.frame_sequence, 3);
     }
-}
 
-// I need to advance the voices at 1 MHz (so 1/4 of the cycles coming in)
-// Generate samples at 1MHz and then downsample them (just pop off an amount and make an average)
-//
+    #[test]
+    fn test_boot_rom_mute() {
+        let boot_rom_path = env::temp_dir().join("gameboy_apu_mute_boot.bin");
+        fs::write(&boot_rom_path, &[0; 0x100][..]).unwrap();
+        let mut mmu =
+            MMU::with_boot_rom_path(None, true, boot_rom_path.to_str().unwrap(), None).unwrap();
+        let mut apu = APU::new();
+
+        // Without the option the chime is heard.
+        apu.output(&mmu, [0.5, 0.5]);
+        assert_eq!(apu.output_buffer.pop_front(), Some([0.5, 0.5]));
+
+        apu.enable_boot_rom_mute();
+        apu.output(&mmu, [0.5, 0.5]);
+        assert_eq!(apu.output_buffer.pop_front(), Some([0.0, 0.0]));
+
+        // Once the boot ROM is unmapped the game is heard.
+        mmu.wb(0xFF50, 1);
+        apu.output(&mmu, [0.5, 0.5]);
+        assert_eq!(apu.output_buffer.pop_front(), Some([0.5, 0.5]));
+    }
+
+    #[test]
+    fn test_buffer_cap() {
+        let mmu = MMU::new(None, false);
+        let mut apu = APU::new();
+        apu.set_buffer_cap(3, AudioOverflow::DropOldest);
+        for n in 0..5 {
+            apu.output(&mmu, [n as f32, n as f32]);
+        }
+        assert_eq!(apu.output_buffer.len(), 3);
+        assert_eq!(apu.output_buffer.front(), Some(&[2.0, 2.0]));
+        assert_eq!(apu.output_buffer.back(), Some(&[4.0, 4.0]));
+
+        // Blocking keeps what's there until the host drains it.
+        apu.output_buffer.clear();
+        apu.set_buffer_cap(3, AudioOverflow::Block);
+        for n in 0..5 {
+            apu.output(&mmu, [n as f32, n as f32]);
+        }
+        assert_eq!(apu.output_buffer.len(), 3);
+        assert_eq!(apu.output_buffer.back(), Some(&[2.0, 2.0]));
+        apu.output_buffer.pop_front();
+        apu.output(&mmu, [5.0, 5.0]);
+        assert_eq!(apu.output_buffer.back(), Some(&[5.0, 5.0]));
+    }
+
+    #[test]
+    fn test_square2_waveform() {
+        let mut mmu = MMU::new(None, false);
+        let mut apu = APU::new();
+
+        // Skipping the boot ROM leaves square 1 playing, as the boot chime does. Turn it off.
+        mmu.wb(0xFF12, 0x00);
+
+        // 50% duty, full volume, and a frequency of 0x700: 1024 cycles for each of the 8 steps.
+        mmu.wb(0xFF16, 0x80);
+        mmu.wb(0xFF17, 0xF0);
+        mmu.wb(0xFF18, 0x00);
+        mmu.wb(0xFF19, 0x87);
+        for _ in 0..8192 / 4 {
+            apu.step(&mut mmu, 4);
+        }
 
-//  let period = 2 * (2048 - freq_val as i32);
-// the period is how many hz it takes until  we advance things (like going to the next wave sample)
-// Our implementation will be 1 tick per call (at 1MHz) so it's literall how many times we call tick()
+        // One sample per tick: 256 for each step of the duty cycle. With square 1 silent the mix
+        // is half of square 2.
+        let samples: Vec<f32> = apu.output_buffer.iter().map(|s| s[0]).collect();
+        assert_eq!(samples.len(), 2048);
+        assert!(samples[0..256].iter().all(|&s| s == 0.5));
+        assert!(samples[256..1280].iter().all(|&s| s == -0.5));
+        assert!(samples[1280..2048].iter().all(|&s| s == 0.5));
+        assert!(apu.output_buffer.iter().all(|s| s[0] == s[1]));
+    }
+
+    #[test]
+    fn test_square_length() {
+        let mut mmu = MMU::new(None, false);
+        let mut apu = APU::new();
+        mmu.wb(0xFF12, 0x00);
+
+        // A length of 2 (64 - 62) steps, enabled.
+        mmu.wb(0xFF17, 0xF0);
+        mmu.wb(0xFF16, 0x80 | 62);
+        mmu.wb(0xFF19, 0xC7);
+        assert!(mmu.apu.square2_playing);
+
+        // The sequencer counts lengths down on every other step.
+        for _ in 0..3 {
+            mmu.timer.frame_sequencer_ticks += 1;
+            apu.step(&mut mmu, 4);
+            assert!(mmu.apu.square2_playing);
+        }
+        mmu.timer.frame_sequencer_ticks += 1;
+        apu.step(&mut mmu, 4);
+        assert!(!mmu.apu.square2_playing);
+        assert_eq!(apu.output_buffer.back(), Some(&[0.0, 0.0]));
+
+        // Triggering again reloads a full length. Turning the DAC off stops it.
+        mmu.wb(0xFF19, 0xC7);
+        assert_eq!(mmu.apu.square2_length, 64);
+        assert!(mmu.apu.square2_playing);
+        mmu.wb(0xFF17, 0x00);
+        assert!(!mmu.apu.square2_playing);
+    }
+
+    #[test]
+    fn test_envelope_cadence() {
+        let mut mmu = MMU::new(None, false);
+        let mut apu = APU::new();
+        mmu.wb(0xFF12, 0x00);
+
+        // Start at volume 10 and get quieter every 2 envelope steps. The envelope steps on
+        // sequencer step 7, so every 8 sequencer steps: every 16 for the volume.
+        mmu.wb(0xFF16, 0x80);
+        mmu.wb(0xFF17, 0xA2);
+        mmu.wb(0xFF19, 0x87);
+        let mut volumes = Vec::new();
+        for _ in 0..48 {
+            mmu.timer.frame_sequencer_ticks += 1;
+            apu.step(&mut mmu, 4);
+            volumes.push(apu.square2.envelope.volume());
+        }
+        assert!(volumes[..14].iter().all(|&v| v == 10));
+        assert!(volumes[14..30].iter().all(|&v| v == 9));
+        assert!(volumes[30..46].iter().all(|&v| v == 8));
+        assert_eq!(volumes[46], 7);
+
+        // The output is scaled by the volume: 7/15 of full, halved by the mix.
+        let sample = apu.output_buffer.back().unwrap()[0];
+        assert!((sample - 7.0 / 15.0 / 2.0).abs() < 1e-6);
+
+        // Triggering again restarts it from NR22.
+        mmu.wb(0xFF19, 0x87);
+        apu.step(&mut mmu, 4);
+        assert_eq!(apu.square2.envelope.volume(), 10);
+    }
+}
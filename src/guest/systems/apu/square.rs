@@ -1,4 +1,6 @@
+use super::envelope::Envelope;
 use crate::emulator::APU_DIVISOR;
+use serde::{Deserialize, Serialize};
 
 // See: https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Square_Wave
 const DUTY_CYCLES: [[i32; 8]; 4] = [
@@ -8,10 +10,11 @@ const DUTY_CYCLES: [[i32; 8]; 4] = [
     [0, 1, 1, 1, 1, 1, 1, 1], // 75%
 ];
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SquareVoice {
     clock: usize,      // Track where we are in playing the current phase of the duty_cycle.
     duty_phase: usize, // Track which of the 8 steps in the current duty cycle we're playing.
-    volume: usize,
+    pub envelope: Envelope,
 }
 
 impl SquareVoice {
@@ -19,27 +22,21 @@ impl SquareVoice {
         Self {
             clock: 0,
             duty_phase: 0,
-            volume: 0,
+            envelope: Envelope::new(),
         }
     }
 
-    pub fn tick(
-        &mut self,
-        length: u8,
-        length_enabled: bool,
-        frequency: u16,
-        wave_duty: u8,
-        reset_clock: bool,
-        adjust_volume: usize,
-    ) -> f32 {
-        if reset_clock {
-            self.clock = 0;
-            self.duty_phase = 0;
-        }
-
-        self.volume = (self.volume + adjust_volume).clamp(0, 15);
+    /// Start the duty cycle and the envelope over, as happens when the channel is triggered.
+    pub fn restart(&mut self, nrx2: u8) {
+        self.clock = 0;
+        self.duty_phase = 0;
+        self.envelope.trigger(nrx2);
+    }
 
-        if length == 0 {
+    /// Advance by one APU tick and return the sample: between -1.0 and 1.0 while playing, scaled
+    /// by the envelope's volume, else silence.
+    pub fn tick(&mut self, playing: bool, frequency: u16, wave_duty: u8) -> f32 {
+        if !playing {
             return 0.0;
         }
 
@@ -58,6 +55,6 @@ impl SquareVoice {
         let duty_cycle = DUTY_CYCLES[wave_duty as usize];
         let duty_sample = duty_cycle[self.duty_phase];
 
-        duty_sample as f32 * 2.0 - 1.0
+        (duty_sample as f32 * 2.0 - 1.0) * self.envelope.volume() as f32 / 15.0
     }
 }
@@ -1,8 +1,10 @@
 use crate::{emulator::APU_DIVISOR, guest::MMU};
+use serde::{Deserialize, Serialize};
 
 // FF1C (NR32) sets audio volume at 0, 100%, 50%, 25% given the value of bits 6 and 5.
 const OUTPUT_VOLUME: [f32; 4] = [0.0, 1.0, 0.5, 0.25];
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WaveVoice {
     clock: usize,        // Track where we are in playing the current wave sample.
     sample_index: usize, // Current sample (0-31) being played.
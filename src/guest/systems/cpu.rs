@@ -4,8 +4,17 @@ use super::super::opcodes::OpCodes;
 
 use super::alu;
 use super::MMU;
+
+const OPCODES_PATH: &str = "data/opcodes.json";
+
 pub struct CPU {
     opcodes: OpCodes,
+    strict_opcodes: bool, // If true, panic on an unimplemented opcode. Otherwise skip it.
+    pause_on_unknown_opcode: bool, // Instead, pause and dump the CPU state. Overrides strict.
+    paused: bool,         // Hit an unimplemented opcode while pausing on them.
+    opcode_histogram: Option<Box<[u64; 256]>>, // Execution count per opcode, if profiling.
+    instruction_count: u64, // Total opcodes executed.
+    serviced_interrupt: Option<u16>, // The vector jumped to by the last step, if any.
 }
 
 impl CPU {
@@ -22,27 +31,142 @@ impl CPU {
     /// Begins at 0x0 and runs through the bootrom. Once the bootrom is complete, it should
     /// be at 0x100. Some emulators ignore the bootrom, pre-initialize the emulator, and begin
     /// at 0x100. We don't take that shortcut, as running the bootrom is a great test.
-    pub fn new() -> Self {
-        Self {
-            opcodes: OpCodes::from_path("data/opcodes.json").unwrap(),
+    ///
+    /// Opcode metadata (cycle counts, mnemonics) is read from `data/opcodes.json`, relative to the
+    /// working directory. An error describing the problem is returned if it can't be loaded.
+    pub fn new(strict_opcodes: bool) -> Result<Self, String> {
+        Self::with_opcodes_path(OPCODES_PATH, strict_opcodes)
+    }
+
+    /// Initialise the CPU, reading opcode metadata from a specific file.
+    pub fn with_opcodes_path(path: &str, strict_opcodes: bool) -> Result<Self, String> {
+        let opcodes = OpCodes::from_path(path).map_err(|e| {
+            format!(
+                "Could not load opcode metadata from {}: {}. Is the emulator being run from the \
+                 repository root?",
+                path, e
+            )
+        })?;
+
+        Ok(Self {
+            opcodes,
+            strict_opcodes,
+            pause_on_unknown_opcode: false,
+            paused: false,
+            opcode_histogram: None,
+            instruction_count: 0,
+            serviced_interrupt: None,
+        })
+    }
+
+    /// Start counting how many times each opcode is executed. Until this is called, nothing is
+    /// counted and the only cost per instruction is checking that the histogram is `None`.
+    pub fn enable_opcode_histogram(&mut self) {
+        self.opcode_histogram = Some(Box::new([0; 256]));
+    }
+
+    /// Execution count for each opcode, indexed by opcode. All CB-prefixed instructions are counted
+    /// under 0xCB. All zeroes if profiling was never enabled.
+    pub fn opcode_histogram(&self) -> [u64; 256] {
+        match &self.opcode_histogram {
+            Some(histogram) => **histogram,
+            None => [0; 256],
+        }
+    }
+
+    /// Rather than panicking or skipping, pause on an unimplemented opcode and dump the state of
+    /// the CPU to stderr. It's up to the emulator to stop running while `is_paused()`.
+    pub fn enable_pause_on_unknown_opcode(&mut self) {
+        self.pause_on_unknown_opcode = true;
+    }
+
+    /// The interrupt vector (0x0040-0x0060) the last `step` jumped to, or `None` if it didn't
+    /// service an interrupt.
+    pub fn serviced_interrupt(&self) -> Option<u16> {
+        self.serviced_interrupt
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause, eg. to single-step after pausing on an unimplemented opcode.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Carry on after pausing. The unimplemented opcode is skipped.
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Describe the CPU's state for debugging: the next few instructions from an address (marking
+    /// the first), followed by the registers.
+    pub fn dump_context(&self, mmu: &MMU, address: u16) -> String {
+        let mut lines = Vec::new();
+        let mut next_address = address;
+
+        for n in 0..5 {
+            let (text, length) = self.disassemble(mmu, next_address);
+            let marker = if n == 0 { "=>" } else { "  " };
+            lines.push(format!("{} {:#06x}: {}", marker, next_address, text));
+            next_address = next_address.wrapping_add(length as u16);
         }
+
+        lines.push(format!(
+            "AF={:#06x} BC={:#06x} DE={:#06x} HL={:#06x} SP={:#06x} PC={:#06x}",
+            mmu.af(),
+            mmu.bc(),
+            mmu.de(),
+            mmu.hl(),
+            mmu.sp,
+            mmu.pc
+        ));
+        lines.join("\n")
+    }
+
+    /// Disassemble the instruction at an address without executing it. Return the instruction as
+    /// text and its length in bytes.
+    pub fn disassemble(&self, mmu: &MMU, address: u16) -> (String, u8) {
+        let bytes = [
+            mmu.rb(address),
+            mmu.rb(address.wrapping_add(1)),
+            mmu.rb(address.wrapping_add(2)),
+        ];
+        self.opcodes.disassemble(bytes)
+    }
+
+    /// How many instructions have been executed. A CB-prefixed instruction counts as one.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
     }
 
     /// Perform a single opcode step and return how many cycles that took.
-    /// Return the number of m-cycles required to perform the operation. This will be used for
-    /// regulating how fast the CPU is emulated at.
-    pub fn do_opcode(&self, mmu: &mut MMU) -> u8 {
+    /// Return the number of t-states required to perform the operation, including fetching any CB
+    /// prefix. This will be used for regulating how fast the CPU is emulated at.
+    pub fn do_opcode(&mut self, mmu: &mut MMU) -> u8 {
         let op_address = mmu.pc; // Hold onto operation address before mutating it, for debugging.
 
         let mut opcode = mmu.get_next_byte();
         let is_cbprefix = opcode == 0xCB;
 
+        // The halt bug: PC doesn't move past the byte following HALT, so it's read again.
+        if mmu.interrupts.halt_bug {
+            mmu.interrupts.halt_bug = false;
+            mmu.pc = mmu.pc.wrapping_sub(1);
+        }
+
+        self.instruction_count += 1;
+        if let Some(histogram) = &mut self.opcode_histogram {
+            histogram[opcode as usize] += 1;
+        }
+
         // If the byte is not the opcode but actually the prefix, get another byte.
         if is_cbprefix {
             opcode = mmu.get_next_byte();
         }
 
-        // The number of m-cycles required for this operation. This may be updated by an operation
+        // The number of t-states required for this operation. This may be updated by an operation
         // if a conditional branch was NOT performed that costs less. We assume the condition is not
         // met.
         let mut cycles = self.opcodes.get_cycles(opcode, is_cbprefix, false);
@@ -94,13 +218,19 @@ impl CPU {
                 0x09 => alu::add_hl_16(mmu, bc),
                 0x0A => mmu.a = mmu.rb(bc),
                 0x0B => mmu.set_bc(bc.wrapping_sub(1)),
-                0x0C => mmu.c += 1,
+                0x0C => mmu.c = alu::inc(mmu, c),
                 0x0D => mmu.c = alu::dec(mmu, c),
                 0x0E => mmu.c = mmu.get_next_byte(),
                 0x0F => {
                     mmu.a = rrc(mmu, mmu.a);
                     mmu.set_flag_z(false);
                 }
+                0x10 => {
+                    // STOP is followed by a padding byte, which is skipped.
+                    mmu.get_next_byte();
+                    mmu.interrupts.is_stopped = true;
+                    mmu.wb(0xFF04, 0);
+                }
                 0x11 => {
                     let d16 = mmu.get_next_word();
                     mmu.set_de(d16);
@@ -182,6 +312,7 @@ impl CPU {
                     let new_hl = hl.wrapping_sub(1);
                     mmu.set_hl(new_hl); // Decrement.
                 }
+                0x33 => mmu.sp = sp.wrapping_add(1),
                 0x34 => {
                     let value = alu::inc(mmu, mmu.rb(hl));
                     mmu.wb(hl, value);
@@ -244,11 +375,11 @@ impl CPU {
                 0x55 => mmu.d = l,
                 0x56 => mmu.d = mmu.rb(hl),
                 0x57 => mmu.d = a,
-                0x58 => mmu.a = b,
-                0x59 => mmu.a = c,
-                0x5A => mmu.a = d,
-                0x5B => mmu.a = e,
-                0x5C => mmu.a = h,
+                0x58 => mmu.e = b,
+                0x59 => mmu.e = c,
+                0x5A => mmu.e = d,
+                0x5B => mmu.e = e,
+                0x5C => mmu.e = h,
                 0x5D => mmu.e = l,
                 0x5E => mmu.e = mmu.rb(hl),
                 0x5F => mmu.e = a,
@@ -274,7 +405,7 @@ impl CPU {
                 0x73 => mmu.wb(hl, e),
                 0x74 => mmu.wb(hl, h),
                 0x75 => mmu.wb(hl, l),
-                0x76 => mmu.interrupts.is_halted = true,
+                0x76 => mmu.interrupts.halt(),
                 0x77 => mmu.wb(hl, a),
                 0x78 => mmu.a = b,
                 0x79 => mmu.a = c,
@@ -283,6 +414,7 @@ impl CPU {
                 0x7C => mmu.a = h,
                 0x7D => mmu.a = l,
                 0x7E => mmu.a = mmu.rb(hl),
+                0x7F => mmu.a = a,
                 0x80 => alu::add(mmu, b),
                 0x81 => alu::add(mmu, c),
                 0x82 => alu::add(mmu, d),
@@ -370,6 +502,7 @@ impl CPU {
                     if !mmu.flag_z() {
                         mmu.push_stack(mmu.pc);
                         mmu.pc = address;
+                        condition_met = true;
                     }
                 }
                 0xC5 => mmu.push_stack(bc),
@@ -377,6 +510,11 @@ impl CPU {
                     let value = mmu.get_next_byte();
                     alu::add(mmu, value);
                 }
+                // RST n: call one of the eight fixed vectors, encoded in bits 3-5 of the opcode.
+                0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                    mmu.push_stack(mmu.pc);
+                    mmu.pc = (opcode & 0x38) as u16;
+                }
                 0xC8 => {
                     if mmu.flag_z() {
                         mmu.pc = mmu.pop_stack();
@@ -396,6 +534,7 @@ impl CPU {
                     if mmu.flag_z() {
                         mmu.push_stack(mmu.pc);
                         mmu.pc = address;
+                        condition_met = true;
                     }
                 }
                 0xCD => {
@@ -429,6 +568,7 @@ impl CPU {
                     if !mmu.flag_c() {
                         mmu.push_stack(mmu.pc);
                         mmu.pc = address;
+                        condition_met = true;
                     }
                 }
                 0xD5 => mmu.push_stack(de),
@@ -453,6 +593,14 @@ impl CPU {
                         condition_met = true;
                     }
                 }
+                0xDC => {
+                    let address = mmu.get_next_word();
+                    if mmu.flag_c() {
+                        mmu.push_stack(mmu.pc);
+                        mmu.pc = address;
+                        condition_met = true;
+                    }
+                }
                 0xDE => {
                     let value = mmu.get_next_byte();
                     alu::sbc(mmu, value);
@@ -473,17 +621,13 @@ impl CPU {
                 }
                 0xE9 => mmu.pc = hl,
                 0xEA => {
-                    let d8 = mmu.get_next_word();
-                    mmu.wb(d8, a)
+                    let address = mmu.get_next_word();
+                    mmu.wb(address, a)
                 }
                 0xEE => {
                     let value = mmu.get_next_byte();
                     alu::xor(mmu, value);
                 }
-                0xEF => {
-                    mmu.push_stack(mmu.pc);
-                    mmu.pc = 0x0028;
-                }
                 0xF0 => {
                     let addr = 0xFF00 + (mmu.get_next_byte() as u16);
                     mmu.a = mmu.rb(addr);
@@ -522,7 +666,7 @@ impl CPU {
                     let d8 = mmu.get_next_byte();
                     alu::cp(mmu, d8)
                 }
-                _ => self.panic_opcode(opcode, is_cbprefix, op_address),
+                _ => self.unknown_opcode(mmu, opcode, is_cbprefix, op_address),
             }
         } else {
             match opcode {
@@ -558,6 +702,7 @@ impl CPU {
                     let value = alu::rl(mmu, mmu.rb(hl));
                     mmu.wb(hl, value);
                 }
+                0x17 => mmu.a = alu::rl(mmu, a),
                 0x18 => mmu.b = alu::rr(mmu, b),
                 0x19 => mmu.c = alu::rr(mmu, c),
                 0x1A => mmu.d = alu::rr(mmu, d),
@@ -574,7 +719,7 @@ impl CPU {
                 0x22 => mmu.d = alu::sla(mmu, d),
                 0x23 => mmu.e = alu::sla(mmu, e),
                 0x24 => mmu.h = alu::sla(mmu, h),
-                0x25 => mmu.h = alu::sla(mmu, l),
+                0x25 => mmu.l = alu::sla(mmu, l),
                 0x26 => {
                     let value = alu::sla(mmu, mmu.rb(hl));
                     mmu.wb(hl, value);
@@ -805,7 +950,6 @@ impl CPU {
                 0xFD => mmu.l = alu::set(7, l),
                 0xFE => mmu.wb(hl, alu::set(7, mmu.rb(hl))),
                 0xFF => mmu.a = alu::set(7, a),
-                _ => self.panic_opcode(opcode, is_cbprefix, op_address),
             }
         }
 
@@ -823,12 +967,18 @@ impl CPU {
     /// 1. Perform an opcode instruction.
     /// 2. Handle an interrupt, jumping to an interrupt address.
     /// 3. Do nothing because the CPU is halted.
-    pub fn step(&self, mmu: &mut MMU) -> u8 {
+    pub fn step(&mut self, mmu: &mut MMU) -> u8 {
         // If EI or DI was called, tick down the delay and possibly modify IME.
         mmu.interrupts.tick_ime_timer();
+        self.serviced_interrupt = None;
 
-        // Check LYC every step.
-        // mmu.check_lyc_interrupt(); // TODO: maybe put this back. It's in GPU now.
+        // Nothing at all runs while stopped, until a button press raises the joypad flag.
+        if mmu.interrupts.is_stopped {
+            if mmu.interrupts.intf & 0x10 == 0 {
+                return 4;
+            }
+            mmu.interrupts.is_stopped = false;
+        }
 
         // Try to handle an interrupt. If none was handled, try to do an opcode if not halted.
         match mmu.try_interrupt() {
@@ -839,18 +989,823 @@ impl CPU {
                     self.do_opcode(mmu)
                 }
             }
-            n => n,
+            n => {
+                self.serviced_interrupt = Some(mmu.pc);
+                n
+            }
         }
     }
 
-    /// Debug function. Panic when an opcode is not handled.
-    fn panic_opcode(&self, opcode: u8, is_cbprefix: bool, operation_address: u16) {
+    /// Debug function. Called when an opcode is not handled. In strict mode this panics, otherwise
+    /// the opcode is reported and treated as a NOP so the program can carry on. When pausing on
+    /// unknown opcodes, the CPU state is dumped and the CPU is paused instead.
+    fn unknown_opcode(&mut self, mmu: &MMU, opcode: u8, is_cbprefix: bool, operation_address: u16) {
         let msg = format!(
             "{} {:#06x}",
             self.opcodes.get_opcode_repr(opcode, is_cbprefix),
             operation_address
         );
 
-        panic!("Panic opcode: {}", msg);
+        if self.pause_on_unknown_opcode {
+            eprintln!("Paused on unimplemented opcode: {}", msg);
+            eprintln!("{}", self.dump_context(mmu, operation_address));
+            eprintln!("F5 to continue, F6 to step.");
+            self.paused = true;
+            return;
+        }
+
+        if self.strict_opcodes {
+            panic!("Panic opcode: {}", msg);
+        }
+
+        eprintln!("Skipping unimplemented opcode: {}", msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a program into work RAM at 0xC000 and point PC at it.
+    fn load_program(mmu: &mut MMU, program: &[u8]) {
+        for (n, byte) in program.iter().enumerate() {
+            mmu.wb(0xC000 + n as u16, *byte);
+        }
+        mmu.pc = 0xC000;
+    }
+
+    #[test]
+    fn test_missing_opcodes_file() {
+        let result = CPU::with_opcodes_path("does/not/exist/opcodes.json", true);
+        let error = result.err().unwrap();
+        assert!(error.contains("does/not/exist/opcodes.json"), "{}", error);
+    }
+
+    #[test]
+    fn test_opcode_histogram() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // NOP, NOP, LD A,0x42, CB SWAP A, INC A, NOP.
+        load_program(&mut mmu, &[0x00, 0x00, 0x3E, 0x42, 0xCB, 0x37, 0x3C, 0x00]);
+
+        // Nothing is counted until profiling is enabled.
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(cpu.opcode_histogram().iter().sum::<u64>(), 0);
+
+        cpu.enable_opcode_histogram();
+        for _ in 0..5 {
+            cpu.do_opcode(&mut mmu);
+        }
+
+        let histogram = cpu.opcode_histogram();
+        assert_eq!(histogram[0x00], 2);
+        assert_eq!(histogram[0x3E], 1);
+        assert_eq!(histogram[0xCB], 1);
+        assert_eq!(histogram[0x3C], 1);
+        assert_eq!(histogram.iter().sum::<u64>(), 5);
+        assert_eq!(mmu.a, 0x25);
+    }
+
+    #[test]
+    fn test_cb_rl_hl() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xCB, 0x16, 0xCB, 0x16]); // RL (HL), RL (HL)
+        mmu.set_hl(0xD000);
+        mmu.wb(0xD000, 0x80);
+        mmu.set_flag_c(false);
+
+        // Bit 7 rotates out into carry; the old carry (0) rotates in. Written back to memory.
+        let cycles = cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.rb(0xD000), 0x00);
+        assert!(mmu.flag_z());
+        assert!(mmu.flag_c());
+        assert!(!mmu.flag_n());
+        assert!(!mmu.flag_h());
+        assert_eq!(cycles, 16);
+
+        // Now the carry rotates back in as bit 0.
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.rb(0xD000), 0x01);
+        assert!(!mmu.flag_z());
+        assert!(!mmu.flag_c());
+        assert_eq!(mmu.hl(), 0xD000);
+    }
+
+    #[test]
+    fn test_cb_cycles() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        mmu.set_hl(0xD000);
+
+        // SWAP B, SWAP (HL), BIT 0,B, BIT 0,(HL), SET 0,(HL). The prefix fetch is included.
+        load_program(
+            &mut mmu,
+            &[0xCB, 0x30, 0xCB, 0x36, 0xCB, 0x40, 0xCB, 0x46, 0xCB, 0xC6],
+        );
+        for &expected in [8, 16, 8, 12, 16].iter() {
+            let address = mmu.pc;
+            let cycles = cpu.do_opcode(&mut mmu);
+            assert_eq!(cycles, expected, "{:#06x}", address);
+        }
+        assert_eq!(mmu.pc, 0xC00A);
+    }
+
+    #[test]
+    fn test_cb_rl_a_and_sla_l() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xCB, 0x17, 0xCB, 0x25]); // RL A, SLA L
+        mmu.a = 0x81;
+        mmu.h = 0x12;
+        mmu.l = 0x41;
+        mmu.set_flag_c(false);
+
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x02);
+        assert!(mmu.flag_c());
+
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.l, 0x82);
+        assert_eq!(mmu.h, 0x12); // Only L is shifted.
+        assert!(!mmu.flag_c());
+    }
+
+    #[test]
+    fn test_inc_c() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0x0C]); // INC C
+        mmu.c = 0xFF;
+        mmu.set_flag_n(true);
+        mmu.set_flag_c(false);
+
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.c, 0x00);
+        assert!(mmu.flag_z());
+        assert!(mmu.flag_h());
+        assert!(!mmu.flag_n());
+        assert!(!mmu.flag_c()); // INC doesn't touch carry.
+    }
+
+    #[test]
+    fn test_jr_c() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // JR C,+5: relative to the next instruction at 0xC002.
+        load_program(&mut mmu, &[0x38, 0x05]);
+        mmu.set_flag_c(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 12);
+        assert_eq!(mmu.pc, 0xC007);
+
+        // JR C,-4.
+        load_program(&mut mmu, &[0x38, 0xFC]);
+        assert_eq!(cpu.do_opcode(&mut mmu), 12);
+        assert_eq!(mmu.pc, 0xBFFE);
+
+        // Without carry there's no jump.
+        load_program(&mut mmu, &[0x38, 0xFC]);
+        mmu.set_flag_c(false);
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.pc, 0xC002);
+    }
+
+    #[test]
+    fn test_rst() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        for (opcode, vector) in [0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF]
+            .iter()
+            .zip(&[0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38])
+        {
+            load_program(&mut mmu, &[*opcode]);
+            mmu.sp = 0xDFFE;
+            assert_eq!(cpu.do_opcode(&mut mmu), 16);
+            assert_eq!(mmu.pc, *vector, "RST {:02X}", vector);
+            assert_eq!(mmu.sp, 0xDFFC);
+            assert_eq!(mmu.pop_stack(), 0xC001);
+        }
+    }
+
+    #[test]
+    fn test_conditional_call() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // CALL NZ/Z/NC/C,0x1234, and the flags that make each one jump.
+        let calls = [
+            (0xC4, false, false),
+            (0xCC, true, false),
+            (0xD4, false, false),
+            (0xDC, false, true),
+        ];
+        for (opcode, z, c) in calls.iter() {
+            load_program(&mut mmu, &[*opcode, 0x34, 0x12]);
+            mmu.sp = 0xDFFE;
+            mmu.set_flag_z(*z);
+            mmu.set_flag_c(*c);
+            assert_eq!(cpu.do_opcode(&mut mmu), 24, "CALL {:02X}", opcode);
+            assert_eq!(mmu.pc, 0x1234);
+            assert_eq!(mmu.pop_stack(), 0xC003);
+
+            // With the opposite flags the operand is skipped and nothing is pushed.
+            load_program(&mut mmu, &[*opcode, 0x34, 0x12]);
+            mmu.set_flag_z(!*z);
+            mmu.set_flag_c(!*c);
+            assert_eq!(cpu.do_opcode(&mut mmu), 12, "CALL {:02X}", opcode);
+            assert_eq!(mmu.pc, 0xC003);
+            assert_eq!(mmu.sp, 0xDFFE);
+        }
+    }
+
+    #[test]
+    fn test_jp_carry() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // JP NC/C,0x1234, and the carry that makes each one jump.
+        for (opcode, c) in [(0xD2, false), (0xDA, true)].iter() {
+            load_program(&mut mmu, &[*opcode, 0x34, 0x12]);
+            mmu.set_flag_c(*c);
+            assert_eq!(cpu.do_opcode(&mut mmu), 16, "JP {:02X}", opcode);
+            assert_eq!(mmu.pc, 0x1234);
+
+            load_program(&mut mmu, &[*opcode, 0x34, 0x12]);
+            mmu.set_flag_c(!*c);
+            assert_eq!(cpu.do_opcode(&mut mmu), 12, "JP {:02X}", opcode);
+            assert_eq!(mmu.pc, 0xC003);
+        }
+    }
+
+    #[test]
+    fn test_ld_a16_sp() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0x08, 0x00, 0xD0]); // LD (0xD000),SP
+        mmu.sp = 0xBEEF;
+
+        assert_eq!(cpu.do_opcode(&mut mmu), 20);
+        assert_eq!(mmu.rb(0xD000), 0xEF);
+        assert_eq!(mmu.rb(0xD001), 0xBE);
+        assert_eq!(mmu.pc, 0xC003);
+    }
+
+    #[test]
+    fn test_add_hl_hl_and_sp() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // ADD HL,HL: bit 11 carries into bit 12 (H), bit 15 doesn't carry out.
+        load_program(&mut mmu, &[0x29]);
+        mmu.set_hl(0x0800);
+        mmu.set_flag_z(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.hl(), 0x1000);
+        assert!(mmu.flag_h() && !mmu.flag_c() && !mmu.flag_n());
+        assert!(mmu.flag_z()); // Z is left alone.
+
+        // Bit 15 carries out (C), bit 11 doesn't.
+        load_program(&mut mmu, &[0x29]);
+        mmu.set_hl(0x8000);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.hl(), 0x0000);
+        assert!(!mmu.flag_h() && mmu.flag_c());
+
+        // ADD HL,SP
+        load_program(&mut mmu, &[0x39]);
+        mmu.set_hl(0x1234);
+        mmu.sp = 0xDFFE;
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.hl(), 0xF232);
+        assert!(mmu.flag_h() && !mmu.flag_c());
+    }
+
+    #[test]
+    fn test_rrca_rra() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // RRCA: bit 0 goes to both bit 7 and the carry.
+        load_program(&mut mmu, &[0x0F, 0x0F]);
+        mmu.a = 0b0000_0001;
+        assert_eq!(cpu.do_opcode(&mut mmu), 4);
+        assert_eq!(mmu.a, 0b1000_0000);
+        assert!(mmu.flag_c() && !mmu.flag_z());
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0b0100_0000);
+        assert!(!mmu.flag_c());
+
+        // RRA: the old carry goes to bit 7, bit 0 goes to the carry.
+        load_program(&mut mmu, &[0x1F, 0x1F]);
+        mmu.a = 0b1000_0001;
+        mmu.set_flag_c(false);
+        assert_eq!(cpu.do_opcode(&mut mmu), 4);
+        assert_eq!(mmu.a, 0b0100_0000);
+        assert!(mmu.flag_c());
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0b1010_0000);
+        assert!(!mmu.flag_c());
+
+        // Z is always reset, even when A ends up 0.
+        load_program(&mut mmu, &[0x1F]);
+        mmu.a = 0b0000_0001;
+        mmu.set_flag_c(false);
+        mmu.set_flag_z(true);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0);
+        assert!(mmu.flag_c() && !mmu.flag_z());
+    }
+
+    #[test]
+    fn test_inc_d_and_sp() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // INC D sets Z and H (and resets N) but leaves C alone.
+        load_program(&mut mmu, &[0x14]);
+        mmu.d = 0xFF;
+        mmu.set_flag_n(true);
+        mmu.set_flag_c(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 4);
+        assert_eq!(mmu.d, 0x00);
+        assert!(mmu.flag_z() && mmu.flag_h() && !mmu.flag_n() && mmu.flag_c());
+
+        // INC SP wraps the same way but touches no flags at all.
+        load_program(&mut mmu, &[0x33]);
+        mmu.sp = 0xFFFF;
+        mmu.set_flag_z(false);
+        mmu.set_flag_h(false);
+        mmu.set_flag_n(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.sp, 0x0000);
+        assert!(!mmu.flag_z() && !mmu.flag_h() && mmu.flag_n() && mmu.flag_c());
+    }
+
+    #[test]
+    fn test_ld_c_a_round_trip() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+
+        // LD (C),A then LD A,(C) through high RAM.
+        load_program(&mut mmu, &[0xE2, 0xF2]);
+        mmu.c = 0x80;
+        mmu.a = 0x42;
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.rb(0xFF80), 0x42);
+        mmu.a = 0;
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.a, 0x42);
+
+        // And through an IO register: SCX (0xFF43).
+        load_program(&mut mmu, &[0xE2, 0xF2]);
+        mmu.c = 0x43;
+        mmu.a = 0x17;
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.ppu.scx, 0x17);
+        mmu.a = 0;
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x17);
+    }
+
+    #[test]
+    fn test_ld_bc_a() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0x02]);
+        mmu.set_bc(0xD123);
+        mmu.a = 0x99;
+
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.rb(0xD123), 0x99);
+        assert_eq!(mmu.bc(), 0xD123);
+    }
+
+    #[test]
+    fn test_adc_register_and_hl() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0x88, 0x8E]); // ADC A,B then ADC A,(HL)
+
+        // The carry alone takes the low nibble past 0xF, from a register.
+        mmu.a = 0x2F;
+        mmu.b = 0x10;
+        mmu.set_flag_c(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 4);
+        assert_eq!(mmu.a, 0x40);
+        assert!(mmu.flag_h());
+        assert!(!mmu.flag_c());
+
+        // And past 0xFF, from memory.
+        mmu.a = 0xF0;
+        mmu.set_hl(0xD000);
+        mmu.wb(0xD000, 0x0F);
+        mmu.set_flag_c(true);
+        assert_eq!(cpu.do_opcode(&mut mmu), 8);
+        assert_eq!(mmu.a, 0x00);
+        assert!(mmu.flag_z() && mmu.flag_h() && mmu.flag_c());
+    }
+
+    #[test]
+    fn test_and_registers() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xA0, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6]);
+        mmu.b = 0x0F;
+        mmu.d = 0x3C;
+        mmu.e = 0x00;
+        mmu.h = 0xD0;
+        mmu.l = 0x81;
+        mmu.wb(0xD081, 0xAA);
+
+        // AND B, D, E, H, L then (HL), each into 0xF0.
+        let expected = [
+            (0x00, 4),
+            (0x30, 4),
+            (0x00, 4),
+            (0xD0, 4),
+            (0x80, 4),
+            (0xA0, 8),
+        ];
+        for &(result, cycles) in expected.iter() {
+            mmu.a = 0xF0;
+            mmu.set_flag_n(true);
+            mmu.set_flag_c(true);
+            assert_eq!(cpu.do_opcode(&mut mmu), cycles);
+            assert_eq!(mmu.a, result);
+            assert_eq!(mmu.flag_z(), result == 0);
+            assert!(!mmu.flag_n());
+            assert!(mmu.flag_h());
+            assert!(!mmu.flag_c());
+        }
+    }
+
+    #[test]
+    fn test_adc_immediate() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xCE, 0x00, 0xCE, 0xFF, 0xCE, 0x01]); // ADC A,0x00/0xFF/0x01
+
+        // Only the carry takes the low nibble past 0xF.
+        mmu.a = 0x0F;
+        mmu.set_flag_c(true);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x10);
+        assert!(mmu.flag_h());
+        assert!(!mmu.flag_c());
+
+        // Only the carry takes the result past 0xFF.
+        mmu.a = 0x00;
+        mmu.set_flag_c(true);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x00);
+        assert!(mmu.flag_z());
+        assert!(mmu.flag_h());
+        assert!(mmu.flag_c());
+
+        // No carry in.
+        mmu.a = 0x41;
+        mmu.set_flag_c(false);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x42);
+        assert!(!mmu.flag_z());
+        assert!(!mmu.flag_n());
+        assert!(!mmu.flag_h());
+        assert!(!mmu.flag_c());
+    }
+
+    #[test]
+    fn test_sbc_half_borrow_boundary() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xDE, 0x05, 0xDE, 0x05]); // SBC A,0x05 twice.
+
+        // Equal low nibbles: only the carry borrows from bit 4. There's no full borrow.
+        mmu.a = 0x15;
+        mmu.set_flag_c(true);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x0F);
+        assert!(mmu.flag_h());
+        assert!(!mmu.flag_c());
+
+        // Without the carry the same nibbles don't borrow.
+        mmu.a = 0x15;
+        mmu.set_flag_c(false);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x10);
+        assert!(!mmu.flag_h());
+        assert!(!mmu.flag_c());
+    }
+
+    #[test]
+    fn test_sbc_immediate() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        load_program(&mut mmu, &[0xDE, 0xFF, 0xDE, 0x00, 0xDE, 0x01]); // SBC A,0xFF/0x00/0x01
+
+        // 0xFF plus the carry is a whole 0x100 to subtract.
+        mmu.a = 0xFF;
+        mmu.set_flag_c(true);
+        let cycles = cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0xFF);
+        assert!(mmu.flag_n());
+        assert!(mmu.flag_h());
+        assert!(mmu.flag_c());
+        assert_eq!(cycles, 8);
+
+        // Only the carry causes the (half) borrow.
+        mmu.a = 0x00;
+        mmu.set_flag_c(true);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0xFF);
+        assert!(mmu.flag_h());
+        assert!(mmu.flag_c());
+
+        // No carry in.
+        mmu.a = 0x01;
+        mmu.set_flag_c(false);
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.a, 0x00);
+        assert!(mmu.flag_z());
+        assert!(mmu.flag_n());
+        assert!(!mmu.flag_h());
+        assert!(!mmu.flag_c());
+    }
+
+    #[test]
+    fn test_logical_immediates() {
+        // (opcode, A, d8, result, H). Each op clears N and C; AND sets H, XOR and OR clear it.
+        let cases = [
+            (0xE6, 0b1100_1010, 0b1010_0110, 0b1000_0010, true), // AND d8
+            (0xE6, 0b1111_0000, 0b0000_1111, 0x00, true),
+            (0xEE, 0b1100_1010, 0b1010_0110, 0b0110_1100, false), // XOR d8
+            (0xEE, 0x5A, 0x5A, 0x00, false),
+            (0xF6, 0b1100_1010, 0b1010_0110, 0b1110_1110, false), // OR d8
+            (0xF6, 0x00, 0x00, 0x00, false),
+        ];
+
+        for &(opcode, a, d8, result, h) in cases.iter() {
+            let mut cpu = CPU::new(true).unwrap();
+            let mut mmu = MMU::new(None, false);
+            load_program(&mut mmu, &[opcode, d8, 0x00]);
+            mmu.a = a;
+            mmu.set_flag_z(result != 0); // Start with every flag wrong.
+            mmu.set_flag_n(true);
+            mmu.set_flag_h(!h);
+            mmu.set_flag_c(true);
+
+            let cycles = cpu.do_opcode(&mut mmu);
+            let case = format!("{:#04x} {:#04x} {:#04x}", opcode, a, d8);
+            assert_eq!(mmu.a, result, "{}", case);
+            assert_eq!(mmu.flag_z(), result == 0, "{}", case);
+            assert!(!mmu.flag_n(), "{}", case);
+            assert_eq!(mmu.flag_h(), h, "{}", case);
+            assert!(!mmu.flag_c(), "{}", case);
+            assert_eq!(mmu.pc, 0xC002, "{}", case); // The immediate byte was consumed.
+            assert_eq!(cycles, 8, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_ld_a16() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        // LD A,(0xD0C0), LD (0xD1C1),A. The address is little endian: low byte first.
+        load_program(&mut mmu, &[0xFA, 0xC0, 0xD0, 0xEA, 0xC1, 0xD1]);
+        mmu.wb(0xD0C0, 0x42);
+        mmu.wb(0xC0D0, 0x99); // Where a big endian read would look.
+
+        assert_eq!(cpu.do_opcode(&mut mmu), 16);
+        assert_eq!(mmu.a, 0x42);
+        assert_eq!(mmu.pc, 0xC003);
+
+        assert_eq!(cpu.do_opcode(&mut mmu), 16);
+        assert_eq!(mmu.rb(0xD1C1), 0x42);
+        assert_eq!(mmu.rb(0xC1D1), 0x00);
+        assert_eq!(mmu.pc, 0xC006);
+    }
+
+    #[test]
+    fn test_ld_r_r_is_nop() {
+        // LD B,B, LD C,C, LD D,D, LD E,E, LD H,H, LD L,L, LD A,A.
+        for &opcode in [0x40, 0x49, 0x52, 0x5B, 0x64, 0x6D, 0x7F].iter() {
+            let mut cpu = CPU::new(true).unwrap();
+            let mut mmu = MMU::new(None, false);
+            load_program(&mut mmu, &[opcode]);
+            mmu.set_af(0x1150);
+            mmu.set_bc(0x2233);
+            mmu.set_de(0x4455);
+            mmu.set_hl(0x6677);
+
+            assert_eq!(cpu.do_opcode(&mut mmu), 4, "{:#04x}", opcode);
+            assert_eq!(
+                [mmu.af(), mmu.bc(), mmu.de(), mmu.hl()],
+                [0x1150, 0x2233, 0x4455, 0x6677],
+                "{:#04x}",
+                opcode
+            );
+            assert_eq!(mmu.pc, 0xC001);
+        }
+    }
+
+    /// Read one of the 8-bit operands of the LD r,r block, indexed as they're encoded in opcodes:
+    /// B, C, D, E, H, L, (HL), A.
+    fn ld_operand(mmu: &MMU, index: u8) -> u8 {
+        match index {
+            0 => mmu.b,
+            1 => mmu.c,
+            2 => mmu.d,
+            3 => mmu.e,
+            4 => mmu.h,
+            5 => mmu.l,
+            6 => mmu.rb(mmu.hl()),
+            _ => mmu.a,
+        }
+    }
+
+    #[test]
+    fn test_ld_r_r() {
+        // Opcodes 0x40-0x7F are LD dst,src with dst in bits 3-5 and src in bits 0-2. 0x76 is HALT.
+        for opcode in (0x40..=0x7Fu8).filter(|&op| op != 0x76) {
+            let mut cpu = CPU::new(true).unwrap();
+            let mut mmu = MMU::new(None, false);
+            load_program(&mut mmu, &[opcode]);
+            mmu.a = 0xA1;
+            mmu.b = 0xB2;
+            mmu.c = 0xC3;
+            mmu.d = 0xD4;
+            mmu.e = 0xE5;
+            mmu.h = 0xD0;
+            mmu.l = 0x10;
+            mmu.wb(0xD010, 0x77);
+
+            let dst = (opcode >> 3) & 0x07;
+            let src = opcode & 0x07;
+            let before: Vec<u8> = (0..8).map(|n| ld_operand(&mmu, n)).collect();
+            cpu.do_opcode(&mut mmu);
+
+            for n in 0..8 {
+                // Changing H or L moves (HL), so don't compare it then.
+                if n == 6 && (dst == 4 || dst == 5) {
+                    continue;
+                }
+                let expected = if n == dst {
+                    before[src as usize]
+                } else {
+                    before[n as usize]
+                };
+                assert_eq!(
+                    ld_operand(&mmu, n),
+                    expected,
+                    "{:#04x}: operand {}",
+                    opcode,
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pause_on_unknown_opcode() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        cpu.enable_pause_on_unknown_opcode();
+        // LD A,0x42, an unimplemented opcode, LD (0xD000),A, JR -2, NOP.
+        load_program(
+            &mut mmu,
+            &[0x3E, 0x42, 0xD3, 0xEA, 0x00, 0xD0, 0x18, 0xFE, 0x00],
+        );
+        mmu.set_af(0x01B0);
+        mmu.set_bc(0x0013);
+        mmu.set_de(0x00D8);
+        mmu.set_hl(0x014D);
+        mmu.sp = 0xFFFE;
+
+        cpu.do_opcode(&mut mmu);
+        assert!(!cpu.is_paused());
+        cpu.do_opcode(&mut mmu); // Doesn't panic, despite being strict.
+        assert!(cpu.is_paused());
+
+        assert_eq!(
+            cpu.dump_context(&mmu, 0xC002),
+            [
+                "=> 0xc002: ILLEGAL_D3",
+                "   0xc003: LD (0xd000),A",
+                "   0xc006: JR -2",
+                "   0xc008: NOP",
+                "   0xc009: NOP",
+                "AF=0x42b0 BC=0x0013 DE=0x00d8 HL=0x014d SP=0xfffe PC=0xc003",
+            ]
+            .join("\n")
+        );
+
+        cpu.resume();
+        assert!(!cpu.is_paused());
+        cpu.do_opcode(&mut mmu);
+        assert_eq!(mmu.rb(0xD000), 0x42);
+    }
+
+    #[test]
+    fn test_halt_wakes_to_interrupt() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        mmu.interrupts.inte = 0x04; // Timer.
+        mmu.interrupts.intf = 0;
+
+        // HALT, NOP.
+        load_program(&mut mmu, &[0x76, 0x00]);
+        cpu.step(&mut mmu);
+        assert!(mmu.interrupts.is_halted);
+
+        // Nothing runs while halted.
+        for _ in 0..10 {
+            cpu.step(&mut mmu);
+        }
+        assert_eq!(mmu.pc, 0xC001);
+
+        // The timer interrupt wakes the CPU straight into its handler.
+        mmu.interrupts.intf = 0x04;
+        cpu.step(&mut mmu);
+        assert!(!mmu.interrupts.is_halted);
+        assert_eq!(mmu.pc, 0x0050);
+        assert_eq!(mmu.pop_stack(), 0xC001);
+    }
+
+    #[test]
+    fn test_halt_with_ime_disabled() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        mmu.interrupts.inte = 0x04;
+        mmu.interrupts.intf = 0;
+
+        // DI, NOP (IME is off after it), HALT, INC A.
+        load_program(&mut mmu, &[0xF3, 0x00, 0x76, 0x3C]);
+        for _ in 0..3 {
+            cpu.step(&mut mmu);
+        }
+        assert!(mmu.interrupts.is_halted);
+
+        // With IME off, the interrupt wakes the CPU but isn't handled. It carries straight on.
+        mmu.interrupts.intf = 0x04;
+        mmu.a = 0;
+        cpu.step(&mut mmu);
+        assert_eq!(mmu.pc, 0xC004);
+        assert_eq!(mmu.a, 1);
+        assert_eq!(mmu.interrupts.intf, 0x04);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        mmu.interrupts.inte = 0x10; // Joypad.
+        mmu.interrupts.intf = 0;
+        mmu.timer.system_counter = 0x1234;
+
+        // STOP, its padding byte, INC A.
+        load_program(&mut mmu, &[0x10, 0x00, 0x3C]);
+        cpu.step(&mut mmu);
+        assert!(mmu.interrupts.is_stopped);
+        assert_eq!(mmu.pc, 0xC002);
+        assert_eq!(mmu.rb(0xFF04), 0);
+
+        for _ in 0..10 {
+            cpu.step(&mut mmu);
+        }
+        assert_eq!(mmu.pc, 0xC002);
+
+        // A button press wakes the CPU, straight into the joypad handler.
+        mmu.interrupts.intf = 0x10;
+        cpu.step(&mut mmu);
+        assert!(!mmu.interrupts.is_stopped);
+        assert_eq!(mmu.pc, 0x0060);
+        assert_eq!(mmu.pop_stack(), 0xC002);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut cpu = CPU::new(true).unwrap();
+        let mut mmu = MMU::new(None, false);
+        mmu.interrupts.inte = 0x04;
+        mmu.interrupts.intf = 0;
+
+        // DI, NOP, HALT, INC A, NOP. The interrupt is already pending when HALT runs.
+        load_program(&mut mmu, &[0xF3, 0x00, 0x76, 0x3C, 0x00]);
+        cpu.step(&mut mmu);
+        cpu.step(&mut mmu);
+        mmu.interrupts.intf = 0x04;
+        cpu.step(&mut mmu);
+        assert!(!mmu.interrupts.is_halted);
+        assert_eq!(mmu.pc, 0xC003);
+
+        // INC A is read twice: PC doesn't move past it the first time.
+        mmu.a = 0;
+        cpu.step(&mut mmu);
+        assert_eq!(mmu.pc, 0xC003);
+        cpu.step(&mut mmu);
+        assert_eq!(mmu.pc, 0xC004);
+        assert_eq!(mmu.a, 2);
     }
 }
@@ -1,8 +1,43 @@
 use super::MMU;
+use serde::{Deserialize, Serialize};
+
+/// The eight buttons, in the order `Gamepad::update_state` takes them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+/// How to resolve simultaneous opposing directions (SOCD), ie. left and right or up and down held
+/// at once. A real d-pad can't do it, but a keyboard can. The hardware itself reports both as
+/// pressed, which some games handle badly (and some glitches rely on).
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Socd {
+    Allow,    // Report both as pressed, like the hardware.
+    Neutral,  // Report neither.
+    LastWins, // Report whichever was pressed most recently.
+}
 
 pub struct Gamepad {
     button_state: u8, // P15
     dpad_state: u8,   // P14
+    socd: Socd,
+    held: [bool; 8],     // The keys held at the last update, before resolving SOCD.
+    resolved: [bool; 8], // The keys reported at the last update, after resolving SOCD.
+}
+
+/// The gamepad's part of a `GuestState`: which keys are held.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GamepadState {
+    pub button_state: u8,
+    pub dpad_state: u8,
 }
 
 impl Gamepad {
@@ -10,9 +45,28 @@ impl Gamepad {
         Self {
             button_state: 0xF,
             dpad_state: 0xF,
+            socd: Socd::Allow,
+            held: [false; 8],
+            resolved: [false; 8],
+        }
+    }
+
+    pub fn set_socd(&mut self, socd: Socd) {
+        self.socd = socd;
+    }
+
+    pub fn snapshot(&self) -> GamepadState {
+        GamepadState {
+            button_state: self.button_state,
+            dpad_state: self.dpad_state,
         }
     }
 
+    pub fn restore(&mut self, state: &GamepadState) {
+        self.button_state = state.button_state;
+        self.dpad_state = state.dpad_state;
+    }
+
     /// Assemble a row bitfield (bits 0-3 only) from four boolean states.
     /// Note that this inverts the state, given true == keypressed, but 0 == keypressed in the row.
     fn parse_row(keys: &[bool]) -> u8 {
@@ -30,8 +84,14 @@ impl Gamepad {
     /// This function is to be called enough to make the input feel crisp but not on every frame.
     /// 60fps is probably a good and simple target.
     pub fn update_state(&mut self, new_state: [bool; 8]) {
-        self.button_state = Self::parse_row(&new_state[4..]);
-        self.dpad_state = Self::parse_row(&new_state[..4]);
+        let mut keys = new_state;
+        self.resolve_socd(&mut keys, 0, 1); // Right and Left.
+        self.resolve_socd(&mut keys, 2, 3); // Up and Down.
+        self.held = new_state;
+        self.resolved = keys;
+
+        self.button_state = Self::parse_row(&keys[4..]);
+        self.dpad_state = Self::parse_row(&keys[..4]);
 
         // TODO: interrupts when a button is pressed. Does it happen here or in `step`?
         // If button state is selected, get state goint from high to low for each button.
@@ -39,19 +99,97 @@ impl Gamepad {
         // Material nonimplication:  a & !b;
     }
 
+    /// If both of a pair of opposing directions are held, decide which (if any) to report.
+    fn resolve_socd(&self, keys: &mut [bool; 8], first: usize, second: usize) {
+        if !(keys[first] && keys[second]) {
+            return;
+        }
+
+        match self.socd {
+            Socd::Allow => (),
+            Socd::Neutral => {
+                keys[first] = false;
+                keys[second] = false;
+            }
+            Socd::LastWins => {
+                // The one that wasn't already held was pressed last. If they were both already
+                // held, keep reporting whichever was before. If they were both pressed at once,
+                // neither wins.
+                let winner = match (self.held[first], self.held[second]) {
+                    (false, true) => Some(first),
+                    (true, false) => Some(second),
+                    _ if self.resolved[first] => Some(first),
+                    _ if self.resolved[second] => Some(second),
+                    _ => None,
+                };
+                keys[first] = winner == Some(first);
+                keys[second] = winner == Some(second);
+            }
+        }
+    }
+
     /// On every frame, read the MMU register value (bits 5 and 6) and set bits 0-3 accordingly.
+    /// Games select one row at a time, but selecting both combines them (a key in either row reads
+    /// as pressed) and selecting neither reads as nothing pressed.
     pub fn step(&self, mmu: &mut MMU) {
         let read_buttons = mmu.gamepad & 0x20;
         let read_dpad = mmu.gamepad & 0x10;
 
-        // Should never be trying to read both or neither.
-        assert_ne!(read_buttons, read_dpad);
-
         // A `0` in bits 4 or 5 represent "selected".
-        mmu.gamepad |= if read_buttons == 0 {
-            self.button_state
-        } else {
-            self.dpad_state
+        let mut row = 0x0F;
+        if read_buttons == 0 {
+            row &= self.button_state;
+        }
+        if read_dpad == 0 {
+            row &= self.dpad_state;
         }
+        mmu.gamepad = (mmu.gamepad & 0xF0) | row;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIGHT_LEFT: [bool; 8] = [true, true, false, false, false, false, false, false];
+    const LEFT: [bool; 8] = [false, true, false, false, false, false, false, false];
+    const RIGHT: [bool; 8] = [true, false, false, false, false, false, false, false];
+
+    #[test]
+    fn test_socd_allow() {
+        let mut gamepad = Gamepad::new();
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1100); // Both pressed (0).
+    }
+
+    #[test]
+    fn test_socd_neutral() {
+        let mut gamepad = Gamepad::new();
+        gamepad.set_socd(Socd::Neutral);
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1111);
+    }
+
+    #[test]
+    fn test_socd_last_wins() {
+        let mut gamepad = Gamepad::new();
+        gamepad.set_socd(Socd::LastWins);
+
+        // Holding left, then pressing right: right wins, for as long as both are held.
+        gamepad.update_state(LEFT);
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1110);
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1110);
+
+        // Releasing left and pressing it again: now left wins.
+        gamepad.update_state(RIGHT);
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1101);
+
+        // Both pressed at once: neither.
+        gamepad.update_state([false; 8]);
+        gamepad.update_state(RIGHT_LEFT);
+        assert_eq!(gamepad.dpad_state, 0b1111);
     }
 }
@@ -6,8 +6,10 @@ mod ppu;
 mod timer;
 
 pub use super::MMU;
-pub use apu::APU;
+pub use apu::{ApuState, AudioOverflow, APU};
 pub use cpu::CPU;
-pub use gamepad::Gamepad;
-pub use ppu::PPU;
+pub use gamepad::{Button, Gamepad, GamepadState, Socd};
+pub use ppu::{
+    FrameStats, LcdcOverrides, PpuState, Sprite, CYCLES_PER_FRAME, CYCLES_PER_LINE, PPU,
+};
 pub use timer::Timer;
@@ -1,16 +1,15 @@
-use super::super::mmu::is_bit_set;
+use super::super::mmu::{check_state_length, is_bit_set, PpuRegisters};
+use super::super::PpuMode;
 use super::MMU;
+use serde::{Deserialize, Serialize};
 
-/// Given MMU state, coordinates, and the address to the current tilemap, get the pixel value.
-fn get_tile_pixel(mmu: &MMU, x: u8, y: u8, tilemap_address: u16) -> u8 {
-    // Use the LCDC hardware register to determine which of the two tile data spaces in VRAM we
+/// Given MMU state, coordinates, the address to the current tilemap, and the LCDC tile data select
+/// bit, get the pixel value.
+fn get_tile_pixel(mmu: &MMU, x: u8, y: u8, tilemap_address: u16, tile_data_table: bool) -> u8 {
+    // Use the LCDC tile data select bit to determine which of the two tile data spaces in VRAM we
     // are utilizing. The upper tiledata table beginning at 0x8800 needs to be accessed
     // with a signed value, indexing on 0x9000.
-    let tiledata_base_address = if mmu.ppu.tile_data_table {
-        0x8000
-    } else {
-        0x8800
-    };
+    let tiledata_base_address = if tile_data_table { 0x8000 } else { 0x8800 };
 
     // There are 1024 tiles mapped in a 32x32 grid of 8x8 pixel tiles. The 1024 tiles are
     // described in one of the two tile maps as a row-major array. To get the tile number
@@ -25,7 +24,7 @@ fn get_tile_pixel(mmu: &MMU, x: u8, y: u8, tilemap_address: u16) -> u8 {
     // by 16 (the size of each whole tile's worth of data) and add (or subtract) that to
     // the tiledata_base_address.
     // If we are accessing TILEDATA_1, we need to access it with a signed offset.
-    let tile_data_number = mmu.rb(tilemap_address + tile_number);
+    let tile_data_number = mmu.ppu_rb(tilemap_address + tile_number);
     let tile_data_address = get_tile_data_address(tiledata_base_address, tile_data_number);
 
     // Get the pixel coordinates in the local 8x8 tile.
@@ -36,8 +35,8 @@ fn get_tile_pixel(mmu: &MMU, x: u8, y: u8, tilemap_address: u16) -> u8 {
     // tile_row_address is the address that the specific row of data where this pixel
     // is found. We multiply by 2 because every row of 8 pixels is 2 bytes of data.
     let tile_row_index = tile_data_address + (pixel_row_num as u16 * 2);
-    let tile_data_lower = mmu.rb(tile_row_index);
-    let tile_data_upper = mmu.rb(tile_row_index + 1);
+    let tile_data_lower = mmu.ppu_rb(tile_row_index);
+    let tile_data_upper = mmu.ppu_rb(tile_row_index + 1);
 
     get_pixel(tile_data_lower, tile_data_upper, pixel_col_num)
 }
@@ -62,11 +61,141 @@ fn get_pixel(tile_lower: u8, tile_upper: u8, pixel_num: u8) -> u8 {
     (p1 << 1) + p0
 }
 
+/// OAM search: walk through the 40 sprites in OAM memory and collect the first 10 that are on
+/// the given line. Returns (x_pos, y_pos, oam_index) for each, with the positions converted to
+/// screen coordinates (OAM stores y + 16 and x + 8).
+///
+/// A sprite is on a line when `line - y_pos` is a row within the sprite (0..height). Only the Y
+/// coordinate is considered: like the hardware, a sprite that is off the left or right side of
+/// the screen still takes one of the 10 slots for the line.
+fn oam_search(mmu: &MMU, line: u8) -> Vec<(isize, isize, u8)> {
+    let line = line as isize;
+    let sprite_y_size = if mmu.ppu.sprite_size { 16 } else { 8 } as isize;
+    let mut sprites = Vec::new();
+
+    for idx in 0..40 {
+        if sprites.len() == 10 {
+            break;
+        }
+
+        let oam_address = 0xFE00 + idx * 4;
+        let y_pos = mmu.ppu_rb(oam_address) as isize - 16;
+        let x_pos = mmu.ppu_rb(oam_address + 1) as isize - 8;
+
+        // The sprite is not on the screen at this line.
+        if line < y_pos || line >= y_pos + sprite_y_size {
+            continue;
+        }
+
+        sprites.push((x_pos, y_pos, idx as u8));
+    }
+
+    sprites
+}
+
+/// How many cycles longer than the usual 172 mode 3 takes on a line. The pixel FIFO stalls:
+/// - to discard the SCX % 8 pixels scrolled off the left of the first tile.
+/// - for 6 cycles when the window starts on the line.
+/// - for 6 cycles per sprite on the line. The hardware can take up to 5 more for each, depending
+///   on how the sprite lines up with the background tiles, which isn't modelled.
+///
+/// Mode 0 is shortened to match, so lines are still 456 cycles.
+fn mode3_penalty(mmu: &MMU, line: u8) -> usize {
+    let ppu = &mmu.ppu;
+    let mut penalty = (ppu.scx % 8) as usize;
+
+    if ppu.window_on && line >= ppu.win_y && ppu.win_x <= 166 {
+        penalty += 6;
+    }
+
+    // Sprites hidden off the left edge (OAM X of 0) are never fetched, so they cost nothing.
+    if ppu.sprite_on {
+        let sprites = oam_search(mmu, line);
+        penalty += 6 * sprites.iter().filter(|&&(x, _, _)| x > -8).count();
+    }
+
+    penalty
+}
+
+/// One of the 40 sprites in OAM, decoded. Positions are screen coordinates, as `oam_search` gives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    pub x: isize,
+    pub y: isize,
+    pub tile: u8,
+    pub bg_priority: bool, // Flag bit 7: drawn behind background colours 1-3.
+    pub y_flip: bool,      // Flag bit 6.
+    pub x_flip: bool,      // Flag bit 5.
+    pub palette_1: bool,   // Flag bit 4: use OBP1 rather than OBP0.
+}
+
+impl Sprite {
+    /// Parse the four bytes of OAM entry `idx`: the coordinates, tile number, and flags.
+    fn read(mmu: &MMU, idx: u8) -> Self {
+        let oam_address = 0xFE00 + idx as u16 * 4;
+        let flags = mmu.ppu_rb(oam_address + 3);
+
+        Self {
+            x: mmu.ppu_rb(oam_address + 1) as isize - 8,
+            y: mmu.ppu_rb(oam_address) as isize - 16,
+            tile: mmu.ppu_rb(oam_address + 2),
+            bg_priority: is_bit_set(flags, 7),
+            y_flip: is_bit_set(flags, 6),
+            x_flip: is_bit_set(flags, 5),
+            palette_1: is_bit_set(flags, 4),
+        }
+    }
+}
+
+pub const CYCLES_PER_LINE: usize = 456; // Every line, including VBlank lines, is 456 cycles.
+pub const LINES_PER_FRAME: usize = 154; // 144 visible lines and 10 lines of VBlank.
+pub const CYCLES_PER_FRAME: usize = CYCLES_PER_LINE * LINES_PER_FRAME; // 70224: ~59.73fps.
+
+/// Statistics about what was drawn in a frame, for debugging performance and accuracy.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct FrameStats {
+    pub sprites: usize,      // Different sprites (OAM entries) drawn on any line.
+    pub window_lines: usize, // Lines with some of the window on them.
+    pub max_sprites_per_line: usize, // The most sprites on one line. The hardware limit is 10.
+}
+
+/// Debug overrides for LCDC (0xFF40) bits, to isolate rendering layers. Each bit that's `Some` is
+/// used for rendering instead of whatever the game wrote. `None` leaves it up to the game.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LcdcOverrides {
+    pub window_bg_on: Option<bool>, // Bit 0: background (and window) on.
+    pub sprite_on: Option<bool>,    // Bit 1: sprites on.
+    pub bg_tilemap: Option<bool>,   // Bit 3: background tilemap select.
+    pub tile_data_table: Option<bool>, // Bit 4: tile data select.
+    pub window_on: Option<bool>,    // Bit 5: window on.
+    pub window_tilemap: Option<bool>, // Bit 6: window tilemap select.
+}
+
 pub struct PPU {
     modeclock: usize, // Current clock step representing where the PPU is in its processing cycle.
     pub bg_color_zero: [bool; 160], // tracks which pixels in a row have background = 0.
     pub image_buffer: [u8; 160 * 144],
     window_line_draw_count: u8, // See page 23 of GB Manual (window interrupt internal state)
+    skip_frame: bool, // The first frame after the LCD turns on isn't shown, so don't draw it.
+    frame_stats: FrameStats, // The frame being drawn.
+    last_frame_stats: FrameStats, // The last complete frame.
+    sprites_drawn: u64, // One bit per OAM entry drawn so far this frame.
+    lcdc_overrides: LcdcOverrides,
+    mode3_penalty: usize, // Extra cycles mode 3 takes on this line. See `mode3_penalty`.
+}
+
+/// The PPU's part of a `GuestState`: where it is in the frame and what it has drawn so far.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PpuState {
+    pub modeclock: usize,
+    pub bg_color_zero: Vec<bool>,
+    pub image_buffer: Vec<u8>,
+    pub window_line_draw_count: u8,
+    pub skip_frame: bool,
+    pub frame_stats: FrameStats,
+    pub last_frame_stats: FrameStats,
+    pub sprites_drawn: u64,
+    pub mode3_penalty: usize,
 }
 
 impl PPU {
@@ -76,26 +205,119 @@ impl PPU {
             bg_color_zero: [false; 160],
             image_buffer: [0; 160 * 144],
             window_line_draw_count: 0,
+            skip_frame: false,
+            frame_stats: FrameStats::default(),
+            last_frame_stats: FrameStats::default(),
+            sprites_drawn: 0,
+            lcdc_overrides: LcdcOverrides::default(),
+            mode3_penalty: 0,
+        }
+    }
+
+    /// Force LCDC bits for rendering, regardless of what the game writes. The register itself (and
+    /// so what the game reads back) is unaffected.
+    pub fn set_lcdc_overrides(&mut self, overrides: LcdcOverrides) {
+        self.lcdc_overrides = overrides;
+    }
+
+    /// The PPU registers as rendering should see them: what the game wrote, with any LCDC bits
+    /// overridden.
+    fn registers(&self, mmu: &MMU) -> PpuRegisters {
+        let forced = self.lcdc_overrides;
+        let mut ppu = mmu.ppu.clone();
+        ppu.window_bg_on = forced.window_bg_on.unwrap_or(ppu.window_bg_on);
+        ppu.sprite_on = forced.sprite_on.unwrap_or(ppu.sprite_on);
+        ppu.bg_tilemap = forced.bg_tilemap.unwrap_or(ppu.bg_tilemap);
+        ppu.tile_data_table = forced.tile_data_table.unwrap_or(ppu.tile_data_table);
+        ppu.window_on = forced.window_on.unwrap_or(ppu.window_on);
+        ppu.window_tilemap = forced.window_tilemap.unwrap_or(ppu.window_tilemap);
+        ppu
+    }
+
+    /// All 40 sprites in OAM, in OAM order, whether or not they're on screen. For debugging sprite
+    /// placement.
+    pub fn oam_sprites(mmu: &MMU) -> Vec<Sprite> {
+        (0..40).map(|idx| Sprite::read(mmu, idx)).collect()
+    }
+
+    /// Statistics for the last complete frame, ie. as of the last VBlank.
+    pub fn last_frame_stats(&self) -> FrameStats {
+        self.last_frame_stats
+    }
+
+    pub fn snapshot(&self) -> PpuState {
+        PpuState {
+            modeclock: self.modeclock,
+            bg_color_zero: self.bg_color_zero.to_vec(),
+            image_buffer: self.image_buffer.to_vec(),
+            window_line_draw_count: self.window_line_draw_count,
+            skip_frame: self.skip_frame,
+            frame_stats: self.frame_stats,
+            last_frame_stats: self.last_frame_stats,
+            sprites_drawn: self.sprites_drawn,
+            mode3_penalty: self.mode3_penalty,
         }
     }
 
+    /// Put the PPU back to a snapshot. A state with buffers of the wrong size is an error, and
+    /// leaves the PPU as it was.
+    pub fn restore(&mut self, state: &PpuState) -> Result<(), String> {
+        check_state_length(
+            "background line",
+            &state.bg_color_zero,
+            self.bg_color_zero.len(),
+        )?;
+        check_state_length("frame", &state.image_buffer, self.image_buffer.len())?;
+
+        self.modeclock = state.modeclock;
+        self.bg_color_zero.copy_from_slice(&state.bg_color_zero);
+        self.image_buffer.copy_from_slice(&state.image_buffer);
+        self.window_line_draw_count = state.window_line_draw_count;
+        self.skip_frame = state.skip_frame;
+        self.frame_stats = state.frame_stats;
+        self.last_frame_stats = state.last_frame_stats;
+        self.sprites_drawn = state.sprites_drawn;
+        self.mode3_penalty = state.mode3_penalty;
+        Ok(())
+    }
+
     fn draw_pixel(&mut self, line: u8, col: u8, value: u8) {
         self.image_buffer[line as usize * 160 + col as usize] = value;
     }
 
     /// TODO: explain the mode cycle and clocks.
-    pub fn step(&mut self, mmu: &mut MMU, cycles: u8) {
-        // The screen might be cleared entirely because the PPU's state has it shut off. Note that
-        // line and mode were also set to 0 (in the ppu )
+    /// Return true if this step entered VBlank, ie. a whole frame has just been drawn.
+    pub fn step(&mut self, mmu: &mut MMU, cycles: u8) -> bool {
+        // The screen might be cleared entirely because the PPU's state has it shut off. Line and
+        // mode were already set to 0 when the LCD was turned off.
         if mmu.ppu.clear_screen {
             self.image_buffer = [0; 160 * 144];
             self.modeclock = 0;
-            mmu.ppu.line = 0;
-            mmu.ppu.mode = 0;
             mmu.ppu.clear_screen = false; // Reset flag.
         }
 
+        // While the LCD is off the PPU does nothing at all: it sits at line 0 and there's no
+        // VBlank.
+        if !mmu.ppu.lcd_on {
+            return false;
+        }
+
+        // The LCD was just turned on. Start a new frame at the beginning of line 0's OAM search.
+        // The hardware doesn't raise a mode 2 STAT interrupt for the first line after the LCD is
+        // enabled, so treat that source as already holding the STAT line high. That first frame
+        // is never displayed either, so the screen stays blank until the next.
+        if mmu.ppu.restart {
+            self.modeclock = 0;
+            self.window_line_draw_count = 0;
+            self.skip_frame = true;
+            mmu.ppu.line = 0;
+            mmu.ppu.mode = PpuMode::OamScan;
+            mmu.ppu.stat_line = mmu.ppu.mode2_int_enable;
+            mmu.ppu.restart = false; // Reset flag.
+        }
+
         let mode = mmu.ppu.mode;
+        let mut entered_vblank = false;
 
         // Increase the clock by number of cycles being emulated. This will govern what needs
         // to happen next such as changing modes. It is possible that we exceed the number of
@@ -103,137 +325,141 @@ impl PPU {
         // self.modeclock. That allows excessive cycles to be carried over to the next mode.
         self.modeclock += cycles as usize;
 
-        if self.modeclock >= 456 {
-            self.modeclock -= 456;
-            mmu.ppu.line = (mmu.ppu.line + 1) % 154;
-            mmu.check_lyc_interrupt();
+        if self.modeclock >= CYCLES_PER_LINE {
+            self.modeclock -= CYCLES_PER_LINE;
+            mmu.ppu.line = (mmu.ppu.line + 1) % LINES_PER_FRAME as u8;
 
             // VBlank line.
-            if mmu.ppu.line >= 144 && mode != 1 {
-                mmu.ppu.mode = 1;
+            if mmu.ppu.line >= 144 && mode != PpuMode::VBlank {
+                mmu.ppu.mode = PpuMode::VBlank;
 
                 // Reset window internal state counter.
                 self.window_line_draw_count = 0;
 
-                // LCDC Status interrupt entering mode 1?
-                if mmu.ppu.mode1_int_enable {
-                    mmu.interrupts.intf |= 0x02;
-                }
                 mmu.interrupts.intf |= 0x01; // Set Vblank interrupt flag.
+                entered_vblank = true;
+                self.skip_frame = false;
+
+                // Start counting the next frame's statistics.
+                self.last_frame_stats = self.frame_stats;
+                self.frame_stats = FrameStats::default();
+                self.sprites_drawn = 0;
             }
         }
 
         // Only handle mode changes if we're in a normal line.
         if mmu.ppu.line < 144 {
-            // Determine if mode should change and interrupt should be set.
+            // Determine if mode should change. Mode 2 is 80 cycles, then mode 3 is 172 plus a
+            // penalty worked out as it starts, and mode 0 is the rest of the line.
             let change_mode = match self.modeclock {
-                0..=80 if mode != 2 => Some((2, mmu.ppu.mode2_int_enable)),
-                81..=252 if mode != 3 => Some((3, false)),
-                253..=455 if mode != 0 => Some((0, mmu.ppu.mode0_int_enable)),
+                0..=80 if mode != PpuMode::OamScan => Some(PpuMode::OamScan),
+                81..=455 if mode == PpuMode::OamScan => Some(PpuMode::Drawing),
+                n if n > 252 + self.mode3_penalty && mode == PpuMode::Drawing => {
+                    Some(PpuMode::HBlank)
+                }
                 _ => None,
             };
 
-            // Change mode and possibly set interrupt.
-            match change_mode {
-                Some((next_mode, set_interrupt)) => {
-                    mmu.ppu.mode = next_mode;
-                    if set_interrupt {
-                        mmu.interrupts.intf |= 0x02;
-                    }
-
-                    // Draw the line only when mode switches to 0.
-                    if next_mode == 0 {
-                        self.draw_scanline(mmu);
-                    }
+            if let Some(next_mode) = change_mode {
+                mmu.ppu.mode = next_mode;
+
+                if next_mode == PpuMode::Drawing {
+                    self.mode3_penalty = mode3_penalty(mmu, mmu.ppu.line);
+                }
+
+                // Draw the line only when mode switches to HBlank.
+                if next_mode == PpuMode::HBlank {
+                    self.draw_scanline(mmu);
                 }
-                None => {}
             }
         }
+
+        // The mode and LY are up to date, so see whether that (or a write to STAT or LYC) raises
+        // the STAT interrupt.
+        mmu.update_stat_interrupt();
+
+        entered_vblank
     }
 
     fn draw_scanline(&mut self, mmu: &MMU) {
-        if !mmu.ppu.lcd_on {
+        if !mmu.ppu.lcd_on || self.skip_frame {
             return;
         }
 
+        self.render_line(mmu, mmu.ppu.line);
+    }
+
+    /// Render one line of the image buffer from VRAM, OAM, and the PPU registers as they are now.
+    /// This skips the mode state machine entirely, which makes it handy for testing rendering.
+    /// Note that the window's internal line counter still advances if the window is drawn.
+    pub fn render_line(&mut self, mmu: &MMU, line: u8) {
         // Reset background priority state.
         self.bg_color_zero = [false; 160];
 
-        self.draw_background_scanline(mmu);
-        self.draw_window_scanline(mmu);
-        self.draw_sprites_scanline(mmu);
+        let window_line_draw_count = self.window_line_draw_count;
+
+        self.draw_background_scanline(mmu, line);
+        self.draw_window_scanline(mmu, line);
+        self.draw_sprites_scanline(mmu, line);
+
+        if self.window_line_draw_count != window_line_draw_count {
+            self.frame_stats.window_lines += 1;
+        }
     }
 
     /// Modify the current line's buffer with sprite data. Sprite pixels may not draw depending on
     /// OAM settings governing if the sprite is in front of or behind the background.
     /// It's easier to work with `isize` values because we're dealing with a mapping space that
     /// can have negative values (off screen sprites).
-    fn draw_sprites_scanline(&mut self, mmu: &MMU) {
-        let ppu = &mmu.ppu;
-        let line = ppu.line as isize;
+    fn draw_sprites_scanline(&mut self, mmu: &MMU, line: u8) {
+        let ppu = &self.registers(mmu);
         let sprite_y_size = if ppu.sprite_size { 16 } else { 8 } as isize;
 
         if !ppu.sprite_on {
             return;
         };
 
-        let mut sprites_to_draw: Vec<(isize, isize, u8)> = Vec::new();
-
-        // Walk through 40 sprites in OAM memory and collect the first 10 that draw on this line.
-        // Note that we hold on to x_pos and idx because they're needed for sorting and access.
-        // We hold on to y_pos just because: we've already read it, may as well hang on to it.
-        for idx in 0..40 {
-            if sprites_to_draw.len() == 10 {
-                break;
-            }
+        let mut sprites_to_draw = oam_search(mmu, line);
+        let line = line as isize;
 
-            // Get the sprite. Does it get drawn on this line and is on screen?
-            let oam_address = 0xFE00 + idx * 4;
-            let y_pos = mmu.rb(oam_address) as isize - 16;
-            let x_pos = mmu.rb(oam_address + 1) as isize - 8;
-
-            // The sprite is not on the screen at this line.
-            if line < y_pos || line >= y_pos + sprite_y_size || x_pos < -7 || x_pos >= 160 {
-                continue;
-            }
-
-            sprites_to_draw.push((x_pos as isize, y_pos as isize, idx as u8));
+        for &(_, _, idx) in sprites_to_draw.iter() {
+            self.sprites_drawn |= 1 << idx;
         }
+        self.frame_stats.sprites = self.sprites_drawn.count_ones() as usize;
+        self.frame_stats.max_sprites_per_line = self
+            .frame_stats
+            .max_sprites_per_line
+            .max(sprites_to_draw.len());
 
         // Now that we have 10, sort them by priority:
         // - if the sprites overlap on the x axis, the lower x_pos is on top.
         // - if sprites overlap fully (same x_pos) the earlier object is on top.
         // This is accomplished by performing a stable sort based on the x_pos.
-        sprites_to_draw.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap());
+        sprites_to_draw.sort_by_key(|&(x_pos, _, _)| x_pos);
 
         // There's now up to 10 sprites to be drawn. Iterate this list in reverse to draw, because
         // the earlier sprites in OAM get priority. Note: we already verified that these sprites
         // should be drawn.
         for &(x_pos, y_pos, idx) in sprites_to_draw.iter().rev() {
-            // Parse four bytes of data representing the coordinates, sprite number, and flags.
             // The positions are handled as signed integers to allow them to be off the screen.
             // If they remain off the screen when added to the line number or column, they will
             // ultimately not be drawn.
-            let oam_address = 0xFE00 + idx as u16 * 4;
+            let sprite = Sprite::read(mmu, idx);
 
             // If the sprite is 8x16, bit 0 in the sprite_number is ignored.
             let sprite_number =
-                (mmu.rb(oam_address + 2) & if sprite_y_size == 16 { 0xFE } else { 0xFF }) as u16;
+                (sprite.tile & if sprite_y_size == 16 { 0xFE } else { 0xFF }) as u16;
 
-            let flags = mmu.rb(oam_address + 3);
-            let palette = if is_bit_set(flags, 4) {
+            let palette = if sprite.palette_1 {
                 ppu.obj_palette_1
             } else {
                 ppu.obj_palette_0
             };
-            let bg_priority = is_bit_set(flags, 7);
-            let y_flip = is_bit_set(flags, 6);
-            let x_flip = is_bit_set(flags, 5);
 
             // Get the y-coordinate of the current sprite. A sprite is 8 or 16 rows tall.
             // Depending on what line we're rendering, we get one of those lines to draw onto it.
             // If y_flip is true, we invert which line we're getting.
-            let sprite_y = if y_flip {
+            let sprite_y = if sprite.y_flip {
                 (sprite_y_size - 1 - (line - y_pos as isize)) as u16
             } else {
                 (line - y_pos as isize) as u16
@@ -245,25 +471,26 @@ impl PPU {
             let sprite_data_address = 0x8000 + (sprite_number * 16) + (sprite_y * 2);
 
             // Get the sprite data (2 bytes, combined makes a row of 8 pixels).
-            let sprite_data_lower = mmu.rb(sprite_data_address);
-            let sprite_data_upper = mmu.rb(sprite_data_address + 1);
+            let sprite_data_lower = mmu.ppu_rb(sprite_data_address);
+            let sprite_data_upper = mmu.ppu_rb(sprite_data_address + 1);
 
             // Walk through each pixel to be drawn.
             for p in 0..8isize {
-                // Is this specific pixel not on the screen? We already check that x_pos is not off
-                // the left side earlier, so only need to check that it's not off the right side.
-                if x_pos + p >= 160 {
-                    // TODO: add with overflow when SML left side of screen.
+                // Is this specific pixel not on the screen? Sprites can hang off either side.
+                if x_pos + p < 0 || x_pos + p >= 160 {
                     continue;
                 }
 
                 // Don't draw if hiding under the background.
-                if !mmu.ppu.window_bg_on && bg_priority && !self.bg_color_zero[x_pos as usize] {
+                if !ppu.window_bg_on
+                    && sprite.bg_priority
+                    && !self.bg_color_zero[(x_pos + p) as usize]
+                {
                     continue;
                 }
 
                 // Number of pixel (0-7) of this row of the sprite. Might be horizontally flipped.
-                let pixel_num = if x_flip { 7 - p } else { p };
+                let pixel_num = if sprite.x_flip { 7 - p } else { p };
                 let pixel_value = get_pixel(sprite_data_lower, sprite_data_upper, pixel_num as u8);
                 let color = (palette >> (pixel_value * 2)) & 0x3;
 
@@ -280,20 +507,13 @@ impl PPU {
     /// Draw the window. This is very similar to the background but is implemented separately to
     /// make the code more understandable. The cost is a bit of repetition and some unnecessary
     /// drawing of background pixels that immediately get covered  up by the window.
-    fn draw_window_scanline(&mut self, mmu: &MMU) {
-        let ppu = &mmu.ppu;
+    fn draw_window_scanline(&mut self, mmu: &MMU, line: u8) {
+        let ppu = &self.registers(mmu);
 
-        if !ppu.window_on || ppu.line < ppu.win_y {
+        if !ppu.window_on || line < ppu.win_y {
             return;
         }
 
-        // // The y coord of the top-left of this current line of the window.
-        // let win_y = self.window_line_draw_count;
-
-        // if win_y < 0 {
-        //     return;
-        // }
-
         let tilemap_address = if ppu.window_tilemap { 0x9C00 } else { 0x9800 };
 
         let mut drew_pixel = false;
@@ -307,14 +527,17 @@ impl PPU {
                 continue;
             }
 
+            // The window's row comes from its own line counter, not LY: lines where the window
+            // wasn't drawn don't count, so it carries on from where it left off.
             let pixel = get_tile_pixel(
                 mmu,
                 win_x as u8,
-                self.window_line_draw_count as u8,
+                self.window_line_draw_count,
                 tilemap_address,
+                ppu.tile_data_table,
             );
 
-            self.draw_pixel(ppu.line, x, pixel);
+            self.draw_pixel(line, x, pixel);
             drew_pixel = true;
         }
 
@@ -326,8 +549,8 @@ impl PPU {
     /// Draw a single scanline by iterating through a line of pixels and getting pixel data from
     /// the relevant tiles. Only a subset of the 256x256 scene is displayed, so we are not always
     /// drawing complete tiles. There's also wrap-around possible.
-    fn draw_background_scanline(&mut self, mmu: &MMU) {
-        let ppu = &mmu.ppu;
+    fn draw_background_scanline(&mut self, mmu: &MMU, line: u8) {
+        let ppu = &self.registers(mmu);
 
         // If LCDC0 (window and bg on) is false, don't draw anything.
         if !ppu.window_bg_on {
@@ -344,9 +567,9 @@ impl PPU {
             // register values. This accounts for the viewport we want to draw not being the same
             // as the 256x256 tilemap scene.
             let x = col.wrapping_add(ppu.scx);
-            let y = ppu.line.wrapping_add(ppu.scy);
+            let y = line.wrapping_add(ppu.scy);
 
-            let pixel_value = get_tile_pixel(mmu, x, y, tilemap_address);
+            let pixel_value = get_tile_pixel(mmu, x, y, tilemap_address, ppu.tile_data_table);
             let color = (ppu.background_palette >> (pixel_value * 2)) & 0x3;
 
             // Set background priority.
@@ -354,7 +577,7 @@ impl PPU {
 
             // Update the image buffer with this pixel value. Given a well-behaved main loop should
             // iterate through every pixel, there is no need to clear the previous buffer data.
-            self.draw_pixel(ppu.line, col, color);
+            self.draw_pixel(line, col, color);
         }
     }
 }
@@ -363,6 +586,94 @@ impl PPU {
 mod tests {
     use super::*;
 
+    /// Write a sprite's four OAM bytes. Positions are given in screen coordinates.
+    fn set_sprite(mmu: &mut MMU, idx: u16, x: isize, y: isize) {
+        let address = 0xFE00 + idx * 4;
+        mmu.wb(address, (y + 16) as u8);
+        mmu.wb(address + 1, (x + 8) as u8);
+        mmu.wb(address + 2, 0);
+        mmu.wb(address + 3, 0);
+    }
+
+    #[test]
+    fn test_oam_search_top_and_bottom_rows() {
+        let mut mmu = MMU::new(None, false);
+        mmu.ppu.sprite_size = false; // 8x8 sprites.
+        set_sprite(&mut mmu, 0, 20, 50);
+
+        // Top row is exactly line 50, bottom row is exactly line 57.
+        assert_eq!(oam_search(&mmu, 49), vec![]);
+        assert_eq!(oam_search(&mmu, 50), vec![(20, 50, 0)]);
+        assert_eq!(oam_search(&mmu, 57), vec![(20, 50, 0)]);
+        assert_eq!(oam_search(&mmu, 58), vec![]);
+
+        // 8x16 sprites have a bottom row 8 lines further down.
+        mmu.ppu.sprite_size = true;
+        assert_eq!(oam_search(&mmu, 65), vec![(20, 50, 0)]);
+        assert_eq!(oam_search(&mmu, 66), vec![]);
+    }
+
+    #[test]
+    fn test_oam_sprites() {
+        let mut mmu = MMU::new(None, false);
+        set_sprite(&mut mmu, 0, 20, 50);
+        set_sprite(&mut mmu, 39, -8, -16); // All zeros in OAM: hidden off the top left.
+        mmu.wb(0xFE02, 0x42);
+        mmu.wb(0xFE03, 0b1011_0000);
+
+        let sprites = PPU::oam_sprites(&mmu);
+        assert_eq!(sprites.len(), 40);
+        assert_eq!(
+            sprites[0],
+            Sprite {
+                x: 20,
+                y: 50,
+                tile: 0x42,
+                bg_priority: true,
+                y_flip: false,
+                x_flip: true,
+                palette_1: true,
+            }
+        );
+        assert_eq!(
+            sprites[39],
+            Sprite {
+                x: -8,
+                y: -16,
+                tile: 0,
+                bg_priority: false,
+                y_flip: false,
+                x_flip: false,
+                palette_1: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_oam_search_partially_above_screen() {
+        let mut mmu = MMU::new(None, false);
+        set_sprite(&mut mmu, 0, 20, -7); // OAM y = 9: only the bottom row is visible.
+
+        assert_eq!(oam_search(&mmu, 0), vec![(20, -7, 0)]);
+        assert_eq!(oam_search(&mmu, 1), vec![]);
+    }
+
+    #[test]
+    fn test_oam_search_limit_includes_offscreen_x() {
+        let mut mmu = MMU::new(None, false);
+
+        // Eleven sprites on line 0, the first of which is off the left side of the screen.
+        set_sprite(&mut mmu, 0, -8, 0);
+        for idx in 1..11 {
+            set_sprite(&mut mmu, idx, idx as isize * 8, 0);
+        }
+
+        let sprites = oam_search(&mmu, 0);
+        assert_eq!(sprites.len(), 10);
+        assert_eq!(sprites[0], (-8, 0, 0));
+        assert_eq!(sprites[9], (72, 0, 9)); // The 11th sprite didn't make it.
+    }
+
     #[test]
     fn test_get_tile_data_address() {
         // low tile data, access as unsigned.
@@ -378,4 +689,438 @@ mod tests {
         let result = get_tile_data_address(0x8800, 0x80);
         assert_eq!(result, 0x8800);
     }
+
+    #[test]
+    fn test_frame_length() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.ppu.lcd_on = true;
+
+        // Run to the start of a VBlank, then count the cycles until the next one.
+        while mmu.interrupts.intf & 0x01 == 0 {
+            ppu.step(&mut mmu, 4);
+        }
+        mmu.interrupts.intf = 0;
+
+        let mut cycles = 4;
+        while !ppu.step(&mut mmu, 4) {
+            cycles += 4;
+        }
+        assert_eq!(mmu.interrupts.intf & 0x01, 0x01);
+
+        assert_eq!(cycles, CYCLES_PER_FRAME);
+        assert_eq!(cycles, 70224);
+    }
+
+    /// Run the PPU to the start of VBlank, then for a whole frame up to the next, 4 cycles at a
+    /// time. Count the STAT interrupt requests in that frame.
+    fn count_stat_interrupts(ppu: &mut PPU, mmu: &mut MMU) -> usize {
+        while !ppu.step(mmu, 4) {}
+
+        let mut count = 0;
+        loop {
+            mmu.interrupts.intf = 0;
+            let entered_vblank = ppu.step(mmu, 4);
+            if mmu.interrupts.intf & 0x02 != 0 {
+                count += 1;
+            }
+            if entered_vblank {
+                return count;
+            }
+        }
+    }
+
+    #[test]
+    fn test_stat_lyc_rising_edge() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF45, 10); // LYC
+
+        // LY matches LYC for a whole line, but that's one interrupt per frame.
+        mmu.wb(0xFF41, 0x40);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 1);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 1);
+
+        mmu.wb(0xFF41, 0x00);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 0);
+
+        // Enabling it while LY already matches is a rising edge too. Toggling the enable again
+        // makes another.
+        while mmu.ppu.line != 10 {
+            ppu.step(&mut mmu, 4);
+        }
+        mmu.interrupts.intf = 0;
+        mmu.wb(0xFF41, 0x40);
+        ppu.step(&mut mmu, 4);
+        assert_eq!(mmu.interrupts.intf & 0x02, 0x02);
+        mmu.interrupts.intf = 0;
+        ppu.step(&mut mmu, 4);
+        assert_eq!(mmu.interrupts.intf & 0x02, 0);
+        mmu.wb(0xFF41, 0x00);
+        ppu.step(&mut mmu, 4);
+        mmu.wb(0xFF41, 0x40);
+        ppu.step(&mut mmu, 4);
+        assert_eq!(mmu.interrupts.intf & 0x02, 0x02);
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF45, 10);
+
+        // One HBlank interrupt per visible line.
+        mmu.wb(0xFF41, 0x08);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 144);
+
+        // With LYC too, line 9's HBlank holds the line high into line 10, where LY == LYC keeps it
+        // high through line 10's HBlank. Neither of those can raise another interrupt.
+        mmu.wb(0xFF41, 0x48);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 143);
+
+        // VBlank follows line 143's HBlank without the line going low, so it adds nothing.
+        mmu.wb(0xFF41, 0x18);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 144);
+
+        // Each line's HBlank goes straight into the next line's mode 2, so only line 0's mode 2
+        // (after VBlank) interrupts.
+        mmu.wb(0xFF41, 0x28);
+        assert_eq!(count_stat_interrupts(&mut ppu, &mut mmu), 145);
+    }
+
+    /// How many cycles the PPU spends in mode 3 on the next line, stepping a cycle at a time.
+    fn mode3_length(ppu: &mut PPU, mmu: &mut MMU) -> usize {
+        while mmu.ppu.mode != PpuMode::OamScan {
+            ppu.step(mmu, 1);
+        }
+        while mmu.ppu.mode != PpuMode::Drawing {
+            ppu.step(mmu, 1);
+        }
+        let mut cycles = 0;
+        while mmu.ppu.mode == PpuMode::Drawing {
+            ppu.step(mmu, 1);
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF40, 0x93); // LCD, background and sprites on. The window is off.
+        for idx in 0..40 {
+            set_sprite(&mut mmu, idx, 0, -16); // Off the top of the screen.
+        }
+        assert_eq!(mode3_length(&mut ppu, &mut mmu), 172);
+
+        // Fine scrolling discards pixels from the first tile. Only SCX % 8 matters.
+        mmu.wb(0xFF43, 3);
+        assert_eq!(mode3_length(&mut ppu, &mut mmu), 175);
+        mmu.wb(0xFF43, 8);
+        assert_eq!(mode3_length(&mut ppu, &mut mmu), 172);
+
+        // Sprites on the line each stall it, unless they're hidden off the left edge.
+        mmu.wb(0xFF43, 0);
+        for idx in 0..2 {
+            set_sprite(&mut mmu, idx, 20, 0);
+        }
+        set_sprite(&mut mmu, 2, -8, 0);
+        while !ppu.step(&mut mmu, 4) {}
+        let mut lines = Vec::new();
+        for _ in 0..10 {
+            let length = mode3_length(&mut ppu, &mut mmu);
+            lines.push((mmu.ppu.line, length));
+        }
+        assert_eq!(lines[0], (0, 172 + 12));
+        assert!(lines[1..8].iter().all(|&(_, length)| length == 184));
+        assert_eq!(lines[8], (8, 172));
+
+        // Mode 0 is shorter to make up for it: the frame is the same length.
+        let mut cycles = 0;
+        while !ppu.step(&mut mmu, 4) {}
+        while !ppu.step(&mut mmu, 4) {
+            cycles += 4;
+        }
+        assert_eq!(cycles + 4, CYCLES_PER_FRAME);
+
+        // The window costs a stall too.
+        mmu.wb(0xFF40, 0xB3);
+        mmu.wb(0xFF4A, 0);
+        mmu.wb(0xFF4B, 7);
+        assert_eq!(mode3_length(&mut ppu, &mut mmu), 172 + 12 + 6);
+    }
+
+    #[test]
+    fn test_lcd_reenable() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+
+        // A background that's entirely colour 3, so drawn lines are easy to spot.
+        for address in 0x8000..0x8010 {
+            mmu.wb(address, 0xFF);
+        }
+
+        // Run into the middle of a frame, then turn the LCD off.
+        for _ in 0..(CYCLES_PER_LINE * 10 / 4) {
+            ppu.step(&mut mmu, 4);
+        }
+        mmu.wb(0xFF40, 0x11);
+        ppu.step(&mut mmu, 4);
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (0, PpuMode::HBlank));
+        assert!(ppu.image_buffer.iter().all(|&p| p == 0));
+
+        // While it's off, nothing happens.
+        mmu.interrupts.intf = 0;
+        for _ in 0..(CYCLES_PER_FRAME / 4) {
+            assert!(!ppu.step(&mut mmu, 4));
+        }
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (0, PpuMode::HBlank));
+        assert_eq!(mmu.interrupts.intf, 0);
+
+        // Turned back on: line 0 in mode 2, without a mode 2 STAT interrupt.
+        mmu.ppu.mode2_int_enable = true;
+        mmu.wb(0xFF40, 0x91);
+        ppu.step(&mut mmu, 4);
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (0, PpuMode::OamScan));
+        assert_eq!(mmu.interrupts.intf & 0x02, 0);
+
+        // Line 1's mode 2 does interrupt.
+        for _ in 0..(CYCLES_PER_LINE / 4) {
+            ppu.step(&mut mmu, 4);
+        }
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (1, PpuMode::OamScan));
+        assert_eq!(mmu.interrupts.intf & 0x02, 0x02);
+
+        // The first frame after turning on stays blank. The one after that is drawn.
+        while !ppu.step(&mut mmu, 4) {}
+        assert!(ppu.image_buffer.iter().all(|&p| p == 0));
+        while !ppu.step(&mut mmu, 4) {}
+        assert!(ppu.image_buffer.iter().all(|&p| p == 3));
+    }
+
+    #[test]
+    fn test_lcd_rapid_toggle() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        for address in 0x8000..0x8010 {
+            mmu.wb(address, 0xFF);
+        }
+        mmu.wb(0xFF40, 0x91);
+
+        // Toggle the LCD several times within a frame, sometimes without the PPU stepping between.
+        for _ in 0..(CYCLES_PER_LINE * 20 / 4) {
+            ppu.step(&mut mmu, 4);
+        }
+        for n in 0..5 {
+            mmu.wb(0xFF40, 0x11);
+            assert_eq!((mmu.rb(0xFF44), mmu.rb(0xFF41) & 0x03), (0, 0)); // LY and mode 0 at once.
+            if n % 2 == 0 {
+                ppu.step(&mut mmu, 4);
+            }
+            mmu.wb(0xFF40, 0x91);
+            for _ in 0..(CYCLES_PER_LINE * 3 / 4) {
+                ppu.step(&mut mmu, 4);
+            }
+        }
+
+        // On, then off again before the PPU sees it: it stays off and doesn't restart.
+        mmu.wb(0xFF40, 0x11);
+        mmu.wb(0xFF40, 0x91);
+        mmu.wb(0xFF40, 0x11);
+        ppu.step(&mut mmu, 4);
+        assert!(!mmu.ppu.restart && !mmu.ppu.clear_screen);
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (0, PpuMode::HBlank));
+        assert!(ppu.image_buffer.iter().all(|&p| p == 0));
+
+        // Turned on for good, it starts a normal frame. The first stays blank, the next is drawn.
+        mmu.wb(0xFF40, 0x91);
+        ppu.step(&mut mmu, 4);
+        assert_eq!((mmu.ppu.line, mmu.ppu.mode), (0, PpuMode::OamScan));
+        while !ppu.step(&mut mmu, 4) {}
+        assert!(ppu.image_buffer.iter().all(|&p| p == 0));
+        while !ppu.step(&mut mmu, 4) {}
+        assert!(ppu.image_buffer.iter().all(|&p| p == 3));
+    }
+
+    #[test]
+    fn test_render_line() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF40, 0x91); // LCD and background on, tile data at 0x8000, tilemap at 0x9800.
+        mmu.wb(0xFF47, 0xE4); // Identity background palette: shade n is colour n.
+
+        // Tile 1's first row is 3 3 1 1 2 2 0 0. Its other rows are blank.
+        mmu.wb(0x8010, 0b1111_0000);
+        mmu.wb(0x8011, 0b1100_1100);
+
+        // Put tile 1 at the second tile of the first row, and the first tile of the second row.
+        mmu.wb(0x9801, 1);
+        mmu.wb(0x9820, 1);
+
+        ppu.render_line(&mmu, 0);
+        assert_eq!(
+            ppu.image_buffer[0..16],
+            [0, 0, 0, 0, 0, 0, 0, 0, 3, 3, 1, 1, 2, 2, 0, 0]
+        );
+        assert!(ppu.image_buffer[16..160].iter().all(|&p| p == 0));
+
+        ppu.render_line(&mmu, 8);
+        let row = &ppu.image_buffer[8 * 160..9 * 160];
+        assert_eq!(row[0..8], [3, 3, 1, 1, 2, 2, 0, 0]);
+        assert!(row[8..].iter().all(|&p| p == 0));
+
+        // Scrolling shifts the same data along the line.
+        mmu.wb(0xFF43, 6);
+        ppu.render_line(&mmu, 0);
+        assert_eq!(ppu.image_buffer[0..6], [0, 0, 3, 3, 1, 1]);
+
+        // The state machine wasn't involved.
+        assert_eq!(mmu.ppu.line, 0);
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF40, 0xF1); // LCD, background and window on. Window tilemap at 0x9C00.
+        mmu.wb(0xFF47, 0xE4); // Identity background palette.
+        mmu.wb(0xFF4A, 0); // WY
+        mmu.wb(0xFF4B, 7); // WX: the window starts at the left edge.
+
+        // Tile 1's row n has just pixel n set, so the window row drawn is where the pixel is.
+        for row in 0..8 {
+            mmu.wb(0x8010 + row * 2, 0x80 >> row);
+        }
+        mmu.wb(0x9C00, 1);
+        let window_row = |ppu: &PPU, line: usize| {
+            ppu.image_buffer[line * 160..line * 160 + 8]
+                .iter()
+                .position(|&p| p == 1)
+        };
+
+        ppu.render_line(&mmu, 0);
+        ppu.render_line(&mmu, 1);
+        assert_eq!(window_row(&ppu, 0), Some(0));
+        assert_eq!(window_row(&ppu, 1), Some(1));
+
+        // Lines without the window don't advance its counter, eg. under a status bar.
+        mmu.wb(0xFF40, 0xD1);
+        ppu.render_line(&mmu, 2);
+        ppu.render_line(&mmu, 3);
+        assert_eq!(window_row(&ppu, 2), None);
+        assert_eq!(ppu.window_line_draw_count, 2);
+
+        mmu.wb(0xFF40, 0xF1);
+        ppu.render_line(&mmu, 4);
+        assert_eq!(window_row(&ppu, 4), Some(2)); // Not row 4.
+
+        // The window off the right of the screen isn't drawn either.
+        mmu.wb(0xFF4B, 167);
+        ppu.render_line(&mmu, 5);
+        assert_eq!(ppu.window_line_draw_count, 3);
+
+        // The counter starts again with every frame.
+        mmu.wb(0xFF4B, 7);
+        while !ppu.step(&mut mmu, 4) {}
+        assert_eq!(ppu.window_line_draw_count, 0);
+    }
+
+    #[test]
+    fn test_frame_stats() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.ppu.sprite_on = true;
+        mmu.ppu.window_on = true;
+        mmu.ppu.win_y = 100; // The window covers lines 100-143.
+        mmu.ppu.win_x = 7;
+
+        // (y, x) in OAM coordinates. The first two share lines 12-17. The last is off screen.
+        let sprites = [(26, 8), (28, 20), (66, 40), (0, 0)];
+        for (n, &(y, x)) in sprites.iter().enumerate() {
+            mmu.wb(0xFE00 + n as u16 * 4, y);
+            mmu.wb(0xFE01 + n as u16 * 4, x);
+        }
+        for n in sprites.len()..40 {
+            mmu.wb(0xFE00 + n as u16 * 4, 0); // Every other sprite is off screen too.
+        }
+
+        // The LCD was just turned on, so the first frame isn't drawn.
+        while !ppu.step(&mut mmu, 4) {}
+        assert_eq!(ppu.last_frame_stats(), FrameStats::default());
+        while !ppu.step(&mut mmu, 4) {}
+
+        let stats = ppu.last_frame_stats();
+        assert_eq!(stats.sprites, 3);
+        assert_eq!(stats.max_sprites_per_line, 2);
+        assert_eq!(stats.window_lines, 44);
+
+        // Every frame is counted afresh.
+        mmu.ppu.window_on = false;
+        while !ppu.step(&mut mmu, 4) {}
+        assert_eq!(ppu.last_frame_stats().sprites, 3);
+        assert_eq!(ppu.last_frame_stats().window_lines, 0);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF40, 0x93); // LCD, background and sprites on. Tile data at 0x8000.
+        mmu.wb(0xFF47, 0x00); // The background is all shade 0.
+        mmu.wb(0xFF48, 0xE4); // Identity sprite palette.
+
+        // Tile 0's first row is solid colour 3, tile 1's is solid colour 1.
+        mmu.wb(0x8000, 0xFF);
+        mmu.wb(0x8001, 0xFF);
+        mmu.wb(0x8010, 0xFF);
+
+        // Sprite 1 comes later in OAM, but it's further left, so it's on top where they overlap.
+        set_sprite(&mut mmu, 0, 4, 0);
+        mmu.wb(0xFE02, 1);
+        set_sprite(&mut mmu, 1, 0, 0);
+        ppu.render_line(&mmu, 0);
+        assert_eq!(
+            ppu.image_buffer[0..13],
+            [3, 3, 3, 3, 3, 3, 3, 3, 1, 1, 1, 1, 0]
+        );
+
+        // With the same X, the earlier sprite in OAM is on top.
+        set_sprite(&mut mmu, 1, 4, 0);
+        ppu.render_line(&mmu, 0);
+        assert_eq!(
+            ppu.image_buffer[0..13],
+            [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_force_sprites_off() {
+        let mut mmu = MMU::new(None, false);
+        let mut ppu = PPU::new();
+        mmu.wb(0xFF40, 0x93); // LCD, background and sprites on. Tile data at 0x8000.
+        mmu.wb(0xFF47, 0x00); // The background is all shade 0.
+        mmu.wb(0xFF48, 0xE4); // Identity sprite palette.
+
+        // Sprite 0 is tile 0, whose first row is solid colour 3.
+        mmu.wb(0x8000, 0xFF);
+        mmu.wb(0x8001, 0xFF);
+        set_sprite(&mut mmu, 0, 0, 0);
+
+        ppu.render_line(&mmu, 0);
+        assert_eq!(ppu.image_buffer[0..9], [3, 3, 3, 3, 3, 3, 3, 3, 0]);
+        assert_eq!(ppu.frame_stats.sprites, 1);
+
+        // The game still has sprites on, but they're not drawn or even searched for.
+        ppu.frame_stats = FrameStats::default();
+        ppu.sprites_drawn = 0;
+        ppu.set_lcdc_overrides(LcdcOverrides {
+            sprite_on: Some(false),
+            ..LcdcOverrides::default()
+        });
+        ppu.render_line(&mmu, 0);
+        assert!(mmu.ppu.sprite_on);
+        assert!(ppu.image_buffer[0..160].iter().all(|&p| p == 0));
+        assert_eq!(ppu.frame_stats.sprites, 0);
+    }
 }
@@ -7,10 +7,11 @@ use crate::emulator::{AUDIO_BUFFER, AUDIO_FREQ};
 
 pub struct Audio {
     player: AudioQueue<f32>,
+    volume: f32, // Master volume multiplier applied to every sample.
 }
 
 impl Audio {
-    pub fn new(context: &sdl2::Sdl) -> Result<Self, String> {
+    pub fn new(context: &sdl2::Sdl, volume: f32) -> Result<Self, String> {
         let audio = context.audio()?;
         let spec = AudioSpecDesired {
             freq: Some(AUDIO_FREQ as i32),
@@ -21,15 +22,105 @@ impl Audio {
         let player = audio.open_queue::<f32, _>(None, &spec)?;
         player.resume();
 
-        Ok(Self { player })
+        Ok(Self { player, volume })
     }
 
     pub fn enqueue(&self, sample: [f32; 2]) {
-        self.player.queue(&sample);
+        self.player
+            .queue(&[sample[0] * self.volume, sample[1] * self.volume]);
 
         // TODO: A better approach to "catching up".
         if self.player.size() > 20_000 {
             self.player.clear();
         }
     }
+
+    /// Let whatever is still queued finish playing, then stop. Gives up after a short while
+    /// rather than holding up exiting.
+    pub fn flush(&self) {
+        for _ in 0..20 {
+            if self.player.size() == 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        self.player.pause();
+    }
+}
+
+// Overlap-add window length and the spacing of windows in the output. Half-overlapping Hann
+// windows sum to a near-constant gain, which is normalised out anyway.
+const STRETCH_WINDOW: usize = 256;
+const STRETCH_HOP: usize = STRETCH_WINDOW / 2;
+
+/// Shorten audio by `factor` by simply dropping samples. This is what happens naturally when
+/// audio is played back faster: the pitch goes up with the speed.
+pub fn speed_up(samples: &[f32], factor: usize) -> Vec<f32> {
+    samples.iter().step_by(factor.max(1)).cloned().collect()
+}
+
+/// Shorten (factor > 1) or lengthen (factor < 1) audio by `factor` while preserving its pitch,
+/// using a simple overlap-add: windows of the input are taken `factor` times further apart than
+/// they are placed in the output, so the waveform inside each window plays at its original rate.
+/// The output is always `samples.len() / factor` long (rounded).
+pub fn time_stretch(samples: &[f32], factor: f64) -> Vec<f32> {
+    let out_len = (samples.len() as f64 / factor).round() as usize;
+    let mut output = vec![0.0; out_len];
+    let mut weights = vec![0.0; out_len];
+
+    let window: Vec<f32> = (0..STRETCH_WINDOW)
+        .map(|n| {
+            let phase = n as f32 / STRETCH_WINDOW as f32;
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * phase).cos()
+        })
+        .collect();
+
+    let mut out_start = 0;
+    while out_start < out_len {
+        let in_start = (out_start as f64 * factor).round() as usize;
+
+        for (n, w) in window.iter().enumerate() {
+            let (o, i) = (out_start + n, in_start + n);
+            if o >= out_len || i >= samples.len() {
+                break;
+            }
+            output[o] += samples[i] * w;
+            weights[o] += w;
+        }
+
+        out_start += STRETCH_HOP;
+    }
+
+    // Undo the gain of the overlapping windows.
+    for (sample, weight) in output.iter_mut().zip(weights.iter()) {
+        if *weight > 1e-3 {
+            *sample /= weight;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_stretch_length() {
+        let samples: Vec<f32> = (0..3200).map(|n| (n as f32 * 0.1).sin()).collect();
+        assert_eq!(time_stretch(&samples, 4.0).len(), 800);
+        assert_eq!(time_stretch(&samples, 2.0).len(), 1600);
+        assert_eq!(time_stretch(&samples, 1.5).len(), 2133);
+        assert_eq!(time_stretch(&samples, 0.5).len(), 6400);
+        assert_eq!(speed_up(&samples, 4).len(), 800);
+    }
+
+    #[test]
+    fn test_time_stretch_preserves_level() {
+        // A constant signal should come out at the same level, aside from the first few samples
+        // where the first window fades in.
+        let samples = vec![0.5; 4000];
+        let output = time_stretch(&samples, 3.0);
+        assert!(output[8..].iter().all(|s| (s - 0.5).abs() < 1e-4));
+    }
 }
@@ -0,0 +1,161 @@
+use std::time::Instant;
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+
+/// A tiny 3x5 pixel font. Each glyph is 5 rows, with the 3 pixels of each row in bits 2-0 (bit 2
+/// is the leftmost pixel). Only the characters the HUD needs are defined.
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        _ => [0; 5], // Unknown characters (and spaces) are blank.
+    }
+}
+
+/// Draw text into a framebuffer of palette indices (0-3) with its top-left corner at x, y. Each
+/// character is 3 pixels wide plus 1 pixel of spacing. Only the "on" pixels of each glyph are
+/// written, using `color`. Anything that falls off the edge of the buffer is clipped.
+pub fn draw_text(buffer: &mut [u8; WIDTH * HEIGHT], x: usize, y: usize, text: &str, color: u8) {
+    for (n, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..3 {
+                let px = x + n * 4 + col;
+                let py = y + row;
+
+                if bits & (0b100 >> col) != 0 && px < WIDTH && py < HEIGHT {
+                    buffer[py * WIDTH + px] = color;
+                }
+            }
+        }
+    }
+}
+
+/// Fill a rectangle of the framebuffer with a single colour. Clipped to the buffer.
+fn fill_rect(buffer: &mut [u8; WIDTH * HEIGHT], x: usize, y: usize, w: usize, h: usize, color: u8) {
+    for py in y..(y + h).min(HEIGHT) {
+        for px in x..(x + w).min(WIDTH) {
+            buffer[py * WIDTH + px] = color;
+        }
+    }
+}
+
+/// A debug overlay drawn into the top-left corner of the frame, showing the frames per second,
+/// the current PPU mode, and the cartridge's current ROM bank.
+pub struct Hud {
+    pub enabled: bool,
+    fps: usize,
+    frame_count: usize,
+    last_tick: Instant,
+}
+
+impl Hud {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            fps: 0,
+            frame_count: 0,
+            last_tick: Instant::now(),
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    /// Count a presented frame. Once a second has passed, the count becomes the displayed FPS.
+    pub fn tick(&mut self) {
+        self.frame_count += 1;
+
+        if self.last_tick.elapsed().as_secs_f64() >= 1.0 {
+            self.fps = self.frame_count;
+            self.frame_count = 0;
+            self.last_tick = Instant::now();
+        }
+    }
+
+    /// Draw the overlay onto a copy of the frame. Darkest colour text on the lightest background.
+    pub fn draw(&self, buffer: &mut [u8; WIDTH * HEIGHT], ppu_mode: u8, rom_bank: u16) {
+        let lines = [
+            format!("FPS {}", self.fps),
+            format!("M {}", ppu_mode),
+            format!("B {}", rom_bank),
+        ];
+
+        fill_rect(buffer, 0, 0, 4 * 7 + 1, lines.len() * 6 + 1, 0);
+        for (n, line) in lines.iter().enumerate() {
+            draw_text(buffer, 1, 1 + n * 6, line, 3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read a rectangle of the buffer back as rows of '#' (any non-zero pixel) and '.'.
+    fn read_region(
+        buffer: &[u8; WIDTH * HEIGHT],
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    ) -> Vec<String> {
+        (y..y + h)
+            .map(|py| {
+                (x..x + w)
+                    .map(|px| {
+                        if buffer[py * WIDTH + px] != 0 {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut buffer = [0; WIDTH * HEIGHT];
+        draw_text(&mut buffer, 10, 20, "42", 3);
+
+        assert_eq!(
+            read_region(&buffer, 10, 20, 8, 5),
+            vec![
+                "#.#.###.", //
+                "#.#...#.", //
+                "###.###.", //
+                "..#.#...", //
+                "..#.###.", //
+            ]
+        );
+
+        // Nothing is drawn outside the text's region.
+        assert_eq!(buffer.iter().filter(|&&p| p != 0).count(), 9 + 11);
+        assert_eq!(buffer[20 * WIDTH + 10], 3);
+    }
+
+    #[test]
+    fn test_draw_text_clips() {
+        let mut buffer = [0; WIDTH * HEIGHT];
+        draw_text(&mut buffer, 158, 142, "88", 3); // Should not panic.
+        assert_eq!(buffer[142 * WIDTH + 158], 3);
+    }
+}
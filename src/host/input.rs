@@ -2,33 +2,40 @@ use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Scancode};
 use sdl2::EventPump;
 
+use crate::config::KeyBindings;
+
 #[derive(PartialEq)]
 pub enum InputEvent {
     None,
     Exit,
     Panic,
+    ToggleHud,
+    CyclePalette,
+    Screenshot,
+    Continue,
+    Step,
 }
 
 pub struct Input {
     event_pump: EventPump,
+    key_bindings: [Scancode; 8], // [Right, Left, Up, Down, A, B, Select, Start]
 }
 
-const KEY_BINDINGS: [Scancode; 8] = [
-    Scancode::Right, // Right
-    Scancode::Left,  // Left
-    Scancode::Up,    // Up
-    Scancode::Down,  // Down
-    Scancode::A,     // A
-    Scancode::S,     // B
-    Scancode::X,     // Select
-    Scancode::Z,     // Start
-];
-
 impl Input {
-    pub fn new(context: &sdl2::Sdl) -> Result<Self, String> {
+    pub fn new(context: &sdl2::Sdl, key_bindings: &KeyBindings) -> Result<Self, String> {
         let event_pump = context.event_pump()?;
 
-        Ok(Self { event_pump })
+        // Resolve each configured key name to an SDL scancode.
+        let mut scancodes = [Scancode::Right; 8];
+        for (n, name) in key_bindings.as_array().iter().enumerate() {
+            scancodes[n] = Scancode::from_name(name)
+                .ok_or_else(|| format!("Unknown key in key bindings: {}", name))?;
+        }
+
+        Ok(Self {
+            event_pump,
+            key_bindings: scancodes,
+        })
     }
 
     /// Return a single, highest priority event.
@@ -48,6 +55,26 @@ impl Input {
                     keycode: Some(Keycode::Space),
                     ..
                 } => InputEvent::Panic,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => InputEvent::ToggleHud,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => InputEvent::CyclePalette,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => InputEvent::Screenshot,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => InputEvent::Continue,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => InputEvent::Step,
                 Event::KeyDown { .. } => InputEvent::None,
                 _ => InputEvent::None,
             };
@@ -60,6 +87,13 @@ impl Input {
         return x;
     }
 
+    /// Is the fast-forward key (Tab) being held down?
+    pub fn is_turbo_held(&self) -> bool {
+        self.event_pump
+            .keyboard_state()
+            .is_scancode_pressed(Scancode::Tab)
+    }
+
     /// Return an array of key states. true = pressed.
     pub fn get_gamepad_state(&self) -> [bool; 8] {
         let keys: Vec<Scancode> = self
@@ -68,9 +102,9 @@ impl Input {
             .pressed_scancodes()
             .collect();
 
-        // Hard coded binding of keyboard to keys.  We use the left 16 keys in the same grid pattern
-        // which means none of the letters/numbers align, but the shape does.
-        let key_states = KEY_BINDINGS
+        // Map the configured key bindings to their pressed state.
+        let key_states = self
+            .key_bindings
             .iter()
             .map(|b| keys.contains(b))
             .collect::<Vec<bool>>();
@@ -1,7 +1,11 @@
 mod audio;
+mod hud;
 mod input;
+mod palette;
 mod screen;
 
-pub use audio::Audio;
+pub use audio::{speed_up, time_stretch, Audio};
+pub use hud::Hud;
 pub use input::{Input, InputEvent};
+pub use palette::{preset as palette_preset, PaletteMode};
 pub use screen::Screen;
@@ -0,0 +1,113 @@
+use crate::config::DEFAULT_PALETTE;
+
+/// How the four DMG shades are turned into colours on screen. Cycled at runtime with F2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteMode {
+    Configured,   // The palette from the config (green by default).
+    Grayscale,    // Evenly spaced grays, with adjustable contrast.
+    HighContrast, // Only black and white.
+}
+
+impl PaletteMode {
+    pub fn next(self) -> Self {
+        match self {
+            PaletteMode::Configured => PaletteMode::Grayscale,
+            PaletteMode::Grayscale => PaletteMode::HighContrast,
+            PaletteMode::HighContrast => PaletteMode::Configured,
+        }
+    }
+
+    /// The palette to draw with in this mode.
+    pub fn palette(
+        self,
+        configured: [(u8, u8, u8); 4],
+        contrast: f32,
+        threshold: u8,
+    ) -> [(u8, u8, u8); 4] {
+        match self {
+            PaletteMode::Configured => configured,
+            PaletteMode::Grayscale => grayscale(contrast),
+            PaletteMode::HighContrast => high_contrast(threshold),
+        }
+    }
+}
+
+/// One of the built-in palettes, by name: "green" (the default), "grayscale" or "high-contrast".
+/// The last two are what the grayscale and high contrast modes draw with, at the given contrast
+/// and threshold.
+pub fn preset(name: &str, contrast: f32, threshold: u8) -> Result<[(u8, u8, u8); 4], String> {
+    match name {
+        "green" => Ok(DEFAULT_PALETTE),
+        "grayscale" => Ok(grayscale(contrast)),
+        "high-contrast" => Ok(high_contrast(threshold)),
+        _ => Err(format!(
+            "Unknown palette: {}. Expected green, grayscale or high-contrast.",
+            name
+        )),
+    }
+}
+
+/// Four evenly spaced grays from white to black. A contrast of 1.0 uses the full range; lower
+/// values pull the shades toward mid-gray and higher values push them apart (clamped to the range).
+pub fn grayscale(contrast: f32) -> [(u8, u8, u8); 4] {
+    let mut palette = [(0, 0, 0); 4];
+
+    for (shade, colour) in palette.iter_mut().enumerate() {
+        let gray = 255.0 - shade as f32 * 85.0;
+        let value = (127.5 + (gray - 127.5) * contrast)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+        *colour = (value, value, value);
+    }
+
+    palette
+}
+
+/// Black and white only: shades below the threshold are white, the rest are black.
+pub fn high_contrast(threshold: u8) -> [(u8, u8, u8); 4] {
+    let mut palette = [(0, 0, 0); 4];
+
+    for (shade, colour) in palette.iter_mut().enumerate() {
+        if (shade as u8) < threshold {
+            *colour = (255, 255, 255);
+        }
+    }
+
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grayscale() {
+        assert_eq!(
+            grayscale(1.0),
+            [(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)]
+        );
+
+        // Half contrast squeezes the shades toward the middle, but keeps them evenly spaced.
+        let palette = grayscale(0.5);
+        assert_eq!(palette[0].0, 191);
+        assert_eq!(palette[3].0, 64);
+    }
+
+    #[test]
+    fn test_preset() {
+        assert_eq!(preset("green", 1.0, 2), Ok(DEFAULT_PALETTE));
+        assert_eq!(preset("grayscale", 0.5, 2), Ok(grayscale(0.5)));
+        assert_eq!(preset("high-contrast", 1.0, 3), Ok(high_contrast(3)));
+        assert!(preset("purple", 1.0, 2).is_err());
+    }
+
+    #[test]
+    fn test_high_contrast() {
+        let white = (255, 255, 255);
+        let black = (0, 0, 0);
+
+        assert_eq!(high_contrast(2), [white, white, black, black]);
+        assert_eq!(high_contrast(1), [white, black, black, black]);
+        assert_eq!(high_contrast(3), [white, white, white, black]);
+    }
+}
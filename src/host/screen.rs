@@ -1,19 +1,22 @@
+use image::ColorType;
 use sdl2;
 
 pub struct Screen {
     sdl_canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    palette: [(u8, u8, u8); 4], // RGB for each of the four shades, lightest first.
+    upscale: bool,              // Draw each frame at 2x before SDL scales it to the window.
 }
 
 impl Screen {
     const DMG_WIDTH: usize = 160;
     const DMG_HEIGHT: usize = 144;
 
-    const PALETTE_HIGH: (u8, u8, u8) = (155, 188, 15); // #9bbc0f
-    const PALETTE_MED: (u8, u8, u8) = (139, 172, 15); // #8bac0f
-    const PALETTE_LOW: (u8, u8, u8) = (48, 98, 48); // #306230
-    const PALETTE_OFF: (u8, u8, u8) = (15, 56, 15); // #0f380f
-
-    pub fn new(context: &sdl2::Sdl, scale_factor: usize) -> Result<Self, String> {
+    pub fn new(
+        context: &sdl2::Sdl,
+        scale_factor: usize,
+        palette: [(u8, u8, u8); 4],
+        upscale: bool,
+    ) -> Result<Self, String> {
         let video_subsys = context.video()?;
 
         let window = video_subsys
@@ -33,28 +36,37 @@ impl Screen {
             .build()
             .map_err(|e| e.to_string())?;
 
-        Ok(Self { sdl_canvas: canvas })
+        Ok(Self {
+            sdl_canvas: canvas,
+            palette,
+            upscale,
+        })
+    }
+
+    /// Change the colours used for the four shades from the next update onward.
+    pub fn set_palette(&mut self, palette: [(u8, u8, u8); 4]) {
+        self.palette = palette;
+    }
+
+    /// Save a frame to a PNG at the Game Boy's own resolution, in the current palette.
+    pub fn save_screenshot(
+        &self,
+        buffer: &[u8; Self::DMG_WIDTH * Self::DMG_HEIGHT],
+        path: &str,
+    ) -> Result<(), String> {
+        save_png(buffer, Self::DMG_WIDTH, &self.palette, path)
     }
 
     /// Update the screen using a buffer of pixel values.
     /// Given the DMG-01 has only four possible colours, the pixel values will be 0-3.
-    pub fn update(&mut self, &buffer: &[u8; Self::DMG_WIDTH * Self::DMG_HEIGHT]) {
-        let mut texture_data = [0u8; Self::DMG_WIDTH * Self::DMG_HEIGHT * 3];
-
-        for (index, pixel) in buffer.iter().enumerate() {
-            let (r, g, b) = match pixel {
-                0 => Self::PALETTE_HIGH,
-                1 => Self::PALETTE_MED,
-                2 => Self::PALETTE_LOW,
-                3 => Self::PALETTE_OFF,
-                _ => panic!("Passed a non-valid value to Screen.update: {}", pixel),
-            };
-
-            // Populate the texture data's R,G,B.
-            texture_data[index * 3] = r;
-            texture_data[index * 3 + 1] = g;
-            texture_data[index * 3 + 2] = b;
-        }
+    pub fn update(&mut self, buffer: &[u8; Self::DMG_WIDTH * Self::DMG_HEIGHT]) {
+        let (pixels, width, height) = if self.upscale {
+            let pixels = upscale_2x(buffer, Self::DMG_WIDTH);
+            (pixels, Self::DMG_WIDTH * 2, Self::DMG_HEIGHT * 2)
+        } else {
+            (buffer.to_vec(), Self::DMG_WIDTH, Self::DMG_HEIGHT)
+        };
+        let texture_data = to_rgb(&pixels, &self.palette);
 
         // Create the texture.
         let creator = self.sdl_canvas.texture_creator();
@@ -62,16 +74,122 @@ impl Screen {
             .create_texture(
                 sdl2::pixels::PixelFormatEnum::RGB24,
                 sdl2::render::TextureAccess::Static,
-                Self::DMG_WIDTH as u32,
-                Self::DMG_HEIGHT as u32,
+                width as u32,
+                height as u32,
             )
             .unwrap();
 
-        texture
-            .update(None, &texture_data, Self::DMG_WIDTH * 3)
-            .unwrap();
+        texture.update(None, &texture_data, width * 3).unwrap();
 
         self.sdl_canvas.copy(&texture, None, None).unwrap();
         self.sdl_canvas.present();
     }
 }
+
+/// Nearest-neighbour upscale of a buffer of pixels `width` wide: every pixel becomes a 2x2 block.
+/// SDL's own scaling to the window can smear pixels unevenly at non-integer sizes. Starting from a
+/// bigger image keeps that smearing to a thinner edge.
+pub fn upscale_2x(buffer: &[u8], width: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(buffer.len() * 4);
+
+    for row in buffer.chunks(width) {
+        let mut doubled = Vec::with_capacity(width * 2);
+        for pixel in row {
+            doubled.push(*pixel);
+            doubled.push(*pixel);
+        }
+        output.extend_from_slice(&doubled);
+        output.extend_from_slice(&doubled);
+    }
+
+    output
+}
+
+/// Turn a buffer of shades (0-3) into RGB24 data, 3 bytes per pixel, using `palette`.
+pub fn to_rgb(buffer: &[u8], palette: &[(u8, u8, u8); 4]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(buffer.len() * 3);
+
+    for pixel in buffer {
+        let (r, g, b) = match pixel {
+            0..=3 => palette[*pixel as usize],
+            _ => panic!("Passed a non-valid shade to to_rgb: {}", pixel),
+        };
+        rgb.extend_from_slice(&[r, g, b]);
+    }
+
+    rgb
+}
+
+/// Write a buffer of shades `width` pixels wide to a PNG, using `palette`.
+pub fn save_png(
+    buffer: &[u8],
+    width: usize,
+    palette: &[(u8, u8, u8); 4],
+    path: &str,
+) -> Result<(), String> {
+    image::save_buffer(
+        path,
+        &to_rgb(buffer, palette),
+        width as u32,
+        (buffer.len() / width) as u32,
+        ColorType::Rgb8,
+    )
+    .map_err(|e| format!("Could not save screenshot to {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_PALETTE;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_upscale_2x() {
+        let buffer = [0, 1, 2, 3, 2, 1];
+        let expected = [
+            0, 0, 1, 1, 2, 2, //
+            0, 0, 1, 1, 2, 2, //
+            3, 3, 2, 2, 1, 1, //
+            3, 3, 2, 2, 1, 1, //
+        ];
+        assert_eq!(upscale_2x(&buffer, 3), expected);
+    }
+
+    #[test]
+    fn test_to_rgb() {
+        let palette = [(1, 2, 3), (4, 5, 6), (7, 8, 9), (10, 11, 12)];
+        let rgb = to_rgb(&[3, 0, 2, 1, 0], &palette);
+        assert_eq!(rgb, [10, 11, 12, 1, 2, 3, 7, 8, 9, 4, 5, 6, 1, 2, 3]);
+
+        for shade in 0..4 {
+            let (r, g, b) = palette[shade];
+            assert_eq!(to_rgb(&[shade as u8], &palette), [r, g, b]);
+        }
+    }
+
+    #[test]
+    fn test_save_png() {
+        // Diagonal stripes: each pixel's shade is (x + y) % 4.
+        let buffer: Vec<u8> = (0..160 * 144)
+            .map(|n| ((n % 160 + n / 160) % 4) as u8)
+            .collect();
+        let rgb = to_rgb(&buffer, &DEFAULT_PALETTE);
+        assert_eq!(rgb.len(), 160 * 144 * 3);
+        assert_eq!(rgb[0..3], [155, 188, 15]);
+        assert_eq!(rgb[3..6], [139, 172, 15]);
+        assert_eq!(rgb[160 * 3..160 * 3 + 3], [139, 172, 15]);
+
+        let path = env::temp_dir().join("gameboy_test_screenshot.png");
+        let path = path.to_str().unwrap();
+        save_png(&buffer, 160, &DEFAULT_PALETTE, path).unwrap();
+
+        let image = image::open(path).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (160, 144));
+        assert_eq!(image.get_pixel(0, 0).0, [155, 188, 15]);
+        assert_eq!(image.get_pixel(2, 0).0, [48, 98, 48]);
+        assert_eq!(image.get_pixel(1, 2).0, [15, 56, 15]);
+        assert_eq!(image.get_pixel(159, 143).0, [48, 98, 48]);
+        fs::remove_file(path).unwrap();
+    }
+}
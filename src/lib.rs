@@ -0,0 +1,7 @@
+//! A DMG-01 Game Boy emulator. The `gameboy` binary is a thin wrapper around `Emulator`; the
+//! library is exposed so that other tools (see `examples/`) can drive the emulator directly.
+pub mod config;
+pub mod emulator;
+mod guest;
+mod host;
+pub mod options;
@@ -1,13 +1,23 @@
-mod emulator;
-mod guest;
-mod host;
-use emulator::Emulator;
+use gameboy::config::{Config, DEFAULT_CONFIG_PATH};
+use gameboy::emulator::{AccessStats, Emulator, RegionCounts};
+use gameboy::options::EmulatorOptions;
+use std::cmp::Reverse;
 use std::env;
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
     let cartridge_path = if args.len() > 1 { Some(&args[1]) } else { None };
     let skip_boot_rom = args.contains(&String::from("--noboot"));
+    let profile = args.contains(&String::from("--profile"));
+    let profile_memory = args.contains(&String::from("--profile-memory"));
+    let log_ram_banks = args.contains(&String::from("--log-ram-banks"));
+    let patch_boot_logo = args.contains(&String::from("--patch-logo"));
+    let print_summary = args.contains(&String::from("--summary"));
+    let debug_opcodes = args.contains(&String::from("--debug-opcodes"));
+    let trace_serial = args.contains(&String::from("--trace-serial"));
+    let mute_boot_rom = args.contains(&String::from("--mute-boot"));
+    let upscale = args.contains(&String::from("--upscale"));
+    let idle_sleep = args.contains(&String::from("--idle-sleep"));
 
     if skip_boot_rom {
         println!("Skipping boot ROM and directly initializing emulator state.");
@@ -15,6 +25,96 @@ pub fn main() {
 
     println!("{}", cartridge_path.unwrap());
 
-    let mut emulator = Emulator::new(cartridge_path, !skip_boot_rom).unwrap();
+    // Optional config file: `--config path/to/config.toml`. Defaults are used if it's absent.
+    let config_path = match args.iter().position(|a| a == "--config") {
+        Some(n) if n + 1 < args.len() => args[n + 1].as_str(),
+        _ => DEFAULT_CONFIG_PATH,
+    };
+    let config = Config::load(config_path).unwrap();
+
+    let mut options = EmulatorOptions::default()
+        .config(config)
+        .use_bootrom(!skip_boot_rom)
+        .profile_opcodes(profile)
+        .profile_memory(profile_memory)
+        .log_ram_banks(log_ram_banks)
+        .patch_boot_logo(patch_boot_logo)
+        .pause_on_unknown_opcode(debug_opcodes)
+        .trace_serial(trace_serial)
+        .mute_boot_rom(mute_boot_rom)
+        .upscale(upscale)
+        .idle_sleep(idle_sleep);
+    if let Some(path) = cartridge_path {
+        options = options.rom_path(path);
+    }
+    if let Some(n) = args.iter().position(|a| a == "--save-dir") {
+        if n + 1 < args.len() {
+            options = options.save_dir(&args[n + 1]);
+        }
+    }
+    if let Some(n) = args.iter().position(|a| a == "--palette") {
+        if n + 1 < args.len() {
+            options = match options.palette_preset(&args[n + 1]) {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+        }
+    }
+    if let Some(n) = args.iter().position(|a| a == "--patch") {
+        if n + 1 < args.len() {
+            options = options.patch_path(&args[n + 1]);
+        }
+    }
+
+    let mut emulator = Emulator::new(options).unwrap();
     emulator.run_forever();
+
+    if profile {
+        print_opcode_histogram(&emulator.opcode_histogram());
+    }
+
+    if profile_memory {
+        print_access_stats(&emulator.access_stats());
+    }
+
+    if print_summary {
+        let summary = emulator.summary();
+        println!("Instructions: {}", summary.instructions);
+        println!("Frames: {}", summary.frames);
+        println!("Serial output:\n{}", summary.serial_output);
+    }
+}
+
+/// Print the most frequently executed opcodes, most common first.
+fn print_opcode_histogram(histogram: &[u64; 256]) {
+    let mut counts: Vec<(usize, u64)> = histogram.iter().cloned().enumerate().collect();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+
+    let total: u64 = histogram.iter().sum();
+    println!("Executed {} opcodes. Most frequent:", total);
+    for (opcode, count) in counts.iter().take(20).filter(|(_, count)| *count > 0) {
+        println!("  {:#04x}: {}", opcode, count);
+    }
+}
+
+/// Print how many reads and writes went to each region of memory.
+fn print_access_stats(stats: &AccessStats) {
+    let print_counts = |name: &str, counts: &RegionCounts| {
+        println!(
+            "{}: ROM {}, VRAM {}, cartridge RAM {}, WRAM {}, OAM {}, IO {}, HRAM {}",
+            name,
+            counts.rom,
+            counts.vram,
+            counts.cartridge_ram,
+            counts.wram,
+            counts.oam,
+            counts.io,
+            counts.hram
+        );
+    };
+    print_counts("Reads", &stats.reads);
+    print_counts("Writes", &stats.writes);
 }
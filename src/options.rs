@@ -0,0 +1,298 @@
+use crate::config::{AudioOverflow, Config, KeyBindings, Socd};
+use crate::host::palette_preset;
+
+/// The hardware model being emulated. Only the original DMG-01 is supported for now.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg,
+}
+
+/// Everything needed to construct an `Emulator`. Start from `EmulatorOptions::default()` and chain
+/// the builder methods to change what's needed:
+/// ```
+/// # use gameboy::options::EmulatorOptions;
+/// let options = EmulatorOptions::default()
+///     .rom_path("tetris.gb")
+///     .use_bootrom(false)
+///     .headless(true);
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct EmulatorOptions {
+    pub rom_path: Option<String>,
+    pub patch_path: Option<String>, // An IPS patch to apply to the ROM as it's loaded.
+    pub use_bootrom: bool,
+    pub boot_rom_path: String,
+    pub model: Model,
+    pub scale: usize,
+    pub upscale: bool, // Draw frames at 2x internal resolution for a crisper scaled image.
+    pub palette: [(u8, u8, u8); 4],
+    pub contrast: f32,               // Contrast of the grayscale palette mode.
+    pub high_contrast_threshold: u8, // Shades below this are white in high contrast mode.
+    pub volume: f32,
+    pub audio_buffer_cap: usize, // Most samples the APU buffers before `audio_overflow` applies.
+    pub audio_overflow: AudioOverflow,
+    pub key_bindings: KeyBindings,
+    pub socd: Socd, // How to resolve opposing d-pad directions held together.
+    pub strict_opcodes: bool, // Panic on unimplemented opcodes rather than skipping them.
+    pub pause_on_unknown_opcode: bool, // Pause and dump the CPU state instead. Overrides strict.
+    pub headless: bool, // Run without any SDL window, audio or input.
+    pub profile_opcodes: bool, // Count how many times each opcode executes.
+    pub profile_memory: bool, // Count reads and writes to each region of memory.
+    pub turbo_preserve_pitch: bool, // Time-stretch audio while fast-forwarding, keeping its pitch.
+    pub log_ram_banks: bool, // Print every cartridge RAM bank switch.
+    pub patch_boot_logo: bool, // Let the boot ROM pass cartridges with an invalid logo.
+    pub trace_serial: bool, // Print every byte sent over the serial port to stdout.
+    pub mute_boot_rom: bool, // Run the boot ROM, but without the chime.
+    pub idle_sleep: bool, // Sleep out frames the game spends halted, waiting for VBlank.
+    pub save_dir: Option<String>, // Where .sav files go. `None` puts them next to the ROM.
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        let config = Config::default();
+
+        Self {
+            rom_path: None,
+            patch_path: None,
+            use_bootrom: true,
+            boot_rom_path: config.boot_rom_path,
+            model: Model::Dmg,
+            scale: config.scale,
+            upscale: false,
+            palette: config.palette,
+            contrast: config.contrast,
+            high_contrast_threshold: config.high_contrast_threshold,
+            volume: config.volume,
+            audio_buffer_cap: config.audio_buffer_cap,
+            audio_overflow: config.audio_overflow,
+            key_bindings: config.keys,
+            socd: config.socd,
+            strict_opcodes: true,
+            pause_on_unknown_opcode: false,
+            headless: false,
+            profile_opcodes: false,
+            profile_memory: false,
+            turbo_preserve_pitch: config.turbo_preserve_pitch,
+            log_ram_banks: false,
+            patch_boot_logo: false,
+            trace_serial: false,
+            mute_boot_rom: false,
+            idle_sleep: false,
+            save_dir: config.save_dir,
+        }
+    }
+}
+
+impl EmulatorOptions {
+    /// Apply the host settings from a user config file.
+    pub fn config(mut self, config: Config) -> Self {
+        self.boot_rom_path = config.boot_rom_path;
+        self.scale = config.scale;
+        self.palette = config.palette;
+        self.contrast = config.contrast;
+        self.high_contrast_threshold = config.high_contrast_threshold;
+        self.volume = config.volume;
+        self.audio_buffer_cap = config.audio_buffer_cap;
+        self.audio_overflow = config.audio_overflow;
+        self.key_bindings = config.keys;
+        self.socd = config.socd;
+        self.turbo_preserve_pitch = config.turbo_preserve_pitch;
+        self.save_dir = config.save_dir;
+        self
+    }
+
+    pub fn rom_path(mut self, path: &str) -> Self {
+        self.rom_path = Some(String::from(path));
+        self
+    }
+
+    pub fn patch_path(mut self, path: &str) -> Self {
+        self.patch_path = Some(String::from(path));
+        self
+    }
+
+    pub fn use_bootrom(mut self, use_bootrom: bool) -> Self {
+        self.use_bootrom = use_bootrom;
+        self
+    }
+
+    pub fn boot_rom_path(mut self, path: &str) -> Self {
+        self.boot_rom_path = String::from(path);
+        self
+    }
+
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn scale(mut self, scale: usize) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn upscale(mut self, upscale: bool) -> Self {
+        self.upscale = upscale;
+        self
+    }
+
+    pub fn palette(mut self, palette: [(u8, u8, u8); 4]) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Use one of the built-in palettes, by name: "green", "grayscale" or "high-contrast". The
+    /// last two use the contrast and threshold set so far, so set those first.
+    pub fn palette_preset(mut self, name: &str) -> Result<Self, String> {
+        self.palette = palette_preset(name, self.contrast, self.high_contrast_threshold)?;
+        Ok(self)
+    }
+
+    pub fn contrast(mut self, contrast: f32) -> Self {
+        self.contrast = contrast;
+        self
+    }
+
+    pub fn high_contrast_threshold(mut self, threshold: u8) -> Self {
+        self.high_contrast_threshold = threshold;
+        self
+    }
+
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn socd(mut self, socd: Socd) -> Self {
+        self.socd = socd;
+        self
+    }
+
+    pub fn strict_opcodes(mut self, strict: bool) -> Self {
+        self.strict_opcodes = strict;
+        self
+    }
+
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    pub fn turbo_preserve_pitch(mut self, preserve: bool) -> Self {
+        self.turbo_preserve_pitch = preserve;
+        self
+    }
+
+    pub fn log_ram_banks(mut self, log: bool) -> Self {
+        self.log_ram_banks = log;
+        self
+    }
+
+    pub fn profile_opcodes(mut self, profile: bool) -> Self {
+        self.profile_opcodes = profile;
+        self
+    }
+
+    pub fn profile_memory(mut self, profile: bool) -> Self {
+        self.profile_memory = profile;
+        self
+    }
+
+    pub fn save_dir(mut self, path: &str) -> Self {
+        self.save_dir = Some(String::from(path));
+        self
+    }
+
+    pub fn pause_on_unknown_opcode(mut self, pause: bool) -> Self {
+        self.pause_on_unknown_opcode = pause;
+        self
+    }
+
+    pub fn patch_boot_logo(mut self, patch: bool) -> Self {
+        self.patch_boot_logo = patch;
+        self
+    }
+
+    pub fn trace_serial(mut self, trace: bool) -> Self {
+        self.trace_serial = trace;
+        self
+    }
+
+    pub fn mute_boot_rom(mut self, mute: bool) -> Self {
+        self.mute_boot_rom = mute;
+        self
+    }
+
+    pub fn idle_sleep(mut self, sleep: bool) -> Self {
+        self.idle_sleep = sleep;
+        self
+    }
+
+    pub fn audio_buffer_cap(mut self, cap: usize, overflow: AudioOverflow) -> Self {
+        self.audio_buffer_cap = cap;
+        self.audio_overflow = overflow;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_PALETTE;
+
+    #[test]
+    fn test_defaults() {
+        let options = EmulatorOptions::default();
+        assert_eq!(options.rom_path, None);
+        assert_eq!(options.patch_path, None);
+        assert_eq!(options.use_bootrom, true);
+        assert_eq!(options.boot_rom_path, "data/dmg_rom.bin");
+        assert_eq!(options.model, Model::Dmg);
+        assert_eq!(options.scale, 8);
+        assert_eq!(options.upscale, false);
+        assert_eq!(options.palette, DEFAULT_PALETTE);
+        assert_eq!(options.socd, Socd::Allow);
+        assert_eq!(options.audio_buffer_cap, 1 << 20);
+        assert_eq!(options.audio_overflow, AudioOverflow::DropOldest);
+        assert_eq!(options.strict_opcodes, true);
+        assert_eq!(options.pause_on_unknown_opcode, false);
+        assert_eq!(options.headless, false);
+        assert_eq!(options.profile_opcodes, false);
+        assert_eq!(options.profile_memory, false);
+        assert_eq!(options.turbo_preserve_pitch, false);
+        assert_eq!(options.log_ram_banks, false);
+        assert_eq!(options.patch_boot_logo, false);
+        assert_eq!(options.trace_serial, false);
+        assert_eq!(options.mute_boot_rom, false);
+        assert_eq!(options.idle_sleep, false);
+        assert_eq!(options.save_dir, None);
+    }
+
+    #[test]
+    fn test_builder() {
+        let options = EmulatorOptions::default()
+            .rom_path("tetris.gb")
+            .use_bootrom(false)
+            .scale(2)
+            .strict_opcodes(false)
+            .headless(true);
+
+        assert_eq!(options.rom_path, Some(String::from("tetris.gb")));
+        assert_eq!(options.use_bootrom, false);
+        assert_eq!(options.scale, 2);
+        assert_eq!(options.strict_opcodes, false);
+        assert_eq!(options.headless, true);
+
+        // Untouched options keep their defaults.
+        assert_eq!(options.model, Model::Dmg);
+        assert_eq!(options.palette, DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse("scale = 3\nvolume = 1.0").unwrap();
+        let options = EmulatorOptions::default().config(config);
+        assert_eq!(options.scale, 3);
+        assert_eq!(options.volume, 1.0);
+    }
+}
@@ -0,0 +1,56 @@
+//! Run the real DMG boot ROM from reset and check it hands over to the cartridge the way the
+//! hardware does. The boot ROM isn't included: put it at `data/dmg_rom.bin` (or set `BOOT_ROM`).
+//! The test is skipped if it's missing.
+use gameboy::emulator::Emulator;
+use gameboy::options::EmulatorOptions;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const MAX_FRAMES: usize = 600; // The logo scroll takes a few seconds.
+
+#[test]
+fn test_boot_sequence() {
+    let boot_rom_path =
+        env::var("BOOT_ROM").unwrap_or_else(|_| EmulatorOptions::default().boot_rom_path);
+    if !Path::new(&boot_rom_path).exists() {
+        println!("Skipping boot sequence: {} not found.", boot_rom_path);
+        return;
+    }
+
+    // A ROM-only cartridge that loops at the entry point, so the registers stay as the boot ROM
+    // left them. It has a blank header, so have the logo and header checksum patched in.
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+    let rom_path = env::temp_dir().join("gameboy_boot_rom.gb");
+    fs::write(&rom_path, rom).unwrap();
+
+    let options = EmulatorOptions::default()
+        .rom_path(rom_path.to_str().unwrap())
+        .boot_rom_path(&boot_rom_path)
+        .use_bootrom(true)
+        .patch_boot_logo(true)
+        .headless(true);
+    let mut emulator = Emulator::new(options).unwrap();
+    assert_eq!(emulator.snapshot().mmu.pc, 0x0000);
+
+    let mut frames = 0;
+    while emulator.snapshot().mmu.boot_rom_enabled {
+        assert!(
+            frames < MAX_FRAMES,
+            "Still booting after {} frames.",
+            frames
+        );
+        emulator.run_frames(1);
+        frames += 1;
+    }
+
+    // The boot ROM unmapped itself by writing to 0xFF50, then fell through to the cartridge.
+    let mmu = emulator.snapshot().mmu;
+    assert_eq!(mmu.pc, 0x0100);
+    assert_eq!(mmu.sp, 0xFFFE);
+
+    // The same values the emulator starts with when it skips the boot ROM. The header checksum
+    // isn't zero, so the boot ROM's last comparison leaves H and C set.
+    assert_eq!(emulator.registers(), [0x01B0, 0x0013, 0x00D8, 0x014D]);
+}
@@ -0,0 +1,58 @@
+//! Feed the emulator random ROMs. Whatever a ROM does, the emulator shouldn't panic: unimplemented
+//! opcodes are skipped and odd memory accesses are ignored, as on hardware.
+use gameboy::emulator::Emulator;
+use gameboy::options::EmulatorOptions;
+use std::env;
+use std::fs;
+
+const ROMS: usize = 40;
+const FRAMES: usize = 10;
+
+/// A small xorshift generator, so that every run tests the same ROMs.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// A ROM full of random bytes, header included. Every other ROM has its cartridge type picked
+/// from the supported ones, so that plenty of them get as far as running, and every third has a
+/// valid ROM size code (which often won't match the data).
+fn random_rom(rng: &mut Rng, n: usize) -> Vec<u8> {
+    let banks = 2 << (n % 3); // 32KB, 64KB or 128KB.
+    let mut rom: Vec<u8> = (0..banks * 0x4000).map(|_| rng.next() as u8).collect();
+    if n % 2 == 0 {
+        rom[0x147] = [0x00, 0x01, 0x03, 0x06, 0x13][n / 2 % 5];
+    }
+    if n % 3 == 0 {
+        rom[0x148] = (rng.next() % 9) as u8;
+    }
+    rom
+}
+
+#[test]
+fn test_random_roms_dont_panic() {
+    let mut rng = Rng(0x1234_5678);
+
+    for n in 0..ROMS {
+        let path = env::temp_dir().join(format!("gameboy_fuzz_{}.gb", n));
+        fs::write(&path, random_rom(&mut rng, n)).unwrap();
+
+        let options = EmulatorOptions::default()
+            .rom_path(&path.to_string_lossy())
+            .use_bootrom(false)
+            .strict_opcodes(false)
+            .headless(true);
+
+        // A header the emulator doesn't support should be an error, not a panic.
+        match Emulator::new(options) {
+            Ok(mut emulator) => emulator.run_frames(FRAMES),
+            Err(e) => println!("ROM {} rejected: {}", n, e),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Run Mooneye test ROMs headless. The ROMs aren't included: put them in `roms/mooneye` (or set
+//! `MOONEYE_DIR`), keeping the suite's directory layout. Tests for missing ROMs are skipped.
+//!
+//! A Mooneye test finishes by loading the Fibonacci numbers 3, 5, 8, 13, 21, 34 into B, C, D, E, H
+//! and L if it passed, or 0x42 into all of them if it failed, then executing `LD B,B`.
+use gameboy::emulator::Emulator;
+use gameboy::options::EmulatorOptions;
+use std::env;
+use std::path::Path;
+
+const MAX_FRAMES: usize = 600; // Tests finish well within 10 seconds of emulated time.
+const PASS: [u16; 3] = [0x0305, 0x080D, 0x1522]; // BC, DE, HL.
+const FAIL: [u16; 3] = [0x4242, 0x4242, 0x4242];
+
+/// Run a Mooneye test ROM, given its path within the suite, until it reports a result. Return
+/// `None` if the ROM isn't there, otherwise whether it passed.
+fn run_mooneye(name: &str) -> Option<Result<(), String>> {
+    let dir = env::var("MOONEYE_DIR").unwrap_or_else(|_| String::from("roms/mooneye"));
+    let path = Path::new(&dir).join(name);
+    if !path.exists() {
+        println!("Skipping {}: ROM not found.", path.display());
+        return None;
+    }
+
+    let options = EmulatorOptions::default()
+        .rom_path(&path.to_string_lossy())
+        .use_bootrom(false)
+        .strict_opcodes(false)
+        .headless(true);
+    let mut emulator = Emulator::new(options).unwrap();
+
+    for _ in 0..MAX_FRAMES {
+        emulator.run_frames(1);
+        let registers = emulator.registers();
+        let result = [registers[1], registers[2], registers[3]];
+
+        if result == PASS {
+            return Some(Ok(()));
+        }
+        if result == FAIL {
+            return Some(Err(format!("{} failed.", name)));
+        }
+    }
+
+    Some(Err(format!(
+        "{} didn't finish in {} frames.",
+        name, MAX_FRAMES
+    )))
+}
+
+/// Assert that a Mooneye test ROM passes, if it's available.
+fn assert_mooneye_passes(name: &str) {
+    if let Some(result) = run_mooneye(name) {
+        result.unwrap();
+    }
+}
+
+#[test]
+fn test_div_write() {
+    assert_mooneye_passes("acceptance/timer/div_write.gb");
+}
+
+#[test]
+fn test_tima_reload() {
+    assert_mooneye_passes("acceptance/timer/tima_reload.gb");
+}
+
+#[test]
+fn test_tima_write_reloading() {
+    assert_mooneye_passes("acceptance/timer/tima_write_reloading.gb");
+}
+
+#[test]
+fn test_rapid_toggle() {
+    assert_mooneye_passes("acceptance/timer/rapid_toggle.gb");
+}
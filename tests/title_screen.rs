@@ -0,0 +1,104 @@
+//! Boot a ROM to its title screen, for regression screenshots. There's no knowing when a game has
+//! finished drawing its title, so run until the screen stops changing for a while instead.
+use gameboy::emulator::Emulator;
+use gameboy::options::EmulatorOptions;
+use std::env;
+use std::fs;
+
+/// The screen a ROM settled on, or was showing when we gave up waiting.
+pub struct Title {
+    pub frame: Vec<u8>,
+    pub frames: usize, // How many frames were run.
+    pub stable: bool,  // False if the screen was still changing at the frame cap.
+}
+
+/// Run a ROM headless, without the boot ROM, until the same frame has been drawn `stable_frames`
+/// times in a row or `max_frames` have been run. Pick `stable_frames` longer than any pause while
+/// the game sets up its title screen, or a blank screen will look settled.
+pub fn boot_to_title(path: &str, stable_frames: usize, max_frames: usize) -> Title {
+    let options = EmulatorOptions::default()
+        .rom_path(path)
+        .use_bootrom(false)
+        .strict_opcodes(false)
+        .headless(true);
+    let mut emulator = Emulator::new(options).unwrap();
+
+    let mut frame = emulator.frame_buffer().to_vec();
+    let mut unchanged = 0;
+
+    for n in 1..=max_frames {
+        emulator.run_frames(1);
+
+        if emulator.frame_buffer()[..] == frame[..] {
+            unchanged += 1;
+        } else {
+            frame = emulator.frame_buffer().to_vec();
+            unchanged = 0;
+        }
+
+        if unchanged == stable_frames {
+            return Title {
+                frame,
+                frames: n,
+                stable: true,
+            };
+        }
+    }
+
+    Title {
+        frame,
+        frames: max_frames,
+        stable: false,
+    }
+}
+
+/// A ROM-only cartridge that draws for a while, then stops. It increments the first 128 tiles'
+/// data 8 times over, taking a few frames for each pass. Like a real game it waits for mode 0 or
+/// 1 before touching VRAM, which the CPU can't read while the PPU is drawing. The background is
+/// all tile 0, so the screen changes with every pass.
+fn write_drawing_rom(name: &str) -> String {
+    let program = [
+        0x06, 0x08, // LD B,8
+        0x21, 0x00, 0x80, // LD HL,0x8000
+        0xF0, 0x41, // LDH A,(STAT)
+        0xE6, 0x02, // AND 0x02: mode 2 or 3?
+        0x20, 0xFA, // JR NZ,-6
+        0x34, // INC (HL)
+        0x23, // INC HL
+        0x7C, // LD A,H
+        0xFE, 0x88, // CP 0x88
+        0x20, 0xF3, // JR NZ,-13
+        0x05, // DEC B
+        0x20, 0xED, // JR NZ,-19
+        0x18, 0xFE, // JR -2
+    ];
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+    let path = env::temp_dir().join(format!("gameboy_{}.gb", name));
+    fs::write(&path, rom).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_boot_to_title() {
+    let title = boot_to_title(&write_drawing_rom("title_screen"), 10, 300);
+    assert!(title.stable);
+    assert!(
+        title.frames > 20,
+        "Settled after only {} frames.",
+        title.frames
+    );
+
+    // Every row of tile 0 is now 0x08, 0x08: pixel 4 is colour 3, which the palette keeps as 3.
+    for pixels in title.frame.chunks(8) {
+        assert_eq!(pixels, [0, 0, 0, 0, 3, 0, 0, 0]);
+    }
+}
+
+#[test]
+fn test_boot_to_title_frame_cap() {
+    let title = boot_to_title(&write_drawing_rom("title_screen_cap"), 10, 5);
+    assert!(!title.stable);
+    assert_eq!(title.frames, 5);
+}